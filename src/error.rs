@@ -3,6 +3,9 @@ pub enum Error {
     /// "catch-all" error type returned by CPAL in cases of unknown or unexpected errors
     CPALError(cpal::BackendSpecificError),
 
+    /// The audio thread has too many commands waiting for it, so it couldn't be sent another one
+    CommandQueueFull,
+
     /// The device no longer exists (ie. it has been disabled or unplugged)
     DeviceNotAvailable,
 
@@ -17,4 +20,7 @@ pub enum Error {
 
     /// Occurs if adding a new Stream ID would cause an integer overflow.
     StreamIdOverflow,
+
+    /// The Bus already has as many effects as it can have
+    TooManyEffects,
 }
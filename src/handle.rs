@@ -0,0 +1,227 @@
+use crate::source::{Length, Source};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
+
+/// A handle to a Source which has been added to a Mixer, used to control it while it plays.
+/// Handles can be cloned and sent to other threads freely. Changes are picked up the next time the Mixer asks the
+/// Source for samples, and volume changes, pausing and stopping are ramped over that block to avoid clicks.
+#[derive(Clone)]
+pub struct SourceHandle {
+    controls: Arc<Controls>,
+}
+
+/// Settings for a Source as it's added to a Mixer, which can be changed later through its SourceHandle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceOptions {
+    /// The gain which every sample of the Source is multiplied by. This is 1.0 by default.
+    pub volume: f32,
+
+    /// Where the Source is panned to, from -1.0 (left) to 1.0 (right). This is 0.0 (the center) by default.
+    pub pan: f32,
+
+    /// How important the Source is when a Mixer has more sources than it can play at once. Sources with a lower
+    /// priority are stopped first to make room for new ones. This is 0 by default.
+    pub priority: i32,
+}
+
+impl Default for SourceOptions {
+    fn default() -> Self {
+        Self { volume: 1.0, pan: 0.0, priority: 0 }
+    }
+}
+
+// The state shared between a Controlled source and its handles
+struct Controls {
+    // The bits of f32s
+    volume: AtomicU32,
+    pan: AtomicU32,
+    paused: AtomicBool,
+    stopped: AtomicBool,
+
+    // Set once the Controlled source has been dropped, so nothing is playing it anymore
+    finished: AtomicBool,
+}
+
+impl SourceHandle {
+    /// Stops the Source for good. The Mixer will discard it once it's faded out.
+    pub fn stop(&self) {
+        self.controls.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Pauses the Source, so that it stays where it is until it's resumed.
+    pub fn pause(&self) {
+        self.controls.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes the Source if it's paused.
+    pub fn resume(&self) {
+        self.controls.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns whether the Source is paused.
+    pub fn is_paused(&self) -> bool {
+        self.controls.paused.load(Ordering::Relaxed)
+    }
+
+    /// Sets the gain which every sample of the Source is multiplied by. This is 1.0 by default.
+    pub fn set_volume(&self, volume: f32) {
+        self.controls.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the gain which every sample of the Source is multiplied by.
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.controls.volume.load(Ordering::Relaxed))
+    }
+
+    /// Pans the Source, from -1.0 (left) to 1.0 (right), using a constant-power law.
+    /// Mono sources are moved between the speakers on either side, passing through the front center speaker if
+    /// there is one, and sources with more channels have their channels on one side turned down and the other
+    /// side's turned up. NaN is taken to mean the center.
+    pub fn set_pan(&self, pan: f32) {
+        self.controls.pan.store(clamp_pan(pan).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns where the Source is panned to, from -1.0 (left) to 1.0 (right).
+    pub fn pan(&self) -> f32 {
+        f32::from_bits(self.controls.pan.load(Ordering::Relaxed))
+    }
+
+    /// Returns whether the Source is still being played. This is false once it's been paused or stopped, or once
+    /// it's run out of samples and been discarded by its Mixer.
+    pub fn is_playing(&self) -> bool {
+        let controls = &self.controls;
+        !(controls.finished.load(Ordering::Relaxed)
+            || controls.stopped.load(Ordering::Relaxed)
+            || controls.paused.load(Ordering::Relaxed))
+    }
+}
+
+/// A Source which can be controlled from elsewhere through a SourceHandle.
+/// Mixers wrap everything they're given in one of these, so Mixer implementations can use it to hand out handles.
+/// It applies the volume, pausing and stopping itself, but leaves panning to the Mixer.
+pub struct Controlled<S: Source> {
+    source: S,
+    controls: Arc<Controls>,
+
+    // The gain applied at the end of the last block, which is ramped towards the handle's volume
+    gain: f32,
+    priority: i32,
+
+    // Set while whatever this Source was added to wants it silenced, such as a Bus while another one is soloed.
+    // It carries on playing while it's silenced, unlike when it's paused.
+    silenced_by: Option<Arc<AtomicBool>>,
+}
+
+impl<S: Source> Controlled<S> {
+    pub fn new(source: S, options: SourceOptions) -> Self {
+        let controls = Controls {
+            volume: AtomicU32::new(options.volume.to_bits()),
+            pan: AtomicU32::new(clamp_pan(options.pan).to_bits()),
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+        };
+        let priority = options.priority;
+        Self { source, controls: Arc::new(controls), gain: options.volume, priority, silenced_by: None }
+    }
+
+    /// Returns a new handle to this Source.
+    pub fn handle(&self) -> SourceHandle {
+        SourceHandle { controls: self.controls.clone() }
+    }
+
+    /// Returns where this Source's handles have panned it to, from -1.0 (left) to 1.0 (right).
+    pub fn pan(&self) -> f32 {
+        f32::from_bits(self.controls.pan.load(Ordering::Relaxed))
+    }
+
+    /// Returns whether this Source's handles have paused it.
+    pub fn is_paused(&self) -> bool {
+        self.controls.paused.load(Ordering::Relaxed)
+    }
+
+    /// Returns the priority this Source was added with.
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Stops this Source, fading it out over the next block just like SourceHandle::stop does.
+    pub fn stop(&self) {
+        self.controls.stopped.store(true, Ordering::Relaxed);
+    }
+
+    // Silences this Source whenever `flag` is set
+    pub(crate) fn set_silenced_by(&mut self, flag: Arc<AtomicBool>) {
+        self.silenced_by = Some(flag);
+    }
+
+    /// Lets this Source's handles know that nothing is playing it anymore. This happens anyway once it's dropped,
+    /// so Mixers only need to call it when they hand a Source over to be dropped somewhere else.
+    pub fn mark_finished(&self) {
+        self.controls.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S: Source> Source for Controlled<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let controls = &self.controls;
+        let stopped = controls.stopped.load(Ordering::Relaxed);
+        let halted = stopped || controls.paused.load(Ordering::Relaxed);
+        let target = if halted { 0.0 } else { f32::from_bits(controls.volume.load(Ordering::Relaxed)) };
+
+        // Once it's faded out, a stopped source ends, and a paused one writes silence without moving on. A source
+        // which has only been turned down to nothing carries on playing, so that it still ends when it runs out.
+        if self.gain == 0.0 && halted {
+            if stopped {
+                return 0
+            }
+            buffer.iter_mut().for_each(|s| *s = 0.0);
+            return buffer.len()
+        }
+
+        let silenced = self.silenced_by.as_ref().map_or(false, |flag| flag.load(Ordering::Relaxed));
+        let target = if silenced { 0.0 } else { target };
+        let count = self.source.write_samples(buffer);
+        if self.gain == target {
+            buffer[..count].iter_mut().for_each(|s| *s *= target);
+        } else {
+            let channels = self.source.channel_count().max(1);
+            let step = (target - self.gain) / (count / channels).max(1) as f32;
+            for frame in buffer[..count].chunks_mut(channels) {
+                self.gain += step;
+                frame.iter_mut().for_each(|s| *s *= self.gain);
+            }
+            self.gain = target;
+        }
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        self.source.total_frames()
+    }
+
+    fn remaining_frames(&self) -> Length {
+        self.source.remaining_frames()
+    }
+}
+
+impl<S: Source> Drop for Controlled<S> {
+    fn drop(&mut self) {
+        self.controls.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+// Keeps a pan position between -1.0 and 1.0, treating NaN (which clamp would pass through) as the center
+pub(crate) fn clamp_pan(pan: f32) -> f32 {
+    if pan.is_nan() { 0.0 } else { pan.clamp(-1.0, 1.0) }
+}
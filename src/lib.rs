@@ -1,48 +1,105 @@
 mod error;
+mod handle;
 pub mod mixer;
+mod render;
 pub mod resampler;
 pub mod source;
 mod stream;
 
+use std::convert::TryFrom;
+
 pub use error::Error;
+pub use handle::{Controlled, SourceHandle, SourceOptions};
 pub use mixer::Mixer;
+pub use render::OfflineRenderer;
 pub use resampler::Resampler;
-pub use source::Source;
+pub use source::{Length, LoopRegion, Seekable, Source, SourceExt};
 pub use stream::OutputStream;
 
 /// A basic sound-playing object. When fed to an output stream, will play the samples it contains until it has no more.
 /// If the samples have a different sample rate than the output stream, the output will sound sped up or slowed down.
-/// Use a resampler (such as boop::resampler::Polyphase, or implement your own) to resample it at the correct rate.
+/// Use a resampler (such as boop::Resampler, or implement your own) to resample it at the correct rate,
+/// or give the Player a sample rate with `with_sample_rate` so that mixers and output streams can do it for you.
 pub struct Player {
     samples: Box<[f32]>,
     channels: usize,
+    sample_rate: Option<u32>,
     offset: usize,
+    loop_region: Option<LoopRegion>,
 }
 
 impl Player {
     pub fn new(samples: Box<[f32]>, channels: usize) -> Self {
-        Self { samples, channels, offset: 0 }
+        Self { samples, channels, sample_rate: None, offset: 0, loop_region: None }
+    }
+
+    /// Sets the sample rate which this Player's samples were recorded at (eg. 44100)
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Returns the region this Player is currently looping, if any.
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        self.loop_region
+    }
+
+    /// Sets a region to loop. Once playback reaches the end of the region it'll jump back to the start of it,
+    /// forever. Pass None to stop looping and let playback continue to the end of the samples.
+    pub fn set_loop(&mut self, region: Option<LoopRegion>) {
+        self.loop_region = region;
+    }
+
+    // Copies samples into the buffer, stopping at the given sample offset
+    fn copy_until(&mut self, buffer: &mut [f32], end: usize) -> usize {
+        let samples = self.samples.get(self.offset..end).unwrap_or(&[]);
+        let count = samples.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&samples[..count]);
+        self.offset += count;
+        count
     }
 }
 
 impl Source for Player {
     fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
-        let old_offset = self.offset;
-        self.offset += buffer.len();
-        if let Some(i) = self.samples.get(old_offset..self.offset) {
-            buffer.copy_from_slice(i);
-            buffer.len()
-        } else {
-            if let Some(i) = self.samples.get(old_offset..) {
-                buffer[..i.len()].copy_from_slice(i);
-                i.len()
-            } else {
-                0
-            }
+        match self.loop_region.map(|region| region.scaled_range(self.channels, self.samples.len())) {
+            Some((start, end)) if start < end => {
+                let mut count = 0;
+                while count < buffer.len() {
+                    if self.offset >= end {
+                        self.offset = start;
+                    }
+                    count += self.copy_until(&mut buffer[count..], end);
+                }
+                count
+            },
+            _ => self.copy_until(buffer, self.samples.len()),
         }
     }
 
     fn channel_count(&self) -> usize {
         self.channels
     }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    fn total_frames(&self) -> Length {
+        Length::Exact((self.samples.len() / self.channels) as u64)
+    }
+
+    fn remaining_frames(&self) -> Length {
+        if self.loop_region.is_some() {
+            return Length::Unknown
+        }
+        Length::Exact((self.samples.len().saturating_sub(self.offset) / self.channels) as u64)
+    }
+}
+
+impl Seekable for Player {
+    fn seek_frame(&mut self, frame: u64) {
+        let offset = usize::try_from(frame).unwrap_or(usize::MAX).saturating_mul(self.channels);
+        self.offset = offset.min(self.samples.len());
+    }
 }
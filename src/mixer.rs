@@ -1,99 +1,418 @@
-use crate::Source;
+mod bus;
+mod channels;
 
-const INIT_CAPACITY: usize = 16;
+pub use bus::{Bus, BusHandle, Effect};
+pub use channels::ChannelMatrix;
+
+use crate::{Controlled, Resampler, Source, SourceHandle, SourceOptions};
+use crossbeam_queue::ArrayQueue;
+use std::sync::Arc;
+
+// How many sources a BufferedMixer has room for unless it's told otherwise
+const DEFAULT_CAPACITY: usize = 256;
+
+// The most frames a BufferedMixer mixes at once. Longer buffers are mixed a block at a time, so that its own buffers
+// can be allocated up front.
+const BLOCK_FRAMES: usize = 1024;
+
+// Channel matrices are set up ahead of time for sources with up to this many channels, so that the audio thread
+// never has to
+const PREPARED_CHANNELS: usize = 8;
+
+// How much of a voice's peak level is left after a block of BLOCK_FRAMES frames, so that VoiceStealing::Quietest
+// goes by how loud a source has been lately rather than only in the last block
+const PEAK_DECAY: f32 = 0.5;
 
 /// The Mixer trait defines objects which mix any number of input streams into a single output stream.
 /// Mixers are designed to be attached to an output device and left there for the entire lifetime of the application.
-/// They will also convert the number of input channels on each input to the expected number of output channels.
-/// However, Mixers do not care what the input or output sample rates are, so you should ensure that all of the Sources
-/// you send it have the same sample rate. You can change a Source's sample rate with boop::Resampler.
+/// They will also convert the number of input channels on each input to the expected number of output channels,
+/// using ChannelMatrix::standard unless they've been given another matrix to use.
+/// Mixers which know their output sample rate will resample any Source which reports a different one, but Sources
+/// which don't report a sample rate are mixed as-is, so you should ensure that they all have the same sample rate.
+/// You can change a Source's sample rate with boop::Resampler.
+/// Each Source is mixed in at its own volume and pan position, which can be changed through its SourceHandle.
 pub trait Mixer: Source {
-    /// Adds a new source to be mixed into this Mixer's output.
-    /// The Mixer will play from this Source until it is exhausted, then discard it.
-    fn add_source(&mut self, source: impl Source + Send + Sync + 'static);
+    /// Adds a new source to be mixed into this Mixer's output, returning a handle which can control it.
+    /// The Mixer will play from this Source until it is exhausted or stopped, then discard it.
+    fn add_source(&mut self, source: impl Source + Send + Sync + 'static) -> SourceHandle {
+        self.add_source_with(source, SourceOptions::default())
+    }
+
+    /// Adds a new source like add_source does, but with the given volume and pan position to start with.
+    fn add_source_with(&mut self, source: impl Source + Send + Sync + 'static, options: SourceOptions) -> SourceHandle;
+
+    /// Adds a source which has already been wrapped up with its handles, such as one sent over from another thread.
+    /// It's mixed as it is, without being resampled. OutputStream calls this on the audio thread, so Mixers should
+    /// override it to avoid allocating if they can. If the Mixer has no room for the source, it's given back.
+    fn add_controlled(
+        &mut self,
+        source: Controlled<Box<dyn Source + Send + Sync>>,
+    ) -> Result<(), Controlled<Box<dyn Source + Send + Sync>>> {
+        self.add_source(source);
+        Ok(())
+    }
+
+    /// Returns the queue which this Mixer sends sources back through once it's finished with them, if it has one.
+    /// OutputStream empties it away from the audio thread, so that sources aren't dropped there.
+    /// Mixers without one drop their sources themselves.
+    fn finished_queue(&self) -> Option<Arc<FinishedQueue>> {
+        None
+    }
+}
+
+/// A queue of sources which a Mixer has finished with, so that they can be dropped on another thread.
+pub type FinishedQueue = ArrayQueue<Controlled<Box<dyn Source + Send + Sync>>>;
+
+// Adds a source to a mixer running at `sample_rate`, resampling it first if it reports a different sample rate
+pub(crate) fn add_resampled<M: Mixer>(
+    mixer: &mut M,
+    source: impl Source + Send + Sync + 'static,
+    options: SourceOptions,
+    sample_rate: u32,
+) -> SourceHandle {
+    match source.sample_rate() {
+        Some(rate) if rate != sample_rate => mixer.add_source_with(Resampler::new(source, rate, sample_rate), options),
+        _ => mixer.add_source_with(source, options),
+    }
+}
+
+/// How a Mixer chooses which source to stop when it's playing as many as it can and another is added.
+/// Sources with the lowest priority are always chosen first, and this decides between those.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceStealing {
+    /// Stops whichever source has had the lowest peak level lately. Paused sources are judged by how loud they were
+    /// before they were paused, and sources which haven't been mixed yet are only stopped if nothing else can be.
+    Quietest,
+
+    /// Stops whichever source was added first.
+    Oldest,
 }
 
 /// A simple additive mixer. Implements the Mixer trait. See the Mixer trait description for more information.
+/// Everything it needs while mixing is allocated up front, so it never allocates or frees memory on the audio thread.
+/// It has room for a fixed number of sources (256 unless it's set with `with_capacity`), and turns away any more
+/// than that, along with sources whose channel count it has no matrix for. Sources it's finished with are sent back
+/// through its finished queue, which is emptied whenever a source is added.
+/// It plays as many sources at once as it has room for unless it's been given a limit with `with_max_voices`.
 pub struct BufferedMixer {
     channels: usize,
-    sources: Vec<Box<dyn Source + Send + Sync>>,
+    sample_rate: Option<u32>,
+    voices: Vec<Voice>,
+    capacity: usize,
+    max_voices: Option<usize>,
+    stealing: VoiceStealing,
+    finished: Arc<FinishedQueue>,
+
+    // Sources which have finished while the finished queue was full, which count towards the capacity until they
+    // can be sent back
+    retired: Vec<Controlled<Box<dyn Source + Send + Sync>>>,
+
+    // Where each source writes its samples, which is big enough for a block of the most channels there's a matrix for
     input_buffer: Vec<f32>,
+
+    // Where sources which are being panned are mixed before they're added to the output
+    pan_buffer: Vec<f32>,
+
+    // The gains for each output channel at the start of a block, then at the end of it
+    pan_gains: Vec<f32>,
+
+    // The matrix for each number of input channels which differs from the output's. Sources with any other number
+    // of channels are turned away.
+    matrices: Vec<ChannelMatrix>,
+}
+
+// A source being mixed, along with what the mixer remembers about it from one block to the next
+struct Voice {
+    source: Controlled<Box<dyn Source + Send + Sync>>,
+
+    // The pan position at the end of the last block, which is ramped towards the handle's pan position
+    pan: f32,
+
+    // The loudest sample lately, which falls off by PEAK_DECAY every block and is only kept track of while voices
+    // are limited. It's None until the voice has been mixed, so that a voice isn't stolen before it's been heard.
+    peak: Option<f32>,
+
+    // Set once the voice has been stopped to make room for another, so that it isn't counted while it fades out
+    stolen: bool,
+}
+
+impl Voice {
+    fn new(source: Controlled<Box<dyn Source + Send + Sync>>) -> Self {
+        Self { pan: source.pan(), source, peak: None, stolen: false }
+    }
 }
 
 impl BufferedMixer {
     /// Constructs a new Mixer. `channels` is the number of channels wanted in the output data.
     pub fn new(channels: usize) -> Self {
-        Self { channels, sources: Vec::with_capacity(INIT_CAPACITY), input_buffer: Vec::new() }
+        let matrices = (1..=PREPARED_CHANNELS)
+            .filter(|&inputs| inputs != channels)
+            .map(|inputs| ChannelMatrix::standard(inputs, channels))
+            .collect();
+        Self {
+            channels,
+            sample_rate: None,
+            voices: Vec::with_capacity(DEFAULT_CAPACITY),
+            capacity: DEFAULT_CAPACITY,
+            max_voices: None,
+            stealing: VoiceStealing::Quietest,
+            finished: Arc::new(ArrayQueue::new(DEFAULT_CAPACITY)),
+            retired: Vec::with_capacity(DEFAULT_CAPACITY),
+            input_buffer: vec![0.0; BLOCK_FRAMES * channels.max(PREPARED_CHANNELS)],
+            pan_buffer: vec![0.0; BLOCK_FRAMES * channels],
+            pan_gains: vec![0.0; channels * 2],
+            matrices,
+        }
     }
-}
 
-impl Mixer for BufferedMixer {
-    fn add_source(&mut self, source: impl Source + Send + Sync + 'static) {
-        self.sources.push(Box::new(source));
+    /// Sets how many sources this Mixer has room for. Sources added once it's full are turned away, and sources
+    /// which have been stopped take up room until they've faded out.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        self.voices.reserve_exact(capacity.saturating_sub(self.voices.len()));
+        self.retired.reserve_exact(capacity.saturating_sub(self.retired.len()));
+        self.finished = Arc::new(ArrayQueue::new(capacity));
+        self.capacity = capacity;
+        self
     }
-}
 
-impl Source for BufferedMixer {
-    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
-        let input_buffer = &mut self.input_buffer;
-        let output_channel_count = self.channels;
+    /// Sets the matrix used to mix any Source with `matrix.inputs()` channels into this Mixer's output, instead of
+    /// the standard one. Sources with more than 8 channels can only be played once they've been given a matrix, unless
+    /// they have as many channels as the Mixer.
+    /// Panics if the matrix doesn't output the same number of channels as this Mixer.
+    pub fn with_channel_matrix(mut self, matrix: ChannelMatrix) -> Self {
+        assert_eq!(matrix.outputs(), self.channels, "channel matrix doesn't match the mixer's channel count");
+        if self.input_buffer.len() < BLOCK_FRAMES * matrix.inputs() {
+            self.input_buffer.resize(BLOCK_FRAMES * matrix.inputs(), 0.0);
+        }
+        self.matrices.retain(|m| m.inputs() != matrix.inputs());
+        self.matrices.push(matrix);
+        self
+    }
+
+    /// Sets the output sample rate of this Mixer. Any Source added afterwards with a different sample rate
+    /// will be resampled to match it.
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Limits how many sources this Mixer plays at once. Once it's playing `max_voices` sources, adding another
+    /// stops one with the lowest priority (chosen by `with_voice_stealing`) to make room, fading it out over the
+    /// next block. If every source playing has a higher priority than the new one, the new one isn't played at all.
+    pub fn with_max_voices(mut self, max_voices: usize) -> Self {
+        self.max_voices = Some(max_voices);
+        self
+    }
+
+    /// Sets how a source is chosen to be stopped when the limit set by `with_max_voices` is reached.
+    /// This is VoiceStealing::Quietest by default.
+    pub fn with_voice_stealing(mut self, stealing: VoiceStealing) -> Self {
+        self.stealing = stealing;
+        self
+    }
+
+    // Adds a source to be mixed, wrapped up so that it can be controlled through the returned handle.
+    // This isn't called on the audio thread, so it's a good time to drop the sources which have finished.
+    fn push(&mut self, source: impl Source + Send + Sync + 'static, options: SourceOptions) -> SourceHandle {
+        while self.finished.pop().is_some() {}
+        let source = Controlled::new(Box::new(source) as Box<dyn Source + Send + Sync>, options);
+        let handle = source.handle();
+        let _ = self.add_voice(Voice::new(source));
+        handle
+    }
+
+    // Starts mixing a voice, stealing another one first if there are already as many playing as there can be.
+    // The voice is given back if there's no room for it, or no matrix for its channels.
+    fn add_voice(&mut self, voice: Voice) -> Result<(), Voice> {
+        let channels = voice.source.channel_count();
+        let mixable = channels == self.channels || self.matrices.iter().any(|matrix| matrix.inputs() == channels);
+        if !mixable || self.voices.len() + self.retired.len() >= self.capacity {
+            return Err(voice)
+        }
+        if let Some(max_voices) = self.max_voices {
+            if self.voices.iter().filter(|v| !v.stolen).count() >= max_voices {
+                let stealing = self.stealing;
+                let victim = self.voices.iter_mut().filter(|v| !v.stolen).enumerate().min_by(|(i, a), (j, b)| {
+                    let priority = a.source.priority().cmp(&b.source.priority());
+                    priority.then_with(|| match stealing {
+                        VoiceStealing::Quietest => {
+                            a.peak.unwrap_or(f32::INFINITY).total_cmp(&b.peak.unwrap_or(f32::INFINITY))
+                        },
+                        VoiceStealing::Oldest => i.cmp(j),
+                    })
+                });
+                match victim {
+                    Some((_, victim)) if victim.source.priority() <= voice.source.priority() => {
+                        victim.source.stop();
+                        victim.stolen = true;
+                    },
+                    _ => return Err(voice),
+                }
+            }
+        }
+        self.voices.push(voice);
+        Ok(())
+    }
+
+    // Sends a source which has finished back to be dropped, or holds on to it until there's room to
+    fn retire(&mut self, source: Controlled<Box<dyn Source + Send + Sync>>) {
+        source.mark_finished();
+        if let Err(source) = self.finished.push(source) {
+            self.retired.push(source);
+        }
+    }
 
+    // Mixes every voice into a block of at most BLOCK_FRAMES frames
+    fn mix_block(&mut self, buffer: &mut [f32]) {
         buffer.iter_mut().for_each(|s| *s = 0.0);
+        let mut index = 0;
+        while index < self.voices.len() {
+            if self.mix_voice(index, buffer) {
+                index += 1;
+            } else {
+                let voice = self.voices.remove(index);
+                self.retire(voice.source);
+            }
+        }
+    }
+
+    // Mixes a voice into a block, returning whether it's still playing
+    fn mix_voice(&mut self, index: usize, buffer: &mut [f32]) -> bool {
+        let output_channel_count = self.channels;
+        let voice = &mut self.voices[index];
+        let source = &mut voice.source;
+        let source_channel_count = source.channel_count();
+
+        // Sources are checked when they're added, but there's nothing stopping them changing their channel count
+        let input_len = buffer.len() / output_channel_count * source_channel_count;
+        let input_buffer = match self.input_buffer.get_mut(..input_len) {
+            Some(input_buffer) if source_channel_count != 0 => input_buffer,
+            _ => return false,
+        };
+        let matrix = match self.matrices.iter().find(|matrix| matrix.inputs() == source_channel_count) {
+            Some(matrix) => Some(matrix),
+            None if source_channel_count == output_channel_count => None,
+            None => return false,
+        };
+
+        // Mono sources go straight to the center of layouts which have one, so panning them moves them across the
+        // front speakers instead
+        let front = if source_channel_count == 1 { channels::front_channels(output_channel_count) } else { None };
 
-        self.sources.retain_mut(|source| {
-            let source_channel_count = source.channel_count();
-            input_buffer.resize_with(buffer.len() * source_channel_count / output_channel_count, Default::default); // TODO: use unsafe for this?
-            let count = source.write_samples(input_buffer);
+        // Paused voices keep the peak they had, so that they aren't taken for the quietest just for being paused
+        let paused = source.is_paused();
+        let count = source.write_samples(input_buffer);
+        if self.max_voices.is_some() && !(paused && voice.peak.is_some()) {
+            let decay = PEAK_DECAY.powf((count / source_channel_count) as f32 / BLOCK_FRAMES as f32);
+            let block_peak = input_buffer[..count].iter().fold(0.0, |peak, s| s.abs().max(peak));
+            voice.peak = Some(voice.peak.map_or(block_peak, |peak| block_peak.max(peak * decay)));
+        }
+
+        // Sources which are panned are mixed separately first, so that the pan can be applied to them on the way
+        // into the output
+        let pan = source.pan();
+        let panned = pan != 0.0 || voice.pan != 0.0;
+        let output = if panned {
+            let pan_buffer = &mut self.pan_buffer[..buffer.len()];
+            pan_buffer.iter_mut().for_each(|s| *s = 0.0);
+            pan_buffer
+        } else {
+            &mut *buffer
+        };
 
-            if source_channel_count == output_channel_count {
-                // Firstly, if the input and output channel counts are the same, pass straight through.
-                for (in_sample, out_sample) in input_buffer[..count].iter().copied().zip(buffer.iter_mut()) {
+        match (matrix, front) {
+            // Copy mono sources which are being panned to each of the front speakers, for the pan to pick between
+            (_, Some(front)) if panned => {
+                let out_frames = output.chunks_exact_mut(output_channel_count);
+                for (&in_sample, out_frame) in input_buffer[..count].iter().zip(out_frames) {
+                    front.iter().for_each(|&channel| out_frame[channel] += in_sample);
+                }
+            },
+            // Map the input's channels onto the output's
+            (Some(matrix), _) => matrix.mix(&input_buffer[..count], output),
+            // If the input and output channel counts are the same, pass straight through.
+            (None, _) => {
+                for (in_sample, out_sample) in input_buffer[..count].iter().copied().zip(output.iter_mut()) {
                     *out_sample += in_sample;
                 }
-            } else if source_channel_count == 1 {
-                // Next, if the input is 1-channel, duplicate the next sample across all output channels.
-                for (in_sample, out_samples) in
-                    input_buffer[..count].iter().copied().zip(buffer.chunks_exact_mut(output_channel_count))
-                {
-                    out_samples.iter_mut().for_each(|s| *s = in_sample);
+            },
+        }
+
+        if panned {
+            // Ramp from the last block's pan position to the new one over the frames which were written
+            let (from, to) = self.pan_gains.split_at_mut(output_channel_count);
+            match front {
+                Some(front) => {
+                    channels::front_pan_gains(voice.pan, front, from);
+                    channels::front_pan_gains(pan, front, to);
+                },
+                None => {
+                    channels::pan_gains(voice.pan, from);
+                    channels::pan_gains(pan, to);
+                },
+            }
+            let frames = (count / source_channel_count).max(1) as f32;
+            let frame_iter = self.pan_buffer[..buffer.len()]
+                .chunks_exact(output_channel_count)
+                .zip(buffer.chunks_exact_mut(output_channel_count));
+            for (index, (in_frame, out_frame)) in frame_iter.enumerate() {
+                let progress = ((index + 1) as f32 / frames).min(1.0);
+                for (channel, (in_sample, out_sample)) in in_frame.iter().zip(out_frame.iter_mut()).enumerate() {
+                    *out_sample += in_sample * (from[channel] + (to[channel] - from[channel]) * progress);
                 }
-            } else {
-                // Different multi-channel counts. What do we do here!?
-                todo!("multi-channel mixing")
             }
+            voice.pan = pan;
+        }
 
-            count == input_buffer.len()
-        });
+        count == input_len
+    }
+}
 
-        buffer.len()
+impl Mixer for BufferedMixer {
+    fn add_source_with(&mut self, source: impl Source + Send + Sync + 'static, options: SourceOptions) -> SourceHandle {
+        match (source.sample_rate(), self.sample_rate) {
+            (Some(from), Some(to)) if from != to => self.push(Resampler::new(source, from, to), options),
+            _ => self.push(source, options),
+        }
     }
 
-    fn channel_count(&self) -> usize {
-        self.channels
+    fn add_controlled(
+        &mut self,
+        source: Controlled<Box<dyn Source + Send + Sync>>,
+    ) -> Result<(), Controlled<Box<dyn Source + Send + Sync>>> {
+        self.add_voice(Voice::new(source)).map_err(|voice| voice.source)
     }
-}
 
-trait RetainMut<T> {
-    fn retain_mut(&mut self, f: impl FnMut(&mut T) -> bool);
+    fn finished_queue(&self) -> Option<Arc<FinishedQueue>> {
+        Some(self.finished.clone())
+    }
 }
 
-impl<T> RetainMut<T> for Vec<T> {
-    fn retain_mut(&mut self, mut f: impl FnMut(&mut T) -> bool) {
-        let len = self.len();
-        let mut del = 0;
-        {
-            let v = &mut **self;
-
-            for i in 0..len {
-                if !f(&mut v[i]) {
-                    del += 1;
-                } else if del > 0 {
-                    v.swap(i - del, i);
-                }
+impl Source for BufferedMixer {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        // Send back the sources which finished while the finished queue was full, if it has room now
+        while let Some(source) = self.retired.pop() {
+            if let Err(source) = self.finished.push(source) {
+                self.retired.push(source);
+                break
             }
         }
-        if del > 0 {
-            self.truncate(len - del);
+
+        if self.channels == 0 {
+            return buffer.len()
+        }
+        for block in buffer.chunks_mut(BLOCK_FRAMES * self.channels) {
+            self.mix_block(block);
         }
+        buffer.len()
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
     }
 }
@@ -0,0 +1,455 @@
+use super::{BufferedMixer, FinishedQueue};
+use crate::{Controlled, Error, Mixer, Resampler, Source, SourceHandle, SourceOptions};
+use crossbeam_queue::ArrayQueue;
+use std::sync::{
+    atomic::{AtomicBool, AtomicIsize, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+    Arc,
+};
+
+// How many sources and effects can be waiting to be added to a bus at once
+const COMMAND_CAPACITY: usize = 256;
+
+// How many effects a bus can have, which are allocated room for up front
+const MAX_EFFECTS: usize = 16;
+
+// The bits of a bus's solo state
+const SOLOED: u8 = 1;
+const REMOVED: u8 = 2;
+
+/// An effect which a Bus runs over its mix before applying its gain, such as a filter or a compressor.
+pub trait Effect {
+    /// Processes interleaved samples with `channels` channels in place.
+    fn process(&mut self, buffer: &mut [f32], channels: usize);
+}
+
+/// A submix, which mixes its sources together with a Mixer and feeds the result into a parent bus as a single
+/// Source, with its own gain, mute and solo. This lets a whole group of sources (such as all the music, or all the
+/// sound effects) be turned up and down at once.
+/// Soloing works across the whole tree of buses: while any bus is soloed, only soloed buses, the buses beneath them
+/// and the buses on the way up to them can be heard, and the sources added straight to a bus are silenced unless it
+/// or a bus above it is soloed.
+/// Buses are Mixers themselves, so a Bus can be used as the Mixer of an OutputStream to make it the master bus, or
+/// added to any other Mixer. Once it's been handed over, it's controlled through a BusHandle.
+pub struct Bus<M: Mixer = BufferedMixer> {
+    mixer: M,
+    shared: Arc<Shared>,
+    effects: Vec<Box<dyn Effect + Send + Sync>>,
+
+    // A source which the Mixer had no room for, waiting for room in the finished queue to be sent back
+    rejected: Option<Controlled<Box<dyn Source + Send + Sync>>>,
+
+    // Set while the effects have been cleared, but some are waiting for room to be sent back to be dropped.
+    // They aren't run, and nothing else is added until they've gone.
+    clearing: bool,
+
+    // The gain applied at the end of the last block, which is ramped towards the handle's volume
+    gain: f32,
+}
+
+/// A handle to a Bus, used to add sources, child buses and effects to it and to control it while it plays.
+/// Handles can be cloned and sent to other threads freely. Like OutputStream, they send what's added over to the
+/// Bus through a lock-free queue, which it empties the next time it's asked for samples, and drop the sources its
+/// Mixer has finished with whenever they add another.
+#[derive(Clone)]
+pub struct BusHandle {
+    shared: Arc<Shared>,
+
+    // The handle the Bus was added to its parent with, which a Bus without a parent doesn't have
+    source: Option<SourceHandle>,
+}
+
+// The state shared between a Bus and its handles
+struct Shared {
+    channels: usize,
+    sample_rate: Option<u32>,
+    commands: ArrayQueue<Command>,
+    finished: Option<Arc<FinishedQueue>>,
+
+    // Effects which have been cleared, waiting to be dropped by a handle, and how many effects the Bus has or has
+    // been sent, which handles keep below MAX_EFFECTS
+    cleared_effects: ArrayQueue<Box<dyn Effect + Send + Sync>>,
+    effect_count: AtomicUsize,
+
+    // The bits of an f32
+    volume: AtomicU32,
+    muted: AtomicBool,
+
+    // Whether the bus is soloed, and whether it's been removed from its parent or dropped, after which it can't be
+    // soloed. They're kept together so that only whichever of soloing and removing changes them first counts the
+    // solo.
+    solo: AtomicU8,
+
+    // How many buses beneath this one are soloed. Every soloed bus is counted by all the buses above it, so the
+    // count at the root says whether anything is soloed at all. It can dip below zero for a moment while a bus is
+    // soloed and removed at once.
+    soloed_below: AtomicIsize,
+
+    // Set while the sources added to this bus are silenced because another bus is soloed. They each have a copy.
+    sources_silenced: Arc<AtomicBool>,
+    parent: Option<Arc<Shared>>,
+}
+
+// Something for a Bus to do before it next writes samples
+enum Command {
+    AddSource(Controlled<Box<dyn Source + Send + Sync>>),
+    InsertEffect(Box<dyn Effect + Send + Sync>),
+    ClearEffects,
+}
+
+impl Shared {
+    // Returns the buses above this one, from its parent up to the root
+    fn ancestors(&self) -> impl Iterator<Item = &Shared> {
+        std::iter::successors(self.parent.as_deref(), |shared| shared.parent.as_deref())
+    }
+
+    fn is_soloed(&self) -> bool {
+        self.solo.load(Ordering::Relaxed) == SOLOED
+    }
+
+    // Counts a change in this bus's solo in every bus above it
+    fn count_solo(&self, delta: isize) {
+        self.ancestors().for_each(|bus| {
+            bus.soloed_below.fetch_add(delta, Ordering::Relaxed);
+        });
+    }
+
+    // Marks the bus as removed, which unsolos it for good
+    fn remove(&self) {
+        if self.solo.swap(REMOVED, Ordering::Relaxed) == SOLOED {
+            self.count_solo(-1);
+        }
+    }
+
+    // Drops the sources and effects which the Bus has finished with
+    fn drop_finished(&self) {
+        if let Some(finished) = &self.finished {
+            while finished.pop().is_some() {}
+        }
+        while self.cleared_effects.pop().is_some() {}
+    }
+
+    // Wraps up a source to be added to the Bus, resampling it if it needs to be. Everything which allocates is
+    // done here, so that the audio thread only has to move the source in.
+    fn prepare(
+        &self,
+        source: impl Source + Send + Sync + 'static,
+        options: SourceOptions,
+    ) -> Controlled<Box<dyn Source + Send + Sync>> {
+        let source: Box<dyn Source + Send + Sync> = match (source.sample_rate(), self.sample_rate) {
+            (Some(from), Some(to)) if from != to => Box::new(Resampler::new(source, from, to)),
+            _ => Box::new(source),
+        };
+        let mut source = Controlled::new(source, options);
+        source.set_silenced_by(self.sources_silenced.clone());
+        source
+    }
+}
+
+impl<M: Mixer> Bus<M> {
+    /// Constructs a new Bus which mixes its sources with `mixer`.
+    /// Sources added through its handles are resampled to the Mixer's sample rate, if it has one.
+    pub fn new(mixer: M) -> Self {
+        Self::with_parent(mixer, None)
+    }
+
+    fn with_parent(mixer: M, parent: Option<Arc<Shared>>) -> Self {
+        let shared = Shared {
+            channels: mixer.channel_count(),
+            sample_rate: mixer.sample_rate(),
+            commands: ArrayQueue::new(COMMAND_CAPACITY),
+            finished: mixer.finished_queue(),
+            cleared_effects: ArrayQueue::new(MAX_EFFECTS),
+            effect_count: AtomicUsize::new(0),
+            volume: AtomicU32::new(1.0f32.to_bits()),
+            muted: AtomicBool::new(false),
+            solo: AtomicU8::new(0),
+            soloed_below: AtomicIsize::new(0),
+            sources_silenced: Arc::new(AtomicBool::new(false)),
+            parent,
+        };
+        let effects = Vec::with_capacity(MAX_EFFECTS);
+        Self { mixer, shared: Arc::new(shared), effects, rejected: None, clearing: false, gain: 1.0 }
+    }
+
+    /// Returns a new handle to this Bus.
+    pub fn handle(&self) -> BusHandle {
+        BusHandle { shared: self.shared.clone(), source: None }
+    }
+
+    /// Adds an effect to the end of this Bus's chain of effects.
+    /// Panics if the Bus already has 16 effects, which is as many as it can have.
+    pub fn with_effect(mut self, effect: impl Effect + Send + Sync + 'static) -> Self {
+        assert!(self.effects.len() < MAX_EFFECTS, "a bus can't have more than {} effects", MAX_EFFECTS);
+        self.effects.push(Box::new(effect));
+        self.shared.effect_count.fetch_add(1, Ordering::Relaxed);
+        self
+    }
+
+    /// Returns a reference to the Mixer.
+    pub fn mixer(&self) -> &M {
+        &self.mixer
+    }
+
+    /// Returns a mutable reference to the Mixer.
+    pub fn mixer_mut(&mut self) -> &mut M {
+        &mut self.mixer
+    }
+
+    // Sends a source back through the finished queue, giving it back if there's no room for it yet
+    fn send_back(
+        &self,
+        source: Controlled<Box<dyn Source + Send + Sync>>,
+    ) -> Option<Controlled<Box<dyn Source + Send + Sync>>> {
+        source.mark_finished();
+        self.shared.finished.as_ref().and_then(|finished| finished.push(source).err())
+    }
+
+    // Sends cleared effects back to be dropped, for as long as there's room for them
+    fn send_back_effects(&mut self) {
+        let count = self.effects.len();
+        while let Some(effect) = self.effects.pop() {
+            if let Err(effect) = self.shared.cleared_effects.push(effect) {
+                self.effects.push(effect);
+                break
+            }
+        }
+        self.shared.effect_count.fetch_sub(count - self.effects.len(), Ordering::Relaxed);
+        self.clearing = !self.effects.is_empty();
+    }
+
+    // Works out whether the Bus and the sources added to it should be silenced because other buses are soloed
+    fn update_solo(&self) -> bool {
+        let shared = &self.shared;
+        let root = shared.ancestors().last().unwrap_or(shared);
+        if root.soloed_below.load(Ordering::Relaxed) <= 0 {
+            shared.sources_silenced.store(false, Ordering::Relaxed);
+            return false
+        }
+
+        // Buses which are soloed are heard along with everything beneath them, and buses above them are heard so
+        // that they can pass them on, but the sources added straight to those buses are silenced
+        let soloed_above = shared.is_soloed() || shared.ancestors().any(Shared::is_soloed);
+        shared.sources_silenced.store(!soloed_above, Ordering::Relaxed);
+        !soloed_above && shared.soloed_below.load(Ordering::Relaxed) <= 0
+    }
+}
+
+impl<M: Mixer> Mixer for Bus<M> {
+    fn add_source_with(&mut self, source: impl Source + Send + Sync + 'static, options: SourceOptions) -> SourceHandle {
+        self.shared.drop_finished();
+        let source = self.shared.prepare(source, options);
+        let handle = source.handle();
+        let _ = self.mixer.add_controlled(source);
+        handle
+    }
+
+    fn add_controlled(
+        &mut self,
+        mut source: Controlled<Box<dyn Source + Send + Sync>>,
+    ) -> Result<(), Controlled<Box<dyn Source + Send + Sync>>> {
+        source.set_silenced_by(self.shared.sources_silenced.clone());
+        self.mixer.add_controlled(source)
+    }
+
+    fn finished_queue(&self) -> Option<Arc<FinishedQueue>> {
+        self.mixer.finished_queue()
+    }
+}
+
+impl<M: Mixer> Source for Bus<M> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        // Sources the Mixer turns away are sent back to be dropped, and nothing else is added until they've gone
+        if let Some(source) = self.rejected.take() {
+            self.rejected = self.send_back(source);
+        }
+        if self.clearing {
+            self.send_back_effects();
+        }
+        while self.rejected.is_none() && !self.clearing {
+            match self.shared.commands.pop() {
+                Some(Command::AddSource(source)) => {
+                    if let Err(source) = self.mixer.add_controlled(source) {
+                        self.rejected = self.send_back(source);
+                    }
+                },
+                // Handles make sure there's room for every effect they send
+                Some(Command::InsertEffect(effect)) => self.effects.push(effect),
+                Some(Command::ClearEffects) => self.send_back_effects(),
+                None => break,
+            }
+        }
+
+        let soloed_out = self.update_solo();
+        let count = self.mixer.write_samples(buffer);
+        let channels = self.mixer.channel_count().max(1);
+        if !self.clearing {
+            for effect in self.effects.iter_mut() {
+                effect.process(&mut buffer[..count], channels);
+            }
+        }
+
+        let shared = &self.shared;
+        let silenced = shared.muted.load(Ordering::Relaxed) || soloed_out;
+        let target = if silenced { 0.0 } else { f32::from_bits(shared.volume.load(Ordering::Relaxed)) };
+        if self.gain == target {
+            if target != 1.0 {
+                buffer[..count].iter_mut().for_each(|s| *s *= target);
+            }
+        } else {
+            let step = (target - self.gain) / (count / channels).max(1) as f32;
+            for frame in buffer[..count].chunks_mut(channels) {
+                self.gain += step;
+                frame.iter_mut().for_each(|s| *s *= self.gain);
+            }
+            self.gain = target;
+        }
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.mixer.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.mixer.sample_rate()
+    }
+}
+
+impl<M: Mixer> Drop for Bus<M> {
+    fn drop(&mut self) {
+        // Stop silencing the rest of the tree if this bus is gone while soloed
+        self.shared.remove();
+    }
+}
+
+impl BusHandle {
+    /// Adds an audio source to the Bus, returning a handle which can control it.
+    /// If the source reports a sample rate which differs from the Bus's, it will be resampled.
+    /// This fails if the Bus has too many sources and effects waiting to be added, such as if it isn't being played.
+    pub fn add_source(&self, source: impl Source + Send + Sync + 'static) -> Result<SourceHandle, Error> {
+        self.add_source_with(source, SourceOptions::default())
+    }
+
+    /// Adds an audio source like add_source does, but with the given volume and pan position to start with.
+    pub fn add_source_with(
+        &self,
+        source: impl Source + Send + Sync + 'static,
+        options: SourceOptions,
+    ) -> Result<SourceHandle, Error> {
+        // Everything which frees memory is done here too, so that the audio thread never has to
+        self.shared.drop_finished();
+        self.send(self.shared.prepare(source, options))
+    }
+
+    /// Adds a new child Bus which mixes its sources with `mixer` and feeds into this one, returning a handle to it.
+    pub fn add_bus<M: Mixer + Send + Sync + 'static>(&self, mixer: M) -> Result<BusHandle, Error> {
+        self.shared.drop_finished();
+        let bus = Bus::with_parent(mixer, Some(self.shared.clone()));
+        let shared = bus.shared.clone();
+
+        // Child buses aren't silenced along with the sources added to this one, since they look after that
+        // themselves
+        let source: Box<dyn Source + Send + Sync> = match (bus.sample_rate(), self.shared.sample_rate) {
+            (Some(from), Some(to)) if from != to => Box::new(Resampler::new(bus, from, to)),
+            _ => Box::new(bus),
+        };
+        let source = self.send(Controlled::new(source, SourceOptions::default()))?;
+        Ok(BusHandle { shared, source: Some(source) })
+    }
+
+    /// Removes the Bus from its parent, fading it and everything playing through it out over the next block.
+    /// This has no effect on a Bus without a parent.
+    pub fn remove(&self) {
+        if let Some(source) = &self.source {
+            self.shared.remove();
+            source.stop();
+        }
+    }
+
+    /// Adds an effect to the end of the Bus's chain of effects.
+    /// This fails if the Bus already has 16 effects, which is as many as it can have, or if it has too many sources
+    /// and effects waiting to be added.
+    pub fn insert_effect(&self, effect: impl Effect + Send + Sync + 'static) -> Result<(), Error> {
+        let shared = &self.shared;
+        shared.drop_finished();
+        let reserved = shared.effect_count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            if count < MAX_EFFECTS { Some(count + 1) } else { None }
+        });
+        if reserved.is_err() {
+            return Err(Error::TooManyEffects)
+        }
+        match shared.commands.push(Command::InsertEffect(Box::new(effect))) {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                shared.effect_count.fetch_sub(1, Ordering::Relaxed);
+                Err(Error::CommandQueueFull)
+            },
+        }
+    }
+
+    /// Removes every effect from the Bus, including any which are still waiting to be added. Their places are free
+    /// for new effects once the Bus has next written samples.
+    /// This fails if the Bus has too many sources and effects waiting to be added.
+    pub fn clear_effects(&self) -> Result<(), Error> {
+        self.shared.drop_finished();
+        match self.shared.commands.push(Command::ClearEffects) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::CommandQueueFull),
+        }
+    }
+
+    /// Sets the gain which the Bus's whole mix is multiplied by. This is 1.0 by default.
+    pub fn set_volume(&self, volume: f32) {
+        self.shared.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the gain which the Bus's whole mix is multiplied by.
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.shared.volume.load(Ordering::Relaxed))
+    }
+
+    /// Mutes or unmutes the Bus. Its sources keep playing while it's muted, they just can't be heard.
+    pub fn set_muted(&self, muted: bool) {
+        self.shared.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Returns whether the Bus is muted.
+    pub fn is_muted(&self) -> bool {
+        self.shared.muted.load(Ordering::Relaxed)
+    }
+
+    /// Solos or unsolos the Bus. While any bus in the same tree is soloed, everything which isn't soloed, beneath a
+    /// soloed bus or on the way up to one is silenced (see Bus).
+    /// This has no effect on a Bus without a parent, or one which has been removed.
+    pub fn set_soloed(&self, soloed: bool) {
+        let shared = &self.shared;
+        if shared.parent.is_none() {
+            return
+        }
+
+        // This does nothing once the bus has been removed, since its state is no longer either of these
+        let (from, to, delta) = if soloed { (0, SOLOED, 1) } else { (SOLOED, 0, -1) };
+        if shared.solo.compare_exchange(from, to, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            shared.count_solo(delta);
+        }
+    }
+
+    /// Returns whether the Bus is soloed.
+    pub fn is_soloed(&self) -> bool {
+        self.shared.is_soloed()
+    }
+
+    /// Returns the number of channels the Bus mixes its sources into.
+    pub fn channel_count(&self) -> usize {
+        self.shared.channels
+    }
+
+    // Sends a source over to the Bus, returning its handle
+    fn send(&self, source: Controlled<Box<dyn Source + Send + Sync>>) -> Result<SourceHandle, Error> {
+        let handle = source.handle();
+        match self.shared.commands.push(Command::AddSource(source)) {
+            Ok(()) => Ok(handle),
+            Err(_) => Err(Error::CommandQueueFull),
+        }
+    }
+}
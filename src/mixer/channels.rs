@@ -0,0 +1,159 @@
+use self::Speaker::*;
+use crate::handle::clamp_pan;
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_2, FRAC_PI_4, SQRT_2};
+
+// The speakers which the standard channel layouts are made of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    SideLeft,
+    SideRight,
+}
+
+/// A matrix of gains which maps audio with one number of channels onto another, such as downmixing 5.1 to stereo.
+/// Each output channel is the sum of every input channel multiplied by its gain for that output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMatrix {
+    inputs: usize,
+    outputs: usize,
+
+    // The gains for each output channel in turn, with one for each input channel
+    gains: Vec<f32>,
+}
+
+impl ChannelMatrix {
+    /// Constructs a matrix from its gains, given as a row of `inputs` gains for each output channel in turn.
+    /// Panics if there aren't exactly `inputs * outputs` gains.
+    pub fn new(inputs: usize, outputs: usize, gains: Vec<f32>) -> Self {
+        assert_eq!(gains.len(), inputs * outputs, "a channel matrix needs a gain for every input and output pair");
+        Self { inputs, outputs, gains }
+    }
+
+    /// Returns the standard matrix for mapping between two channel counts.
+    /// Mono, stereo, quad, 5.1 and 7.1 are mapped onto each other with the usual coefficients (as in ITU-R BS.775),
+    /// in the order WAV files use: front left, front right, front center, LFE, back left, back right, side left,
+    /// side right. Speakers which the output doesn't have are folded into the nearest ones it does at -3dB, apart
+    /// from the LFE channel, which is dropped. Channels aren't made up when upmixing, so stereo played on a 5.1 system
+    /// only comes out of the front left and right speakers.
+    /// Any other channel counts are mapped straight across, with mono going to every channel.
+    pub fn standard(inputs: usize, outputs: usize) -> Self {
+        let mut gains = vec![0.0; inputs * outputs];
+        match (layout(inputs), layout(outputs)) {
+            (Some(from), Some(to)) => {
+                for (input, &speaker) in from.iter().enumerate() {
+                    fold(speaker, to, 1.0, &mut |output, gain| gains[output * inputs + input] += gain);
+                }
+            },
+            _ if inputs == 1 => gains.iter_mut().for_each(|gain| *gain = 1.0),
+            _ => (0..inputs.min(outputs)).for_each(|channel| gains[channel * inputs + channel] = 1.0),
+        }
+        Self { inputs, outputs, gains }
+    }
+
+    /// Returns the number of channels this matrix takes in.
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    /// Returns the number of channels this matrix puts out.
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    /// Returns the gain which an input channel is multiplied by before it's added to an output channel.
+    pub fn gain(&self, input: usize, output: usize) -> f32 {
+        self.gains[output * self.inputs + input]
+    }
+
+    /// Mixes interleaved `input` frames into interleaved `output` frames, adding to what's already there.
+    /// Stops at whichever runs out of frames first.
+    pub fn mix(&self, input: &[f32], output: &mut [f32]) {
+        if self.inputs == 0 || self.outputs == 0 {
+            return
+        }
+        for (in_frame, out_frame) in input.chunks_exact(self.inputs).zip(output.chunks_exact_mut(self.outputs)) {
+            for (out_sample, gains) in out_frame.iter_mut().zip(self.gains.chunks_exact(self.inputs)) {
+                *out_sample += in_frame.iter().zip(gains).map(|(sample, gain)| sample * gain).sum::<f32>();
+            }
+        }
+    }
+}
+
+// Fills `gains` with how much to turn each channel of a layout with `gains.len()` channels up or down by to pan it
+// to `pan`, from -1.0 (left) to 1.0 (right). The channels on either side follow a constant-power law which leaves
+// them at unity in the center, and the rest are left alone. Layouts which aren't known aren't panned at all.
+pub(super) fn pan_gains(pan: f32, gains: &mut [f32]) {
+    let angle = (clamp_pan(pan) + 1.0) * FRAC_PI_4;
+    let (left, right) = (SQRT_2 * angle.cos(), SQRT_2 * angle.sin());
+    gains.iter_mut().for_each(|gain| *gain = 1.0);
+    if let Some(layout) = layout(gains.len()).filter(|layout| layout.len() > 1) {
+        for (gain, speaker) in gains.iter_mut().zip(layout) {
+            match speaker {
+                FrontLeft | BackLeft | SideLeft => *gain = left,
+                FrontRight | BackRight | SideRight => *gain = right,
+                _ => (),
+            }
+        }
+    }
+}
+
+// Returns the front left, center and front right channels of a layout with `channels` channels, if it has all three.
+// Mono sources go straight to the center speaker of these layouts, so they're panned across all three instead.
+pub(super) fn front_channels(channels: usize) -> Option<[usize; 3]> {
+    let layout = layout(channels)?;
+    let find = |speaker| layout.iter().position(|&s| s == speaker);
+    Some([find(FrontLeft)?, find(FrontCenter)?, find(FrontRight)?])
+}
+
+// Fills `gains` with how much each channel of a mono source which has been copied to the `front` channels is
+// turned up or down by to pan it to `pan`. It moves from the left to the center and then to the right, following a
+// constant-power law between each pair, so it's only in the center speaker when it's in the center.
+pub(super) fn front_pan_gains(pan: f32, front: [usize; 3], gains: &mut [f32]) {
+    let pan = clamp_pan(pan);
+    let angle = pan.abs() * FRAC_PI_2;
+    let side = if pan < 0.0 { front[0] } else { front[2] };
+    gains.iter_mut().for_each(|gain| *gain = 0.0);
+    gains[front[1]] = angle.cos();
+    gains[side] = angle.sin();
+}
+
+// Returns the speakers in a standard layout with this many channels
+fn layout(channels: usize) -> Option<&'static [Speaker]> {
+    match channels {
+        1 => Some(&[FrontCenter]),
+        2 => Some(&[FrontLeft, FrontRight]),
+        4 => Some(&[FrontLeft, FrontRight, BackLeft, BackRight]),
+        6 => Some(&[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight]),
+        8 => Some(&[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight, SideLeft, SideRight]),
+        _ => None,
+    }
+}
+
+// Calls `add` with the index and gain of each speaker in `layout` which a speaker's audio should go to, folding it
+// into the nearest speakers if the layout doesn't have it
+fn fold(speaker: Speaker, layout: &[Speaker], gain: f32, add: &mut impl FnMut(usize, f32)) {
+    if let Some(index) = layout.iter().position(|&s| s == speaker) {
+        return add(index, gain)
+    }
+    let has = |speaker| layout.contains(&speaker);
+    let gain = gain * FRAC_1_SQRT_2;
+    match speaker {
+        FrontCenter => {
+            fold(FrontLeft, layout, gain, add);
+            fold(FrontRight, layout, gain, add);
+        },
+        FrontLeft | FrontRight => fold(FrontCenter, layout, gain, add),
+        LowFrequency => (),
+        SideLeft if has(BackLeft) => fold(BackLeft, layout, gain, add),
+        SideRight if has(BackRight) => fold(BackRight, layout, gain, add),
+        BackLeft if has(SideLeft) => fold(SideLeft, layout, gain, add),
+        BackRight if has(SideRight) => fold(SideRight, layout, gain, add),
+        SideLeft | BackLeft => fold(FrontLeft, layout, gain, add),
+        SideRight | BackRight => fold(FrontRight, layout, gain, add),
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{mixer, source::frames_in, Mixer, Source, SourceHandle, SourceOptions};
+use std::time::Duration;
+
+// The default number of frames in each block, roughly what an output device would ask for
+const DEFAULT_BLOCK_FRAMES: usize = 512;
+
+/// A headless alternative to OutputStream, which renders a Mixer's output into memory instead of playing it.
+/// Audio is pulled from the Mixer in fixed-size blocks, just like an output device would, but as fast as possible.
+/// This is useful for testing, or for rendering audio on machines without any output devices.
+pub struct OfflineRenderer<M>
+where
+    M: Mixer,
+{
+    mixer: M,
+    block_frames: usize,
+    block: Vec<f32>,
+    pub sample_rate: u32,
+    pub channel_count: u16,
+}
+
+impl<M> OfflineRenderer<M>
+where
+    M: Mixer,
+{
+    /// Sets up and returns an OfflineRenderer with the given output format.
+    /// Like OutputStream::with, this takes a closure which sets up a Mixer given the output channel count.
+    pub fn with<F>(sample_rate: u32, channel_count: u16, mixer_setup: F) -> Self
+    where
+        F: FnOnce(u16) -> M,
+    {
+        let mixer = mixer_setup(channel_count);
+        let block_frames = DEFAULT_BLOCK_FRAMES;
+        let block = vec![0.0; block_frames * usize::from(channel_count)];
+        Self { mixer, block_frames, block, sample_rate, channel_count }
+    }
+
+    /// Sets how many frames are pulled from the Mixer at a time.
+    pub fn with_block_size(mut self, frames: usize) -> Self {
+        assert!(frames != 0);
+        self.block_frames = frames;
+        self.block.resize(frames * usize::from(self.channel_count), 0.0);
+        self
+    }
+
+    /// Adds an audio source to the renderer, returning a handle which can control it.
+    /// The source will be played until it ends or is stopped.
+    /// If the source reports a sample rate which differs from the renderer's, it will be resampled.
+    pub fn add_source(&mut self, source: impl Source + Send + Sync + 'static) -> SourceHandle {
+        self.add_source_with(source, SourceOptions::default())
+    }
+
+    /// Adds an audio source like add_source does, but with the given volume and pan position to start with.
+    pub fn add_source_with(
+        &mut self,
+        source: impl Source + Send + Sync + 'static,
+        options: SourceOptions,
+    ) -> SourceHandle {
+        mixer::add_resampled(&mut self.mixer, source, options, self.sample_rate)
+    }
+
+    /// Renders the next block of audio, returning its interleaved samples.
+    pub fn render_block(&mut self) -> &[f32] {
+        self.mixer.write_samples(&mut self.block);
+        &self.block
+    }
+
+    /// Fills a buffer with interleaved samples, one block at a time.
+    /// If the buffer isn't a whole number of blocks long, the last block will be shorter than the rest.
+    pub fn render_into(&mut self, buffer: &mut [f32]) {
+        let block_len = self.block.len();
+        for block in buffer.chunks_mut(block_len) {
+            self.mixer.write_samples(block);
+        }
+    }
+
+    /// Renders the given amount of time, returning its interleaved samples.
+    pub fn render_duration(&mut self, time: Duration) -> Vec<f32> {
+        let frames = frames_in(time, self.sample_rate) as usize;
+        let mut buffer = vec![0.0; frames * usize::from(self.channel_count)];
+        self.render_into(&mut buffer);
+        buffer
+    }
+
+    /// Renders `blocks` blocks of audio, passing each one to `sink` as it's finished.
+    /// The sink could write each block to a WavWriter, for example.
+    pub fn render_blocks<F>(&mut self, blocks: usize, mut sink: F)
+    where
+        F: FnMut(&[f32]),
+    {
+        for _ in 0..blocks {
+            sink(self.render_block());
+        }
+    }
+
+    /// Returns a reference to the Mixer.
+    pub fn mixer(&self) -> &M {
+        &self.mixer
+    }
+
+    /// Returns a mutable reference to the Mixer.
+    pub fn mixer_mut(&mut self) -> &mut M {
+        &mut self.mixer
+    }
+
+    /// Consumes the renderer, returning its Mixer.
+    pub fn into_inner(self) -> M {
+        self.mixer
+    }
+}
@@ -1,7 +1,9 @@
-use crate::Source;
+use crate::{source::Length, Seekable, Source};
+use std::convert::TryFrom;
 
 /// Implementation of a PQF resampler. Construct with: Resampler::new(source, source_rate, dest_rate)
 /// Once constructed, it will behave as a Source object which outputs samples at the target sample rate.
+/// If the source knows its own sample rate, Resampler::with_rate(source, dest_rate) can be used instead.
 pub struct Resampler<S>
 where
     S: Source,
@@ -9,6 +11,7 @@ where
     source: S,
     from: u32,
     to: u32,
+    dest_rate: u32,
     left_offset: usize,
     kaiser_values: Box<[f64]>,
     filter_1: Box<[f32]>,
@@ -31,7 +34,14 @@ where
 }
 
 impl<S: Source> Resampler<S> {
-    pub fn new(mut source: S, source_rate: u32, dest_rate: u32) -> Self {
+    /// Constructs a Resampler which converts from the source's own sample rate to `dest_rate`.
+    /// If the source doesn't report a sample rate, it's assumed to already be at `dest_rate`.
+    pub fn with_rate(source: S, dest_rate: u32) -> Self {
+        let source_rate = source.sample_rate().unwrap_or(dest_rate);
+        Self::new(source, source_rate, dest_rate)
+    }
+
+    pub fn new(source: S, source_rate: u32, dest_rate: u32) -> Self {
         assert!(source_rate != 0);
         assert!(dest_rate != 0);
 
@@ -75,7 +85,7 @@ impl<S: Source> Resampler<S> {
 
             #[inline]
             fn kaiser(k: f64) -> f64 {
-                if k < -1.0 || k > 1.0 {
+                if !(-1.0..=1.0).contains(&k) {
                     0.0
                 } else {
                     // 6.20426 is the Kaiser beta value for a rejection of 65 dB.
@@ -121,20 +131,11 @@ impl<S: Source> Resampler<S> {
             filter_2.set_len(filter_samples);
         }
 
-        let last_sample = {
-            let len = source.write_samples(&mut filter_1);
-            if len == filter_samples {
-                let len = source.write_samples(&mut filter_2);
-                if len == filter_samples { None } else { Some(len) }
-            } else {
-                Some(len)
-            }
-        };
-
-        Self {
+        let mut resampler = Self {
             source,
             from,
             to,
+            dest_rate,
             left_offset,
             kaiser_values,
             filter_1: filter_1.into_boxed_slice(),
@@ -143,8 +144,33 @@ impl<S: Source> Resampler<S> {
             buffer_size: filter_samples,
             input_offset: 0,
             output_count: 0,
-            last_sample,
-        }
+            last_sample: None,
+        };
+        resampler.refill();
+        resampler
+    }
+
+    // Converts a number of input frames, counted from the start of the filters, into the number of output frames
+    // which will be written before the filter window passes them
+    fn output_frames_for(&self, input_frames: u64) -> u64 {
+        let from = u64::from(self.from);
+        let upscaled = (input_frames * u64::from(self.to)).saturating_sub(self.left_offset as u64);
+        (upscaled + from - 1) / from
+    }
+
+    // Discards everything in both filters and fills them again from the source's current position
+    fn refill(&mut self) {
+        self.input_offset = 0;
+        self.output_count = 0;
+        self.last_sample = {
+            let len = self.source.write_samples(&mut self.filter_1);
+            if len == self.buffer_size {
+                let len = self.source.write_samples(&mut self.filter_2);
+                if len == self.buffer_size { None } else { Some(self.buffer_size + len) }
+            } else {
+                Some(len)
+            }
+        };
     }
 }
 
@@ -244,4 +270,40 @@ impl<S: Source> Source for Resampler<S> {
     fn channel_count(&self) -> usize {
         self.source.channel_count()
     }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.dest_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.source.total_frames() {
+            Length::Exact(frames) => Length::Exact(self.output_frames_for(frames)),
+            Length::Unknown => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.source.remaining_frames() {
+            Length::Exact(frames) => {
+                // Everything that's been read from the source since the filters were last refilled,
+                // plus everything the source still has left to give
+                let channels = self.source.channel_count() as u64;
+                let filled = self.last_sample.unwrap_or(self.whole_filter_size) as u64;
+                let input_frames = (self.input_offset + filled) / channels + frames;
+                let written = (self.output_count / self.source.channel_count()) as u64;
+                Length::Exact(self.output_frames_for(input_frames).saturating_sub(written))
+            },
+            Length::Unknown => Length::Unknown,
+        }
+    }
+}
+
+impl<S: Seekable> Seekable for Resampler<S> {
+    fn seek_frame(&mut self, frame: u64) {
+        // Seek to the input frame which lines up with the requested output frame, then flush the filters
+        // so that none of the audio from before the seek can bleed into the output.
+        let source_frame = u128::from(frame) * u128::from(self.from) / u128::from(self.to);
+        self.source.seek_frame(u64::try_from(source_frame).unwrap_or(u64::MAX));
+        self.refill();
+    }
 }
@@ -1,4 +1,22 @@
+pub mod aiff;
+mod ext;
+#[cfg(feature = "flac")]
+pub mod flac;
+#[cfg(feature = "mp3")]
+pub mod mp3;
+#[cfg(feature = "opus")]
+pub mod opus;
+#[cfg(feature = "tracker")]
+pub mod tracker;
+#[cfg(feature = "vorbis")]
+pub mod vorbis;
 pub mod wav;
+#[cfg(any(feature = "flac", feature = "opus", feature = "vorbis"))]
+mod xiph;
+
+pub use ext::{Amplify, Chain, Delay, FadeIn, FadeOut, Repeat, SkipDuration, SourceExt, TakeDuration};
+
+use std::{convert::TryFrom, time::Duration};
 
 /// An audio source. Anything implementing this trait may be played to an output stream.
 pub trait Source {
@@ -8,4 +26,93 @@ pub trait Source {
 
     /// Returns the number of channels in this Source object's audio data.
     fn channel_count(&self) -> usize;
+
+    /// Returns the sample rate of this Source object's audio data (eg. 44100), if it's known.
+    /// Sources which return None are assumed to already be at the rate of whatever they're being played into.
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the total length of this Source object's audio data, in frames (one sample for each channel).
+    fn total_frames(&self) -> Length {
+        Length::Unknown
+    }
+
+    /// Returns the number of frames this Source object has left to write before it's exhausted.
+    fn remaining_frames(&self) -> Length {
+        Length::Unknown
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        (**self).write_samples(buffer)
+    }
+
+    fn channel_count(&self) -> usize {
+        (**self).channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        (**self).sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        (**self).total_frames()
+    }
+
+    fn remaining_frames(&self) -> Length {
+        (**self).remaining_frames()
+    }
+}
+
+/// A region of an audio source to be looped, in frames. `end` is the frame after the last one to be played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopRegion {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl LoopRegion {
+    // Converts this region into offsets of `frame_size` units each (eg. samples or bytes), clamped to `len`
+    pub(crate) fn scaled_range(&self, frame_size: usize, len: usize) -> (usize, usize) {
+        let scale = |frame: u64| usize::try_from(frame).unwrap_or(usize::MAX).saturating_mul(frame_size).min(len);
+        (scale(self.start), scale(self.end))
+    }
+}
+
+/// The length of an audio source, in frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Length {
+    /// The source is exactly this many frames long
+    Exact(u64),
+
+    /// The source can't tell how long it is, or never ends
+    Unknown,
+}
+
+/// An audio source which can jump to any point in its audio data.
+pub trait Seekable: Source {
+    /// Moves playback to the given frame, where a frame is one sample for each channel.
+    /// Seeking past the end is allowed, and leaves the Source exhausted.
+    fn seek_frame(&mut self, frame: u64);
+
+    /// Moves playback to the given point in time, rounded down to the nearest frame.
+    /// Returns false without seeking if this Source doesn't know its sample rate.
+    fn seek_duration(&mut self, time: Duration) -> bool {
+        match self.sample_rate() {
+            Some(rate) => {
+                self.seek_frame(frames_in(time, rate));
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// Converts a Duration into a number of frames at the given sample rate, rounded down
+pub(crate) fn frames_in(time: Duration, sample_rate: u32) -> u64 {
+    let rate = u64::from(sample_rate);
+    let nanos = u64::from(time.subsec_nanos()) * rate / 1_000_000_000;
+    time.as_secs().saturating_mul(rate).saturating_add(nanos)
 }
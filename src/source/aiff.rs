@@ -0,0 +1,281 @@
+use super::{
+    wav::{self, ByteOrder, Format},
+    Length, Seekable, Source,
+};
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
+
+/// A Source which plays an AIFF or AIFF-C file.
+/// By default it plays from a file in memory, but it can also stream from any reader (see `from_reader`).
+pub struct AiffPlayer<R: Read + Seek = Cursor<Vec<u8>>> {
+    reader: R,
+    header: Header,
+    bytes: Vec<u8>,
+
+    // The index of the next sample to be read from the sound data
+    position: usize,
+
+    // Whether the reader needs to be moved to `position` before reading from it again
+    needs_seek: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// This does not appear to be an AIFF or AIFF-C file
+    InvalidFile,
+
+    /// The audio data in this file is malformed
+    MalformedData,
+
+    /// The audio data in this file is encoded in a way we don't support
+    UnknownFormat,
+
+    /// The underlying reader returned an error while the file was being read
+    Io(io::ErrorKind),
+}
+
+impl AiffPlayer {
+    pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        Self::from_reader(Cursor::new(file.into()))
+    }
+}
+
+impl<R: Read + Seek> AiffPlayer<R> {
+    /// Creates an AiffPlayer which streams its file from a reader, only reading as much as it needs at a time.
+    pub fn from_reader(mut reader: R) -> Result<Self, Error> {
+        let header = read_header(&mut reader)?;
+        Ok(Self { reader, header, bytes: Vec::new(), position: 0, needs_seek: true })
+    }
+
+    /// Returns the total number of samples in this AIFF file
+    pub fn length(&self) -> usize {
+        self.header.length
+    }
+
+    /// Consumes this AiffPlayer, returning the reader it was streaming from.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read + Seek> Source for AiffPlayer<R> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let sample_count = buffer.len().min(self.header.length - self.position);
+        if sample_count == 0 {
+            return 0
+        }
+        let buffer = &mut buffer[..sample_count];
+
+        let sample_bytes = self.header.encoding.sample_bytes();
+        if self.needs_seek {
+            let offset = self.header.data_start + (self.position * sample_bytes) as u64;
+            if self.reader.seek(SeekFrom::Start(offset)).is_err() {
+                return 0
+            }
+            self.needs_seek = false;
+        }
+
+        self.bytes.resize(sample_count * sample_bytes, 0);
+        let filled = wav::read_fully(&mut self.reader, &mut self.bytes);
+        let samples_written = self.header.encoding.decode(&self.bytes[..filled], buffer);
+        if filled != samples_written * sample_bytes {
+            // We read part of a sample, so the reader is no longer where we think it is
+            self.needs_seek = true;
+        }
+        self.position += samples_written;
+        samples_written
+    }
+
+    fn channel_count(&self) -> usize {
+        self.header.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.header.sample_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        Length::Exact((self.header.length / self.header.channels) as u64)
+    }
+
+    fn remaining_frames(&self) -> Length {
+        Length::Exact(((self.header.length - self.position) / self.header.channels) as u64)
+    }
+}
+
+impl<R: Read + Seek> Seekable for AiffPlayer<R> {
+    fn seek_frame(&mut self, frame: u64) {
+        let sample = usize::try_from(frame).unwrap_or(usize::MAX).saturating_mul(self.header.channels);
+        self.position = sample.min(self.header.length);
+        self.needs_seek = true;
+    }
+}
+
+// Everything we need to know about an AIFF file before we can start decoding it
+#[derive(Clone, Debug)]
+struct Header {
+    encoding: Encoding,
+    channels: usize,
+    sample_rate: u32,
+    data_start: u64,
+
+    // The total number of samples in the sound data
+    length: usize,
+}
+
+// How the samples in the sound data are stored
+#[derive(Clone, Copy, Debug)]
+enum Encoding {
+    // Signed 8-bit, which wav files don't have (their 8-bit samples are unsigned)
+    I8,
+
+    // Anything else is stored the same way a wav file would store it, in the given byte order
+    Wav(Format, ByteOrder),
+}
+
+impl Encoding {
+    fn sample_bytes(self) -> usize {
+        match self {
+            Encoding::I8 => 1,
+            Encoding::Wav(format, _) => format.sample_bytes(),
+        }
+    }
+
+    // Decodes as many whole samples from `data` as will fit in `buffer`, returning how many were written
+    fn decode(self, data: &[u8], buffer: &mut [f32]) -> usize {
+        match self {
+            Encoding::I8 => {
+                let iter = buffer.iter_mut().zip(data.iter().copied());
+                let samples_written = iter.len();
+                iter.for_each(|(out, b)| *out = f32::from(b as i8) / f32::from(i8::MAX));
+                samples_written
+            },
+            Encoding::Wav(format, order) => wav::decode_samples(format, order, data, buffer),
+        }
+    }
+}
+
+// Walks through every chunk in an AIFF file, reading the ones we're interested in and skipping over the rest.
+// This leaves the reader at an unspecified position.
+fn read_header<R: Read + Seek>(reader: &mut R) -> Result<Header, Error> {
+    let mut form_header = [0u8; 12];
+    match read_exact(reader, &mut form_header) {
+        Ok(()) => (),
+        Err(Error::MalformedData) => return Err(Error::InvalidFile),
+        Err(e) => return Err(e),
+    }
+    let is_aifc = match (&form_header[0..4], &form_header[8..12]) {
+        (b"FORM", b"AIFF") => false,
+        (b"FORM", b"AIFC") => true,
+        _ => return Err(Error::InvalidFile),
+    };
+
+    let mut comm = None;
+    let mut data = None;
+    let mut chunk_start: u64 = 12;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        match read_exact(reader, &mut chunk_header) {
+            Ok(()) => (),
+            Err(Error::MalformedData) => break,
+            Err(e) => return Err(e),
+        }
+        let chunk_len =
+            u64::from(u32::from_be_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]));
+        let body_start = chunk_start + 8;
+
+        match &chunk_header[0..4] {
+            b"COMM" => comm = Some(read_body(reader, chunk_len)?),
+            b"SSND" => {
+                // The sound data can start a little way into the chunk, so that it lines up with blocks of some size
+                let mut offset = [0u8; 4];
+                read_exact(reader, &mut offset)?;
+                let offset = u64::from(u32::from_be_bytes(offset));
+                data = Some((body_start + 8 + offset, chunk_len.saturating_sub(8 + offset)));
+            },
+            _ => (),
+        }
+
+        // Chunks are padded to an even number of bytes
+        chunk_start = body_start + chunk_len + (chunk_len & 1);
+        reader.seek(SeekFrom::Start(chunk_start)).map_err(|e| Error::Io(e.kind()))?;
+    }
+
+    // AIFF-C files add a compression type to the end of the common chunk
+    let comm_len = if is_aifc { 22 } else { 18 };
+    let (comm, (data_start, data_len)) = match (comm, data) {
+        (Some(comm), Some(data)) if comm.len() >= comm_len => (comm, data),
+        _ => return Err(Error::InvalidFile),
+    };
+
+    let channels = u16::from_be_bytes([comm[0], comm[1]]);
+    let frames = u32::from_be_bytes([comm[2], comm[3], comm[4], comm[5]]);
+    let sample_bits = u16::from_be_bytes([comm[6], comm[7]]);
+    let sample_rate = parse_extended(&comm[8..18]).round();
+    if channels == 0 || !(1.0..=f64::from(u32::MAX)).contains(&sample_rate) {
+        return Err(Error::InvalidFile)
+    }
+    let channels = usize::from(channels);
+
+    // Samples which don't fill a whole number of bytes are left-aligned in them, so they can be decoded as if they
+    // did. Plain AIFF files are always big-endian PCM.
+    let compression_type = if is_aifc { &comm[18..22] } else { b"NONE" };
+    let pcm = |order| match sample_bits {
+        1..=8 => Ok(Encoding::I8),
+        9..=16 => Ok(Encoding::Wav(Format::I16, order)),
+        17..=24 => Ok(Encoding::Wav(Format::I24, order)),
+        25..=32 => Ok(Encoding::Wav(Format::I32, order)),
+        _ => Err(Error::UnknownFormat),
+    };
+    let encoding = match compression_type {
+        b"NONE" | b"twos" => pcm(ByteOrder::Big)?,
+        b"sowt" => pcm(ByteOrder::Little)?,
+        b"raw " if sample_bits == 8 => Encoding::Wav(Format::U8, ByteOrder::Big),
+        b"in24" => Encoding::Wav(Format::I24, ByteOrder::Big),
+        b"in32" => Encoding::Wav(Format::I32, ByteOrder::Big),
+        b"fl32" | b"FL32" => Encoding::Wav(Format::F32, ByteOrder::Big),
+        b"fl64" | b"FL64" => Encoding::Wav(Format::F64, ByteOrder::Big),
+        b"alaw" | b"ALAW" => Encoding::Wav(Format::ALaw, ByteOrder::Big),
+        b"ulaw" | b"ULAW" => Encoding::Wav(Format::MuLaw, ByteOrder::Big),
+        _ => return Err(Error::UnknownFormat),
+    };
+
+    let file_length = reader.seek(SeekFrom::End(0)).map_err(|e| Error::Io(e.kind()))?;
+    if data_start + data_len > file_length {
+        return Err(Error::MalformedData)
+    }
+
+    // The common chunk says how many frames there are, but the sound data may be padded out past them
+    let length = (data_len / encoding.sample_bytes() as u64).min(u64::from(frames) * channels as u64) as usize;
+    Ok(Header { encoding, channels, sample_rate: sample_rate as u32, data_start, length })
+}
+
+// Reads an 80-bit IEEE 754 extended precision number, which is how AIFF files store their sample rate.
+// These have a 15-bit exponent and a 64-bit mantissa with an explicit leading 1.
+fn parse_extended(bytes: &[u8]) -> f64 {
+    let sign_exponent = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let mut mantissa = [0u8; 8];
+    mantissa.copy_from_slice(&bytes[2..10]);
+    let mantissa = u64::from_be_bytes(mantissa);
+
+    let exponent = i32::from(sign_exponent & 0x7FFF) - 16383 - 63;
+    let magnitude = mantissa as f64 * 2f64.powi(exponent);
+    if sign_exponent & 0x8000 != 0 { -magnitude } else { magnitude }
+}
+
+// Fills the buffer from the reader, returning MalformedData if the reader runs out first
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::MalformedData,
+        kind => Error::Io(kind),
+    })
+}
+
+// Reads the body of a chunk, which may come up short if the file is truncated
+fn read_body<R: Read>(reader: &mut R, chunk_len: u64) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    reader.take(chunk_len).read_to_end(&mut body).map_err(|e| Error::Io(e.kind()))?;
+    Ok(body)
+}
@@ -0,0 +1,411 @@
+use super::{frames_in, Length, Seekable, Source};
+use std::time::Duration;
+
+/// Extension methods which wrap a Source in a commonly-used effect. This is implemented for every Source.
+/// All of these work on whole frames, so they'll stay aligned to the source's channels.
+/// Anything which takes a Duration returns None if the source doesn't know its sample rate, in which case the
+/// version which takes a number of frames can be used instead.
+pub trait SourceExt: Source + Sized {
+    /// Multiplies every sample by `gain`.
+    fn amplify(self, gain: f32) -> Amplify<Self> {
+        Amplify { source: self, gain }
+    }
+
+    /// Plays only the first `time` of this source, then ends.
+    fn take_duration(self, time: Duration) -> Option<TakeDuration<Self>> {
+        let frames = duration_frames(&self, time)?;
+        Some(self.take_frames(frames))
+    }
+
+    /// Plays only the first `frames` frames of this source, then ends.
+    fn take_frames(self, frames: u64) -> TakeDuration<Self> {
+        TakeDuration { remaining: frames.saturating_mul(self.channel_count() as u64), frames, source: self }
+    }
+
+    /// Discards the first `time` of this source, then plays the rest of it.
+    fn skip_duration(self, time: Duration) -> Option<SkipDuration<Self>> {
+        let frames = duration_frames(&self, time)?;
+        Some(self.skip_frames(frames))
+    }
+
+    /// Discards the first `frames` frames of this source, then plays the rest of it.
+    fn skip_frames(self, frames: u64) -> SkipDuration<Self> {
+        SkipDuration { remaining: frames.saturating_mul(self.channel_count() as u64), frames, source: self }
+    }
+
+    /// Plays this source until it ends, then plays `next`.
+    /// Both sources must have the same channel count, and should have the same sample rate.
+    fn chain<S: Source>(self, next: S) -> Chain<Self, S> {
+        assert_eq!(self.channel_count(), next.channel_count(), "chained sources must have the same channel count");
+        Chain { first: self, second: next, first_done: false }
+    }
+
+    /// Plays this source forever, seeking back to the start every time it ends.
+    fn repeat(self) -> Repeat<Self>
+    where
+        Self: Seekable,
+    {
+        Repeat { source: self }
+    }
+
+    /// Plays `time` of silence before this source starts.
+    fn delay(self, time: Duration) -> Option<Delay<Self>> {
+        let frames = duration_frames(&self, time)?;
+        Some(self.delay_frames(frames))
+    }
+
+    /// Plays `frames` frames of silence before this source starts.
+    fn delay_frames(self, frames: u64) -> Delay<Self> {
+        Delay { remaining: frames.saturating_mul(self.channel_count() as u64), frames, source: self }
+    }
+
+    /// Fades this source in from silence over the first `time` of it.
+    fn fade_in(self, time: Duration) -> Option<FadeIn<Self>> {
+        let frames = duration_frames(&self, time)?;
+        Some(self.fade_in_frames(frames))
+    }
+
+    /// Fades this source in from silence over its first `frames` frames.
+    fn fade_in_frames(self, frames: u64) -> FadeIn<Self> {
+        FadeIn { source: self, frames, position: 0 }
+    }
+
+    /// Fades this source out to silence over the last `time` of it.
+    /// If the source doesn't know how much of it is remaining, the fade starts straight away and the source
+    /// ends once it's finished.
+    fn fade_out(self, time: Duration) -> Option<FadeOut<Self>> {
+        let frames = duration_frames(&self, time)?;
+        Some(self.fade_out_frames(frames))
+    }
+
+    /// Fades this source out to silence over its last `frames` frames, like fade_out does.
+    fn fade_out_frames(self, frames: u64) -> FadeOut<Self> {
+        let (delay, frames) = match self.remaining_frames() {
+            Length::Exact(remaining) => (remaining.saturating_sub(frames), frames.min(remaining)),
+            Length::Unknown => (0, frames),
+        };
+        FadeOut { source: self, delay, frames, position: 0 }
+    }
+}
+
+impl<S: Source> SourceExt for S {}
+
+fn duration_frames(source: &impl Source, time: Duration) -> Option<u64> {
+    source.sample_rate().map(|sample_rate| frames_in(time, sample_rate))
+}
+
+fn add_lengths(a: Length, b: Length) -> Length {
+    match (a, b) {
+        (Length::Exact(a), Length::Exact(b)) => Length::Exact(a.saturating_add(b)),
+        _ => Length::Unknown,
+    }
+}
+
+/// A Source which multiplies every sample by a gain. See SourceExt::amplify.
+pub struct Amplify<S: Source> {
+    source: S,
+    gain: f32,
+}
+
+impl<S: Source> Source for Amplify<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let count = self.source.write_samples(buffer);
+        buffer[..count].iter_mut().for_each(|s| *s *= self.gain);
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        self.source.total_frames()
+    }
+
+    fn remaining_frames(&self) -> Length {
+        self.source.remaining_frames()
+    }
+}
+
+/// A Source which ends after a set amount of time. See SourceExt::take_duration and SourceExt::take_frames.
+pub struct TakeDuration<S: Source> {
+    source: S,
+    frames: u64,
+
+    // How many samples we're still allowed to write
+    remaining: u64,
+}
+
+impl<S: Source> Source for TakeDuration<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let len = (buffer.len() as u64).min(self.remaining) as usize;
+        let count = self.source.write_samples(&mut buffer[..len]);
+        self.remaining -= count as u64;
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.source.total_frames() {
+            Length::Exact(frames) => Length::Exact(frames.min(self.frames)),
+            Length::Unknown => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.source.remaining_frames() {
+            Length::Exact(frames) => Length::Exact(frames.min(self.remaining / self.channel_count() as u64)),
+            Length::Unknown => Length::Unknown,
+        }
+    }
+}
+
+/// A Source which discards its first stretch of audio. See SourceExt::skip_duration and SourceExt::skip_frames.
+pub struct SkipDuration<S: Source> {
+    source: S,
+    frames: u64,
+
+    // How many samples still need to be discarded before we can start writing
+    remaining: u64,
+}
+
+impl<S: Source> Source for SkipDuration<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        // Use the output buffer as scratch space for the samples we're throwing away
+        while self.remaining > 0 && !buffer.is_empty() {
+            let len = (buffer.len() as u64).min(self.remaining) as usize;
+            let count = self.source.write_samples(&mut buffer[..len]);
+            if count < len {
+                self.remaining = 0;
+                return 0
+            }
+            self.remaining -= count as u64;
+        }
+        self.source.write_samples(buffer)
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.source.total_frames() {
+            Length::Exact(frames) => Length::Exact(frames.saturating_sub(self.frames)),
+            Length::Unknown => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.source.remaining_frames() {
+            Length::Exact(frames) => Length::Exact(frames.saturating_sub(self.remaining / self.channel_count() as u64)),
+            Length::Unknown => Length::Unknown,
+        }
+    }
+}
+
+/// A Source which plays one source after another. See SourceExt::chain.
+pub struct Chain<A: Source, B: Source> {
+    first: A,
+    second: B,
+    first_done: bool,
+}
+
+impl<A: Source, B: Source> Source for Chain<A, B> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let mut count = 0;
+        if !self.first_done {
+            count = self.first.write_samples(buffer);
+            self.first_done = count < buffer.len();
+        }
+        if count < buffer.len() {
+            count += self.second.write_samples(&mut buffer[count..]);
+        }
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.first.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.first.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        add_lengths(self.first.total_frames(), self.second.total_frames())
+    }
+
+    fn remaining_frames(&self) -> Length {
+        if self.first_done {
+            self.second.remaining_frames()
+        } else {
+            add_lengths(self.first.remaining_frames(), self.second.remaining_frames())
+        }
+    }
+}
+
+/// A Source which loops forever. See SourceExt::repeat.
+pub struct Repeat<S: Seekable> {
+    source: S,
+}
+
+impl<S: Seekable> Source for Repeat<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let mut count = self.source.write_samples(buffer);
+        while count < buffer.len() {
+            self.source.seek_frame(0);
+            let written = self.source.write_samples(&mut buffer[count..]);
+            if written == 0 {
+                // An empty source would otherwise keep us here forever
+                break
+            }
+            count += written;
+        }
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// A Source which plays silence before it starts. See SourceExt::delay and SourceExt::delay_frames.
+pub struct Delay<S: Source> {
+    source: S,
+    frames: u64,
+
+    // How many samples of silence are left to write
+    remaining: u64,
+}
+
+impl<S: Source> Source for Delay<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let silence = (buffer.len() as u64).min(self.remaining) as usize;
+        buffer[..silence].iter_mut().for_each(|s| *s = 0.0);
+        self.remaining -= silence as u64;
+        silence + self.source.write_samples(&mut buffer[silence..])
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        add_lengths(Length::Exact(self.frames), self.source.total_frames())
+    }
+
+    fn remaining_frames(&self) -> Length {
+        add_lengths(Length::Exact(self.remaining / self.channel_count() as u64), self.source.remaining_frames())
+    }
+}
+
+/// A Source which fades in from silence. See SourceExt::fade_in and SourceExt::fade_in_frames.
+pub struct FadeIn<S: Source> {
+    source: S,
+    frames: u64,
+    position: u64,
+}
+
+impl<S: Source> Source for FadeIn<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let count = self.source.write_samples(buffer);
+        for frame in buffer[..count].chunks_mut(self.source.channel_count()) {
+            if self.position >= self.frames {
+                break
+            }
+            let gain = self.position as f32 / self.frames as f32;
+            frame.iter_mut().for_each(|s| *s *= gain);
+            self.position += 1;
+        }
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        self.source.total_frames()
+    }
+
+    fn remaining_frames(&self) -> Length {
+        self.source.remaining_frames()
+    }
+}
+
+/// A Source which fades out to silence. See SourceExt::fade_out and SourceExt::fade_out_frames.
+pub struct FadeOut<S: Source> {
+    source: S,
+
+    // How many frames to play at full volume before the fade starts
+    delay: u64,
+    frames: u64,
+    position: u64,
+}
+
+impl<S: Source> Source for FadeOut<S> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.source.channel_count();
+
+        // Don't write anything past the end of the fade
+        let left = (self.delay + self.frames - self.position).saturating_mul(channels as u64);
+        let len = (buffer.len() as u64).min(left) as usize;
+        let count = self.source.write_samples(&mut buffer[..len]);
+
+        for frame in buffer[..count].chunks_mut(channels) {
+            if self.position >= self.delay {
+                let gain = 1.0 - (self.position - self.delay) as f32 / self.frames as f32;
+                frame.iter_mut().for_each(|s| *s *= gain);
+            }
+            self.position += 1;
+        }
+        count
+    }
+
+    fn channel_count(&self) -> usize {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+
+    fn total_frames(&self) -> Length {
+        self.source.total_frames()
+    }
+
+    fn remaining_frames(&self) -> Length {
+        let left = self.delay + self.frames - self.position;
+        match self.source.remaining_frames() {
+            Length::Exact(frames) => Length::Exact(frames.min(left)),
+            Length::Unknown => Length::Unknown,
+        }
+    }
+}
@@ -0,0 +1,308 @@
+mod frame;
+
+use self::frame::FrameDecoder;
+use super::{xiph, Length, Seekable, Source};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+
+/// A Source which decodes a FLAC file as it's played.
+/// By default it decodes from a file in memory, but it can also stream from any reader (see `from_reader`).
+pub struct FlacPlayer<R: Read + Seek = Cursor<Vec<u8>>> {
+    reader: BufReader<R>,
+    frames: FrameDecoder,
+    channels: usize,
+    sample_rate: u32,
+    comments: Vec<(String, String)>,
+
+    // The total number of frames in the stream, if the stream info says
+    length: Option<u64>,
+
+    // Points in the stream to seek to, sorted by frame
+    seek_points: Vec<SeekPoint>,
+
+    // The byte offset of the first FLAC frame in the file, which seek points are relative to
+    first_frame: u64,
+
+    // The samples of the last frame decoded, which starts at frame `block_start` of the stream.
+    // Samples before `decoded[decoded_offset]` have already been written.
+    decoded: Vec<f32>,
+    decoded_offset: usize,
+    block_start: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// This does not appear to be a FLAC file
+    InvalidFile,
+
+    /// The audio data in this file is malformed
+    MalformedData,
+
+    /// The underlying reader returned an error while the file was being read
+    Io(io::ErrorKind),
+}
+
+// A point in the stream which can be seeked to directly
+#[derive(Clone, Copy, Debug)]
+struct SeekPoint {
+    frame: u64,
+
+    // The byte offset of the FLAC frame starting there, relative to the first one
+    offset: u64,
+}
+
+impl FlacPlayer {
+    pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        Self::from_reader(Cursor::new(file.into()))
+    }
+}
+
+impl<R: Read + Seek> FlacPlayer<R> {
+    /// Creates a FlacPlayer which streams its file from a reader, only reading as much as it needs at a time.
+    pub fn from_reader(reader: R) -> Result<Self, Error> {
+        let mut reader = BufReader::new(reader);
+        skip_id3v2(&mut reader)?;
+        let mut magic = [0u8; 4];
+        match read_exact(&mut reader, &mut magic) {
+            Ok(()) if &magic == b"fLaC" => (),
+            Ok(()) | Err(Error::MalformedData) => return Err(Error::InvalidFile),
+            Err(e) => return Err(e),
+        }
+
+        // The stream info always comes first, and every other metadata block we're interested in is optional
+        let mut stream_info = None;
+        let mut seek_points = Vec::new();
+        let mut comments = Vec::new();
+        loop {
+            let mut block_header = [0u8; 4];
+            read_exact(&mut reader, &mut block_header)?;
+            let is_last = block_header[0] & 0x80 != 0;
+            let block_len = u64::from(u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]));
+
+            match block_header[0] & 0x7F {
+                0 => stream_info = Some(parse_stream_info(&read_body(&mut reader, block_len)?)?),
+                3 => seek_points = parse_seek_table(&read_body(&mut reader, block_len)?),
+                4 => comments = xiph::parse_comments(&read_body(&mut reader, block_len)?),
+                _ => {
+                    reader.seek(SeekFrom::Current(block_len as i64)).map_err(|e| Error::Io(e.kind()))?;
+                },
+            }
+            if is_last {
+                break
+            }
+        }
+
+        let stream_info = stream_info.ok_or(Error::InvalidFile)?;
+        let first_frame = reader.stream_position().map_err(|e| Error::Io(e.kind()))?;
+        Ok(Self {
+            reader,
+            frames: FrameDecoder::new(stream_info.channels, stream_info.bits_per_sample),
+            channels: stream_info.channels,
+            sample_rate: stream_info.sample_rate,
+            comments,
+            length: stream_info.length,
+            seek_points,
+            first_frame,
+            decoded: Vec::new(),
+            decoded_offset: 0,
+            block_start: 0,
+        })
+    }
+
+    /// Returns the total number of frames in this file, if it says.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Returns the comments stored in this file as (field, value) pairs, such as `("TITLE", "...")`.
+    /// Field names aren't case-sensitive, and the same field may appear more than once.
+    pub fn comments(&self) -> &[(String, String)] {
+        &self.comments
+    }
+
+    /// Returns the value of the first comment with the given field name, ignoring case.
+    pub fn comment(&self, field: &str) -> Option<&str> {
+        self.comments.iter().find(|(name, _)| name.eq_ignore_ascii_case(field)).map(|(_, value)| value.as_str())
+    }
+
+    /// Consumes this FlacPlayer and returns the reader it was streaming from.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    // The frame of the stream which the next FLAC frame to be decoded starts at
+    fn next_block(&self) -> u64 {
+        self.block_start + (self.decoded.len() / self.channels) as u64
+    }
+
+    // Decodes the next FLAC frame into `decoded`, returning false once there's nothing left to decode
+    fn decode_next(&mut self) -> bool {
+        let next_block = self.next_block();
+        match self.frames.decode(&mut self.reader, &mut self.decoded) {
+            Ok(true) => {
+                self.block_start = next_block;
+                self.decoded_offset = 0;
+                true
+            },
+            Ok(false) | Err(_) => false,
+        }
+    }
+}
+
+impl<R: Read + Seek> Source for FlacPlayer<R> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.decoded_offset == self.decoded.len() {
+                if !self.decode_next() {
+                    break
+                }
+                continue
+            }
+
+            let samples = &self.decoded[self.decoded_offset..];
+            let count = samples.len().min(buffer.len() - written);
+            buffer[written..(written + count)].copy_from_slice(&samples[..count]);
+            self.decoded_offset += count;
+            written += count;
+        }
+        written
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.length {
+            Some(length) => Length::Exact(length),
+            None => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.length {
+            Some(length) => {
+                let position = self.block_start + (self.decoded_offset / self.channels) as u64;
+                Length::Exact(length.saturating_sub(position))
+            },
+            None => Length::Unknown,
+        }
+    }
+}
+
+impl<R: Read + Seek> Seekable for FlacPlayer<R> {
+    fn seek_frame(&mut self, frame: u64) {
+        let frame = self.length.map_or(frame, |length| frame.min(length));
+
+        // Jump to the closest seek point before the frame (or the start of the stream), unless decoding on from
+        // where we are gets there sooner
+        let point = self
+            .seek_points
+            .iter()
+            .rev()
+            .find(|point| point.frame <= frame)
+            .copied()
+            .unwrap_or(SeekPoint { frame: 0, offset: 0 });
+        if frame < self.block_start || point.frame > self.next_block() {
+            self.decoded.clear();
+            self.decoded_offset = 0;
+            self.block_start = point.frame;
+            let _ = self.reader.seek(SeekFrom::Start(self.first_frame + point.offset));
+        }
+
+        // FLAC frames can only be decoded whole, so decode up to the one the frame is in
+        while frame >= self.next_block() {
+            if !self.decode_next() {
+                self.decoded_offset = self.decoded.len();
+                return
+            }
+        }
+        self.decoded_offset = (frame - self.block_start) as usize * self.channels;
+    }
+}
+
+struct StreamInfo {
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    length: Option<u64>,
+}
+
+fn parse_stream_info(body: &[u8]) -> Result<StreamInfo, Error> {
+    if body.len() < 34 {
+        return Err(Error::InvalidFile)
+    }
+
+    // After the block and frame sizes, there's a 20-bit sample rate, then 3 bits for the channel count, 5 for the
+    // bit depth and 36 for the length, each of which is stored minus one except the length
+    let mut fields = [0u8; 8];
+    fields.copy_from_slice(&body[10..18]);
+    let fields = u64::from_be_bytes(fields);
+    let sample_rate = (fields >> 44) as u32;
+    let bits_per_sample = ((fields >> 36) & 0x1F) as u32 + 1;
+    if sample_rate == 0 || bits_per_sample < 4 {
+        return Err(Error::InvalidFile)
+    }
+    Ok(StreamInfo {
+        channels: ((fields >> 41) & 0x7) as usize + 1,
+        sample_rate,
+        bits_per_sample,
+        length: Some(fields & 0xF_FFFF_FFFF).filter(|&length| length != 0),
+    })
+}
+
+// Reads the seek points out of a SEEKTABLE block, leaving out placeholders
+fn parse_seek_table(body: &[u8]) -> Vec<SeekPoint> {
+    let mut points: Vec<SeekPoint> = body
+        .chunks_exact(18)
+        .map(|point| {
+            let mut frame = [0u8; 8];
+            frame.copy_from_slice(&point[0..8]);
+            let mut offset = [0u8; 8];
+            offset.copy_from_slice(&point[8..16]);
+            SeekPoint { frame: u64::from_be_bytes(frame), offset: u64::from_be_bytes(offset) }
+        })
+        .filter(|point| point.frame != u64::MAX)
+        .collect();
+    points.sort_by_key(|point| point.frame);
+    points
+}
+
+// Some FLAC files start with an ID3v2 tag, even though it isn't allowed
+fn skip_id3v2<R: Read + Seek>(reader: &mut R) -> Result<(), Error> {
+    let mut header = [0u8; 10];
+    let tag_len = match read_exact(reader, &mut header) {
+        Ok(()) if &header[0..3] == b"ID3" => {
+            // The size is stored 7 bits to a byte, and doesn't include the header or the footer (if there is one)
+            let size = header[6..10].iter().fold(0u64, |size, &byte| (size << 7) | u64::from(byte & 0x7F));
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer
+        },
+        Ok(()) | Err(Error::MalformedData) => 0,
+        Err(e) => return Err(e),
+    };
+    reader.seek(SeekFrom::Start(tag_len)).map_err(|e| Error::Io(e.kind()))?;
+    Ok(())
+}
+
+// Fills the buffer from the reader, returning MalformedData if the reader runs out first
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::MalformedData,
+        kind => Error::Io(kind),
+    })
+}
+
+// Reads the body of a metadata block
+fn read_body<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body).map_err(|e| Error::Io(e.kind()))?;
+    if body.len() as u64 != len {
+        return Err(Error::MalformedData)
+    }
+    Ok(body)
+}
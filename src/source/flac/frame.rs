@@ -0,0 +1,349 @@
+use super::Error;
+use std::io::{self, Read};
+
+// The fixed predictors are LPC predictors with these coefficients and no shift, indexed by order
+const FIXED_COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+// How the channels of a frame are stored. Stereo frames can store the difference between the channels (the side
+// channel) in place of one of them, since it's often quieter and compresses better.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChannelAssignment {
+    Independent,
+    LeftSide,
+    SideRight,
+    MidSide,
+}
+
+// Decodes FLAC frames one at a time, keeping hold of its buffers between them
+#[derive(Clone, Debug)]
+pub(super) struct FrameDecoder {
+    channels: usize,
+    bits_per_sample: u32,
+
+    // The samples of the frame being decoded, one channel after another. These are 64-bit since a side channel
+    // holding the difference between two 32-bit channels needs 33 bits.
+    planar: Vec<i64>,
+}
+
+impl FrameDecoder {
+    pub(super) fn new(channels: usize, bits_per_sample: u32) -> Self {
+        Self { channels, bits_per_sample, planar: Vec::new() }
+    }
+
+    // Decodes the next frame into interleaved samples, replacing the contents of `out`.
+    // Returns false if the reader has run out of frames, and leaves `out` alone if anything goes wrong.
+    pub(super) fn decode<R: Read>(&mut self, reader: &mut R, out: &mut Vec<f32>) -> Result<bool, Error> {
+        let mut bits = BitReader::new(reader);
+        let first_byte = match bits.read_byte() {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(Error::Io(e.kind())),
+        };
+
+        // Frame header: a 14-bit sync code, a reserved bit and the blocking strategy
+        if first_byte != 0xFF || bits.read(8)? & 0xFE != 0xF8 {
+            return Err(Error::MalformedData)
+        }
+        let block_size_code = bits.read(4)?;
+        let sample_rate_code = bits.read(4)?;
+        let (channels, assignment) = match bits.read(4)? {
+            channels @ 0..=7 => (channels as usize + 1, ChannelAssignment::Independent),
+            8 => (2, ChannelAssignment::LeftSide),
+            9 => (2, ChannelAssignment::SideRight),
+            10 => (2, ChannelAssignment::MidSide),
+            _ => return Err(Error::MalformedData),
+        };
+        let bits_per_sample = match bits.read(3)? {
+            0 => self.bits_per_sample,
+            1 => 8,
+            2 => 12,
+            4 => 16,
+            5 => 20,
+            6 => 24,
+            7 => 32,
+            _ => return Err(Error::MalformedData),
+        };
+        if bits.read(1)? != 0 || channels != self.channels || bits_per_sample != self.bits_per_sample {
+            return Err(Error::MalformedData)
+        }
+
+        // The frame or sample number comes next, but the player keeps track of where it is itself
+        bits.skip_utf8_number()?;
+        let block_size = match block_size_code {
+            1 => 192,
+            2..=5 => 576 << (block_size_code - 2),
+            6 => bits.read(8)? as usize + 1,
+            7 => bits.read(16)? as usize + 1,
+            8..=15 => 256 << (block_size_code - 8),
+            _ => return Err(Error::MalformedData),
+        };
+
+        // Likewise, the sample rate can only be the one in the stream info
+        match sample_rate_code {
+            12 => bits.read(8)?,
+            13 | 14 => bits.read(16)?,
+            15 => return Err(Error::MalformedData),
+            _ => 0,
+        };
+        let header_crc = bits.crc8;
+        if bits.read(8)? as u8 != header_crc {
+            return Err(Error::MalformedData)
+        }
+
+        self.planar.clear();
+        self.planar.resize(block_size * channels, 0);
+        for (channel, samples) in self.planar.chunks_exact_mut(block_size).enumerate() {
+            let is_side = match assignment {
+                ChannelAssignment::Independent => false,
+                ChannelAssignment::LeftSide | ChannelAssignment::MidSide => channel == 1,
+                ChannelAssignment::SideRight => channel == 0,
+            };
+            decode_subframe(&mut bits, bits_per_sample + u32::from(is_side), samples)?;
+        }
+
+        // Frame footer: padding up to the next byte, then a CRC-16 of the whole frame
+        bits.align();
+        let frame_crc = bits.crc16;
+        if bits.read(16)? as u16 != frame_crc {
+            return Err(Error::MalformedData)
+        }
+
+        if channels == 2 {
+            let (left, right) = self.planar.split_at_mut(block_size);
+            for (left, right) in left.iter_mut().zip(right.iter_mut()) {
+                match assignment {
+                    ChannelAssignment::Independent => (),
+                    ChannelAssignment::LeftSide => *right = left.wrapping_sub(*right),
+                    ChannelAssignment::SideRight => *left = left.wrapping_add(*right),
+                    ChannelAssignment::MidSide => {
+                        // The lowest bit of the mid channel was lost when it was halved, but it's the same as the
+                        // lowest bit of the side channel
+                        let mid = (*left << 1) | (*right & 1);
+                        let side = *right;
+                        *left = mid.wrapping_add(side) >> 1;
+                        *right = mid.wrapping_sub(side) >> 1;
+                    },
+                }
+            }
+        }
+
+        let scale = (1u64 << (bits_per_sample - 1)) as f32;
+        out.clear();
+        out.reserve(block_size * channels);
+        for frame in 0..block_size {
+            out.extend((0..channels).map(|channel| self.planar[channel * block_size + frame] as f32 / scale));
+        }
+        Ok(true)
+    }
+}
+
+// Decodes one channel of a frame
+fn decode_subframe<R: Read>(bits: &mut BitReader<R>, bits_per_sample: u32, samples: &mut [i64]) -> Result<(), Error> {
+    // Subframe header: a zero bit, the subframe type, and whether the samples have wasted bits
+    if bits.read(1)? != 0 {
+        return Err(Error::MalformedData)
+    }
+    let subframe_type = bits.read(6)?;
+
+    // Wasted bits are low bits which are 0 in every sample, so they aren't stored
+    let wasted_bits = if bits.read(1)? == 1 { bits.read_unary()? + 1 } else { 0 };
+    if wasted_bits >= bits_per_sample {
+        return Err(Error::MalformedData)
+    }
+    let bits_per_sample = bits_per_sample - wasted_bits;
+
+    match subframe_type {
+        // Constant
+        0 => {
+            let sample = bits.read_signed(bits_per_sample)?;
+            samples.iter_mut().for_each(|s| *s = sample);
+        },
+
+        // Verbatim
+        1 => {
+            for sample in samples.iter_mut() {
+                *sample = bits.read_signed(bits_per_sample)?;
+            }
+        },
+
+        // Fixed and linear prediction, which start with the samples they can't be predicted from
+        8..=12 | 32..=63 => {
+            let order = (if subframe_type < 32 { subframe_type - 8 } else { subframe_type - 31 }) as usize;
+            if order > samples.len() {
+                return Err(Error::MalformedData)
+            }
+            for sample in samples[..order].iter_mut() {
+                *sample = bits.read_signed(bits_per_sample)?;
+            }
+
+            let mut coefficients = [0i64; 32];
+            let shift = if subframe_type < 32 {
+                coefficients[..order].copy_from_slice(FIXED_COEFFICIENTS[order]);
+                0
+            } else {
+                let precision = bits.read(4)? as u32 + 1;
+                let shift = bits.read_signed(5)?;
+                if precision == 16 || shift < 0 {
+                    return Err(Error::MalformedData)
+                }
+                for coefficient in coefficients[..order].iter_mut() {
+                    *coefficient = bits.read_signed(precision)?;
+                }
+                shift as u32
+            };
+
+            decode_residual(bits, order, samples)?;
+            for i in order..samples.len() {
+                let prediction = coefficients[..order]
+                    .iter()
+                    .zip(samples[(i - order)..i].iter().rev())
+                    .fold(0i64, |sum, (c, s)| sum.wrapping_add(c.wrapping_mul(*s)));
+                samples[i] = samples[i].wrapping_add(prediction >> shift);
+            }
+        },
+
+        _ => return Err(Error::MalformedData),
+    }
+
+    if wasted_bits > 0 {
+        samples.iter_mut().for_each(|s| *s <<= wasted_bits);
+    }
+    Ok(())
+}
+
+// Reads the Rice-coded differences from the prediction into the samples after the first `order` of them
+fn decode_residual<R: Read>(bits: &mut BitReader<R>, order: usize, samples: &mut [i64]) -> Result<(), Error> {
+    let (parameter_bits, escape) = match bits.read(2)? {
+        0 => (4, 0xF),
+        1 => (5, 0x1F),
+        _ => return Err(Error::MalformedData),
+    };
+
+    // The residual is split into equal partitions, each with its own Rice parameter, but the first partition
+    // doesn't include the samples before it which weren't predicted
+    let partition_order = bits.read(4)? as usize;
+    let partition_len = samples.len() >> partition_order;
+    if partition_len << partition_order != samples.len() || partition_len < order {
+        return Err(Error::MalformedData)
+    }
+
+    let mut start = order;
+    for partition in 0..(1 << partition_order) {
+        let end = (partition + 1) * partition_len;
+        let parameter = bits.read(parameter_bits)? as u32;
+        if parameter == escape {
+            // Escaped partitions store their residual as plain numbers of a given size
+            let residual_bits = bits.read(5)? as u32;
+            for sample in samples[start..end].iter_mut() {
+                *sample = bits.read_signed(residual_bits)?;
+            }
+        } else {
+            for sample in samples[start..end].iter_mut() {
+                let value = (u64::from(bits.read_unary()?) << parameter) | bits.read(parameter)?;
+
+                // Values are zigzag-encoded, so 0, 1, 2, 3... stands for 0, -1, 1, -2...
+                *sample = (value >> 1) as i64 ^ -((value & 1) as i64);
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+// Reads numbers of any size up to 56 bits from a byte reader, most significant bit first, keeping track of the
+// CRCs of everything read so far
+struct BitReader<'r, R> {
+    reader: &'r mut R,
+
+    // Bits which have been read but not used yet, in the lowest `bit_count` bits
+    bits: u64,
+    bit_count: u32,
+
+    crc8: u8,
+    crc16: u16,
+}
+
+impl<'r, R: Read> BitReader<'r, R> {
+    fn new(reader: &'r mut R) -> Self {
+        Self { reader, bits: 0, bit_count: 0, crc8: 0, crc16: 0 }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.reader.read_exact(&mut byte)?;
+        let byte = byte[0];
+
+        // CRC-8 with polynomial x^8 + x^2 + x + 1, and CRC-16 with polynomial x^16 + x^15 + x^2 + 1
+        self.crc8 ^= byte;
+        self.crc16 ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            self.crc8 = if self.crc8 & 0x80 != 0 { (self.crc8 << 1) ^ 0x07 } else { self.crc8 << 1 };
+            self.crc16 = if self.crc16 & 0x8000 != 0 { (self.crc16 << 1) ^ 0x8005 } else { self.crc16 << 1 };
+        }
+        Ok(byte)
+    }
+
+    fn read(&mut self, count: u32) -> Result<u64, Error> {
+        while self.bit_count < count {
+            self.bits = (self.bits << 8) | u64::from(self.read_byte().map_err(io_error)?);
+            self.bit_count += 8;
+        }
+        self.bit_count -= count;
+        Ok((self.bits >> self.bit_count) & ((1 << count) - 1))
+    }
+
+    // Reads a two's complement number
+    fn read_signed(&mut self, count: u32) -> Result<i64, Error> {
+        if count == 0 {
+            return Ok(0)
+        }
+        let value = self.read(count)?;
+        Ok(((value << (64 - count)) as i64) >> (64 - count))
+    }
+
+    // Reads a number stored as that many 0 bits followed by a 1 bit
+    fn read_unary(&mut self) -> Result<u32, Error> {
+        let mut zeros = 0;
+        loop {
+            let unused = self.bits & ((1 << self.bit_count) - 1);
+            if unused != 0 {
+                let one = 63 - unused.leading_zeros();
+                zeros += self.bit_count - 1 - one;
+                self.bit_count = one;
+                return Ok(zeros)
+            }
+            zeros += self.bit_count;
+            self.bits = u64::from(self.read_byte().map_err(io_error)?);
+            self.bit_count = 8;
+        }
+    }
+
+    // Skips past a number stored in the same way as a UTF-8 character, but up to 7 bytes long
+    fn skip_utf8_number(&mut self) -> Result<(), Error> {
+        let first_byte = self.read(8)?;
+        let extra_bytes = match (first_byte as u8).leading_ones() {
+            0 => 0,
+            ones @ 2..=7 => ones - 1,
+            _ => return Err(Error::MalformedData),
+        };
+        for _ in 0..extra_bytes {
+            if self.read(8)? & 0xC0 != 0x80 {
+                return Err(Error::MalformedData)
+            }
+        }
+        Ok(())
+    }
+
+    // Throws away whatever's left of the current byte
+    fn align(&mut self) {
+        self.bit_count -= self.bit_count % 8;
+    }
+}
+
+// Running out of data partway through a frame means the file is cut short
+fn io_error(error: io::Error) -> Error {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => Error::MalformedData,
+        kind => Error::Io(kind),
+    }
+}
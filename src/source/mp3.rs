@@ -0,0 +1,531 @@
+use super::{Length, Seekable, Source};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use symphonia_bundle_mp3::MpaDecoder;
+use symphonia_core::{
+    audio::{AudioBufferRef, Signal},
+    codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_MP3},
+    formats::Packet,
+};
+
+// Every decoder puts out this many frames of latency before the first real one
+const DECODER_DELAY: u64 = 528 + 1;
+
+// The most bytes a packet's audio data can start before the packet itself (in MPEG-1; MPEG-2 only allows half this)
+const MAX_RESERVOIR_BYTES: u64 = 511;
+
+/// A Source which decodes an MP3 file as it's played.
+/// By default it decodes from a file in memory, but it can also stream from any reader (see `from_reader`).
+///
+/// If the file has a Xing or VBRI header (as most VBR files do), it's used to find the file's length, and if the
+/// file has a LAME tag, the silence added by the encoder at either end is cut off so that files play back to back
+/// without gaps.
+pub struct Mp3Player<R: Read + Seek = Cursor<Vec<u8>>> {
+    packets: Packets<R>,
+    decoder: MpaDecoder,
+
+    // How many frames of silence to cut from the start of the decoded audio, and how many frames are left after that
+    // (if it's known)
+    delay: u64,
+    length: Option<u64>,
+
+    // The next packet to decode
+    next_packet: usize,
+
+    // The samples decoded from the last packet, which start at frame `block_start` of the stream.
+    // Samples before `decoded[decoded_offset]` have already been written.
+    decoded: Vec<f32>,
+    decoded_offset: usize,
+    block_start: u64,
+}
+
+// Finds and reads the MPEG frames in a file, which we call packets since "frame" means something else here
+struct Packets<R: Read + Seek> {
+    reader: BufReader<R>,
+
+    // Where `reader` is in the file, so it can be moved around without throwing away what it's buffered
+    reader_position: u64,
+
+    // The header of the first packet, which every other packet should match
+    header: FrameHeader,
+
+    // The byte offsets of every packet of audio found so far, which doesn't include any Xing or VBRI header
+    offsets: Vec<u64>,
+
+    // Where to look for the packet after the last one found, or None once the end of the file's been found
+    scan_position: Option<u64>,
+
+    // The contents of the last packet read
+    packet: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// This does not appear to be an MP3 file
+    InvalidFile,
+
+    /// The underlying reader returned an error while the file was being read
+    Io(io::ErrorKind),
+}
+
+// The parts of an MPEG audio frame header we care about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FrameHeader {
+    mpeg1: bool,
+    sample_rate: u32,
+    channels: usize,
+    packet_bytes: u64,
+}
+
+impl FrameHeader {
+    // Parses the header of a layer III frame, returning None if it isn't one (or it's one we can't decode)
+    fn parse(header: [u8; 4]) -> Option<Self> {
+        const MPEG1_BITRATES: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+        const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+        const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+        // An 11-bit sync code, then the version (MPEG-2.5, reserved, MPEG-2, MPEG-1)
+        // and the layer (reserved, III, II, I)
+        if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 || header[1] & 0x06 != 0x02 {
+            return None
+        }
+        let (mpeg1, rate_shift) = match (header[1] >> 3) & 0x3 {
+            0 => (false, 2),
+            2 => (false, 1),
+            3 => (true, 0),
+            _ => return None,
+        };
+
+        // Free-format bitrates (0) aren't supported, since the frame size has to be worked out from the next frame
+        let bitrate_index = usize::from(header[2] >> 4);
+        let sample_rate_index = usize::from((header[2] >> 2) & 0x3);
+        if bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+            return None
+        }
+        let bitrate = if mpeg1 { MPEG1_BITRATES } else { MPEG2_BITRATES }[bitrate_index] * 1000;
+        let sample_rate = SAMPLE_RATES[sample_rate_index] >> rate_shift;
+        let padding = u64::from((header[2] >> 1) & 0x1);
+        let slots_per_second = if mpeg1 { 144 } else { 72 };
+        Some(Self {
+            mpeg1,
+            sample_rate,
+            channels: if header[3] >> 6 == 3 { 1 } else { 2 },
+            packet_bytes: u64::from(slots_per_second * bitrate / sample_rate) + padding,
+        })
+    }
+
+    fn frames_per_packet(&self) -> u64 {
+        if self.mpeg1 { 1152 } else { 576 }
+    }
+
+    // The size of the side information following the header, which is where a Xing header would go
+    fn side_info_bytes(&self) -> usize {
+        match (self.mpeg1, self.channels) {
+            (true, 1) => 17,
+            (true, _) => 32,
+            (false, 1) => 9,
+            (false, _) => 17,
+        }
+    }
+
+    // Whether a stream can switch from this header to another one partway through
+    fn is_compatible(&self, other: &Self) -> bool {
+        self.mpeg1 == other.mpeg1 && self.sample_rate == other.sample_rate && self.channels == other.channels
+    }
+}
+
+impl Mp3Player {
+    pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        Self::from_reader(Cursor::new(file.into()))
+    }
+}
+
+impl<R: Read + Seek> Mp3Player<R> {
+    /// Creates an Mp3Player which streams its file from a reader, only reading as much as it needs at a time.
+    pub fn from_reader(reader: R) -> Result<Self, Error> {
+        let mut reader = BufReader::new(reader);
+        let start = skip_id3v2(&mut reader)?;
+        let mut packets = Packets::new(reader, start)?;
+        let decoder = MpaDecoder::try_new(CodecParameters::new().for_codec(CODEC_TYPE_MP3), &DecoderOptions::default())
+            .map_err(|_| Error::InvalidFile)?;
+
+        // The first packet may be a Xing or VBRI header rather than audio
+        let mut delay = 0;
+        let mut length = None;
+        if let Some(info) = read_info_tag(&packets.packet, &packets.header) {
+            packets.skip_first();
+            let frames = info.packets.map(|count| u64::from(count) * packets.header.frames_per_packet());
+            match info.gapless {
+                Some((encoder_delay, padding)) => {
+                    delay = DECODER_DELAY + encoder_delay;
+                    length = frames.map(|frames| frames.saturating_sub(encoder_delay + padding));
+                },
+                None => length = frames,
+            }
+        }
+
+        Ok(Self {
+            packets,
+            decoder,
+            delay,
+            length,
+            next_packet: 0,
+            decoded: Vec::new(),
+            decoded_offset: 0,
+            block_start: 0,
+        })
+    }
+
+    /// Returns the total number of frames in this file, if it has a header saying.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Consumes this Mp3Player and returns the reader it was streaming from.
+    pub fn into_inner(self) -> R {
+        self.packets.reader.into_inner()
+    }
+
+    // Decodes the given packet into `decoded`, returning false if there's no such packet. Packets which can't be
+    // decoded are treated as silence.
+    fn decode_packet(&mut self, index: usize) -> bool {
+        if !self.packets.read(index) {
+            return false
+        }
+        let channels = self.packets.header.channels;
+        let frames = self.packets.header.frames_per_packet() as usize;
+        self.decoded.clear();
+        match self.decoder.decode(&Packet::new_from_slice(0, 0, 0, &self.packets.packet)) {
+            Ok(AudioBufferRef::F32(buffer)) if buffer.frames() == frames => {
+                self.decoded.reserve(frames * channels);
+                for frame in 0..frames {
+                    self.decoded.extend((0..channels).map(|channel| buffer.chan(channel)[frame]));
+                }
+            },
+            _ => self.decoded.resize(frames * channels, 0.0),
+        }
+        true
+    }
+
+    // Decodes the next packet which has any audio in it (once the encoder's silence is cut off), returning false once
+    // there's nothing left to decode
+    fn decode_next(&mut self) -> bool {
+        let frames_per_packet = self.packets.header.frames_per_packet();
+        let channels = self.packets.header.channels;
+        loop {
+            let index = self.next_packet;
+            if !self.decode_packet(index) {
+                return false
+            }
+            self.next_packet += 1;
+
+            // Work out which part of the packet is left once the encoder's silence is cut off
+            let packet_start = index as u64 * frames_per_packet;
+            let start = packet_start.max(self.delay);
+            let end = packet_start + frames_per_packet;
+            let end = self.length.map_or(end, |length| end.min(self.delay + length));
+            if start >= end {
+                if self.length.map_or(false, |length| start >= self.delay + length) {
+                    self.decoded.clear();
+                    return false
+                }
+                continue
+            }
+
+            self.decoded.truncate((end - packet_start) as usize * channels);
+            self.decoded.drain(..((start - packet_start) as usize * channels));
+            self.decoded_offset = 0;
+            self.block_start = start - self.delay;
+            return true
+        }
+    }
+
+    // Returns the packet the decoder needs to start from for the given packet to be decoded properly. Packets can
+    // keep some of their data in the packets before them, and the decoder blends each packet into the one before it.
+    fn preroll_start(&mut self, index: usize) -> usize {
+        let overhead = 4 + self.packets.header.side_info_bytes() as u64;
+        let mut start = index.saturating_sub(1);
+        let mut reservoir = 0;
+        while start > 0 && reservoir < MAX_RESERVOIR_BYTES {
+            start -= 1;
+            match (self.packets.find(start), self.packets.find(start + 1)) {
+                (Some(offset), Some(next_offset)) => reservoir += (next_offset - offset).saturating_sub(overhead),
+                _ => break,
+            }
+        }
+        start
+    }
+}
+
+impl<R: Read + Seek> Source for Mp3Player<R> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.decoded_offset == self.decoded.len() {
+                if !self.decode_next() {
+                    break
+                }
+                continue
+            }
+
+            let samples = &self.decoded[self.decoded_offset..];
+            let count = samples.len().min(buffer.len() - written);
+            buffer[written..(written + count)].copy_from_slice(&samples[..count]);
+            self.decoded_offset += count;
+            written += count;
+        }
+        written
+    }
+
+    fn channel_count(&self) -> usize {
+        self.packets.header.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.packets.header.sample_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.length {
+            Some(length) => Length::Exact(length),
+            None => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.length {
+            Some(length) => {
+                let position = self.block_start + (self.decoded_offset / self.packets.header.channels) as u64;
+                Length::Exact(length.saturating_sub(position))
+            },
+            None => Length::Unknown,
+        }
+    }
+}
+
+impl<R: Read + Seek> Seekable for Mp3Player<R> {
+    fn seek_frame(&mut self, frame: u64) {
+        let frame = self.length.map_or(frame, |length| frame.min(length));
+        let channels = self.packets.header.channels;
+        let block_end = self.block_start + (self.decoded.len() / channels) as u64;
+        if (self.block_start..block_end).contains(&frame) {
+            self.decoded_offset = (frame - self.block_start) as usize * channels;
+            return
+        }
+
+        // Unless the packet is just ahead of where we are, start decoding a few packets before it
+        let index = ((frame + self.delay) / self.packets.header.frames_per_packet()) as usize;
+        let preroll_start = self.preroll_start(index);
+        if frame < block_end || preroll_start > self.next_packet {
+            self.decoder.reset();
+            for packet in preroll_start..index {
+                self.decode_packet(packet);
+            }
+            self.next_packet = index;
+        }
+        self.decoded.clear();
+        self.decoded_offset = 0;
+
+        loop {
+            if !self.decode_next() {
+                self.block_start = self.length.unwrap_or(frame);
+                return
+            }
+            let block_end = self.block_start + (self.decoded.len() / channels) as u64;
+            if frame < block_end {
+                self.decoded_offset = frame.saturating_sub(self.block_start) as usize * channels;
+                return
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Packets<R> {
+    // Finds the first packet after `start`, and reads it
+    fn new(reader: BufReader<R>, start: u64) -> Result<Self, Error> {
+        let mut packets = Self {
+            reader,
+            reader_position: start,
+            // This is filled in once the first packet is found
+            header: FrameHeader { mpeg1: true, sample_rate: 0, channels: 0, packet_bytes: 0 },
+            offsets: Vec::new(),
+            scan_position: None,
+            packet: Vec::new(),
+        };
+        let (offset, header) = packets.sync(start, None).ok_or(Error::InvalidFile)?;
+        packets.header = header;
+        packets.offsets.push(offset);
+        packets.scan_position = Some(offset + header.packet_bytes);
+        if !packets.read(0) {
+            return Err(Error::InvalidFile)
+        }
+        Ok(packets)
+    }
+
+    // Forgets about the first packet, for when it isn't audio
+    fn skip_first(&mut self) {
+        self.offsets.remove(0);
+    }
+
+    // Reads the given packet into `packet`, returning false if there's no such packet
+    fn read(&mut self, index: usize) -> bool {
+        let offset = match self.find(index) {
+            Some(offset) => offset,
+            None => return false,
+        };
+        let mut packet = std::mem::take(&mut self.packet);
+        let read = match self.read_header(offset) {
+            Some(header) => {
+                packet.resize(header.packet_bytes as usize, 0);
+                self.read_at(offset, &mut packet)
+            },
+            None => false,
+        };
+        self.packet = packet;
+        read
+    }
+
+    // Returns the byte offset of the given packet, scanning forward through the file for it if need be
+    fn find(&mut self, index: usize) -> Option<u64> {
+        while self.offsets.len() <= index {
+            let scan_position = self.scan_position?;
+
+            // Usually the next packet comes straight after the last one, but if it doesn't, go looking for it
+            let header = self.header;
+            let found = match self.read_header(scan_position) {
+                Some(next) if next.is_compatible(&header) => Some((scan_position, next)),
+                _ => self.sync(scan_position, Some(header)),
+            };
+            self.scan_position = found.map(|(offset, next)| offset + next.packet_bytes);
+            if let Some((offset, _)) = found {
+                self.offsets.push(offset);
+            }
+        }
+        Some(self.offsets[index])
+    }
+
+    // Searches for the next packet at or after `offset` which is compatible with `expected`, if given. Sync codes
+    // can turn up by chance in other data, so the packet has to be followed by another one like it (or the end of
+    // the file).
+    fn sync(&mut self, mut offset: u64, expected: Option<FrameHeader>) -> Option<(u64, FrameHeader)> {
+        let mut window = [0u8; 4];
+        if !self.read_at(offset, &mut window) {
+            return None
+        }
+        loop {
+            let header = FrameHeader::parse(window)
+                .filter(|header| expected.map_or(true, |expected| header.is_compatible(&expected)));
+            if let Some(header) = header {
+                let next_offset = offset + header.packet_bytes;
+                let followed = match self.read_header(next_offset) {
+                    Some(next) => next.is_compatible(&header),
+                    None => !self.read_at(next_offset, &mut [0u8; 1]),
+                };
+                if followed {
+                    return Some((offset, header))
+                }
+            }
+
+            let mut byte = [0u8; 1];
+            if !self.read_at(offset + 4, &mut byte) {
+                return None
+            }
+            window = [window[1], window[2], window[3], byte[0]];
+            offset += 1;
+        }
+    }
+
+    fn read_header(&mut self, offset: u64) -> Option<FrameHeader> {
+        let mut header = [0u8; 4];
+        if self.read_at(offset, &mut header) { FrameHeader::parse(header) } else { None }
+    }
+
+    // Reads bytes from `offset` until the buffer is full, returning false if the file ends first
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> bool {
+        if self.reader.seek_relative(offset as i64 - self.reader_position as i64).is_err() {
+            self.reader_position = self.reader.stream_position().unwrap_or(u64::MAX);
+            return false
+        }
+        self.reader_position = offset;
+        match self.reader.read_exact(buffer) {
+            Ok(()) => {
+                self.reader_position += buffer.len() as u64;
+                true
+            },
+            Err(_) => {
+                // The reader could be anywhere now, so make sure the next read seeks to the right place
+                self.reader_position = self.reader.stream_position().unwrap_or(u64::MAX);
+                false
+            },
+        }
+    }
+}
+
+// What's in a Xing or VBRI header
+struct InfoTag {
+    // The number of packets of audio in the file
+    packets: Option<u32>,
+
+    // The number of frames of silence the encoder added to the start and end, from a LAME tag
+    gapless: Option<(u64, u64)>,
+}
+
+// Reads the Xing (or Info, which is the same thing for CBR files) or VBRI header which may take the place of the
+// audio in the first packet of a file
+fn read_info_tag(packet: &[u8], header: &FrameHeader) -> Option<InfoTag> {
+    let read_u32 =
+        |offset: usize| packet.get(offset..(offset + 4)).map(|n| u32::from_be_bytes([n[0], n[1], n[2], n[3]]));
+
+    // VBRI headers always come 32 bytes after the frame header
+    if packet.get(36..40) == Some(b"VBRI") {
+        return Some(InfoTag { packets: read_u32(36 + 14), gapless: None })
+    }
+
+    let mut offset = 4 + header.side_info_bytes();
+    match packet.get(offset..(offset + 4)) {
+        Some(b"Xing") | Some(b"Info") => (),
+        _ => return None,
+    }
+    let flags = read_u32(offset + 4)?;
+    offset += 8;
+
+    // The flags say which of the frame count, byte count, seek table and quality come next
+    let packets = if flags & 0x1 != 0 { read_u32(offset) } else { None };
+    for &(flag, len) in &[(0x1, 4), (0x2, 4), (0x4, 100), (0x8, 4)] {
+        if flags & flag != 0 {
+            offset += len;
+        }
+    }
+
+    // After that comes the LAME tag, if the encoder wrote one, which starts with the encoder's name. The encoder's
+    // delay and padding are 12 bits each, 21 bytes in.
+    let gapless = match packet.get(offset..(offset + 24)) {
+        Some(lame) if matches!(&lame[0..4], b"LAME" | b"Lavf" | b"Lavc") => {
+            let trim = u32::from_be_bytes([0, lame[21], lame[22], lame[23]]);
+            Some((u64::from(trim >> 12), u64::from(trim & 0xFFF)))
+        },
+        _ => None,
+    };
+    Some(InfoTag { packets, gapless })
+}
+
+// Skips past any ID3v2 tags at the start of the file, returning where the audio starts
+fn skip_id3v2<R: Read + Seek>(reader: &mut R) -> Result<u64, Error> {
+    let mut start = 0;
+    loop {
+        let mut header = [0u8; 10];
+        reader.seek(SeekFrom::Start(start)).map_err(|e| Error::Io(e.kind()))?;
+        match reader.read_exact(&mut header) {
+            Ok(()) if &header[0..3] == b"ID3" => {
+                // The size is stored 7 bits to a byte, and doesn't include the header or the footer (if there is one)
+                let size = header[6..10].iter().fold(0u64, |size, &byte| (size << 7) | u64::from(byte & 0x7F));
+                let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+                start += 10 + size + footer;
+            },
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::Io(e.kind())),
+        }
+    }
+    reader.seek(SeekFrom::Start(start)).map_err(|e| Error::Io(e.kind()))?;
+    Ok(start)
+}
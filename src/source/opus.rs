@@ -0,0 +1,370 @@
+use super::{xiph, Length, Seekable, Source};
+use audiopus::{
+    coder::{Decoder, GenericCtl},
+    packet::{self as opus_packet, Packet as OpusPacket},
+    Channels, MutSignals, SampleRate,
+};
+use ogg::{OggReadError, Packet, PacketReader};
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
+
+// Opus always decodes at 48kHz, and granule positions count frames at that rate whatever the input rate was
+const SAMPLE_RATE: u32 = 48000;
+
+// The longest a packet can be (120ms)
+const MAX_PACKET_FRAMES: usize = 5760;
+
+// How far before a seek target to start decoding, so the decoder has settled by the time it gets there (80ms)
+const SEEK_PREROLL: u64 = 3840;
+
+/// A Source which decodes an Ogg Opus file as it's played.
+/// By default it decodes from a file in memory, but it can also stream from any reader (see `from_reader`).
+/// Only the first logical stream in the file is played, and it must be mono or stereo.
+///
+/// Opus always decodes at 48kHz, so this usually wants to go through a `Resampler`.
+pub struct OpusPlayer<R: Read + Seek = Cursor<Vec<u8>>> {
+    packets: PacketReader<R>,
+    stream_serial: u32,
+    decoder: Decoder,
+    channels: usize,
+    comments: Vec<(String, String)>,
+
+    // How many frames at the start of the stream are only there to prime the decoder, and shouldn't be played
+    pre_skip: u64,
+
+    // The total number of frames in the stream (not counting the pre-skip), taken from the granule position of its
+    // last page
+    length: Option<u64>,
+
+    // The granule position the next packet's audio starts at
+    granule: u64,
+
+    // Samples which have been decoded but not written yet, starting at `decoded[decoded_offset]`
+    decoded: Vec<f32>,
+    decoded_offset: usize,
+
+    // The index of the next sample to be written
+    position: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// This does not appear to be an Ogg Opus file
+    InvalidFile,
+
+    /// The audio data in this file is malformed
+    MalformedData,
+
+    /// This file uses a channel mapping which isn't supported (more than two channels, or more than one stream)
+    UnsupportedChannels,
+
+    /// The underlying reader returned an error while the file was being read
+    Io(io::ErrorKind),
+}
+
+impl From<OggReadError> for Error {
+    fn from(error: OggReadError) -> Self {
+        match error {
+            OggReadError::ReadError(e) => Error::Io(e.kind()),
+            OggReadError::NoCapturePatternFound | OggReadError::InvalidStreamStructVer(_) => Error::InvalidFile,
+            _ => Error::MalformedData,
+        }
+    }
+}
+
+impl OpusPlayer {
+    pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        Self::from_reader(Cursor::new(file.into()))
+    }
+}
+
+impl<R: Read + Seek> OpusPlayer<R> {
+    /// Creates an OpusPlayer which streams its file from a reader, only reading as much as it needs at a time.
+    pub fn from_reader(mut reader: R) -> Result<Self, Error> {
+        let length = xiph::read_ogg_length(&mut reader).map_err(|e| Error::Io(e.kind()))?;
+        let mut packets = PacketReader::new(reader);
+
+        // The first packet in the file starts the stream we'll play, and the stream starts with two headers
+        let head_packet = packets.read_packet_expected()?;
+        let stream_serial = head_packet.stream_serial();
+        let head = parse_head(&head_packet.data)?;
+        let tags_packet = xiph::read_header_packet(&mut packets, stream_serial)?;
+        if !tags_packet.data.starts_with(b"OpusTags") {
+            return Err(Error::MalformedData)
+        }
+
+        let channels = match head.channels {
+            1 => Channels::Mono,
+            _ => Channels::Stereo,
+        };
+        let decoder = Decoder::new(SampleRate::Hz48000, channels).map_err(|_| Error::MalformedData)?;
+        decoder.set_gain(i32::from(head.output_gain)).map_err(|_| Error::MalformedData)?;
+
+        Ok(Self {
+            packets,
+            stream_serial,
+            decoder,
+            channels: head.channels,
+            comments: xiph::parse_comments(&tags_packet.data[8..]),
+            pre_skip: head.pre_skip,
+            length: length
+                .filter(|&(serial, _)| serial == stream_serial)
+                .map(|(_, granule)| granule.saturating_sub(head.pre_skip)),
+            granule: 0,
+            decoded: Vec::new(),
+            decoded_offset: 0,
+            position: 0,
+        })
+    }
+
+    /// Returns the total number of frames in this file, if it could be found.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Returns the comments stored in this file as (field, value) pairs, such as `("TITLE", "...")`.
+    /// Field names aren't case-sensitive, and the same field may appear more than once.
+    pub fn comments(&self) -> &[(String, String)] {
+        &self.comments
+    }
+
+    /// Returns the value of the first comment with the given field name, ignoring case.
+    pub fn comment(&self, field: &str) -> Option<&str> {
+        self.comments.iter().find(|(name, _)| name.eq_ignore_ascii_case(field)).map(|(_, value)| value.as_str())
+    }
+
+    /// Consumes this OpusPlayer and returns the reader it was streaming from.
+    pub fn into_inner(self) -> R {
+        self.packets.into_inner()
+    }
+
+    // Reads the next packet in our stream, returning None at the end of the stream or if it can't be read
+    fn read_packet(&mut self) -> Option<Packet> {
+        loop {
+            match self.packets.read_packet() {
+                Ok(Some(packet)) if packet.stream_serial() == self.stream_serial => return Some(packet),
+                Ok(Some(_)) => (),
+                Ok(None) | Err(_) => return None,
+            }
+        }
+    }
+
+    // Decodes a packet into interleaved samples. A packet which can't be decoded becomes silence, so that everything
+    // after it still lines up with the granule positions.
+    fn decode_packet(&mut self, packet: &[u8]) -> Vec<f32> {
+        let mut samples = vec![0.0; MAX_PACKET_FRAMES * self.channels];
+        let output = MutSignals::try_from(&mut samples[..]).expect("output buffer is empty");
+        // An empty packet means one was lost, which the decoder can fill in for
+        let frames = match self.decoder.decode_float(OpusPacket::try_from(packet).ok(), output, false) {
+            Ok(frames) => frames,
+            Err(_) => {
+                samples.iter_mut().for_each(|sample| *sample = 0.0);
+                OpusPacket::try_from(packet)
+                    .ok()
+                    .and_then(|packet| opus_packet::nb_samples(packet, SampleRate::Hz48000).ok())
+                    .unwrap_or(0)
+            },
+        };
+        samples.truncate(frames.min(MAX_PACKET_FRAMES) * self.channels);
+        samples
+    }
+
+    // Decodes the next packet into `decoded`, returning false once there's nothing left to decode
+    fn decode_next(&mut self) -> bool {
+        loop {
+            let packet = match self.read_packet() {
+                Some(packet) => packet,
+                None => return false,
+            };
+            // We'll come across the header packets again after seeking back to the start of the file
+            if packet.data.starts_with(b"OpusHead") || packet.data.starts_with(b"OpusTags") {
+                continue
+            }
+
+            let mut samples = self.decode_packet(&packet.data);
+            let start = self.granule;
+            self.granule += (samples.len() / self.channels) as u64;
+
+            // The last packet may be padded, in which case the granule position of its page says where it ends,
+            // and anything before the pre-skip isn't part of the stream
+            let mut end = self.granule;
+            if packet.last_in_stream() {
+                end = end.min(packet.absgp_page());
+            }
+            samples.truncate(end.saturating_sub(start) as usize * self.channels);
+            let skip = (self.pre_skip.saturating_sub(start) as usize * self.channels).min(samples.len());
+            samples.drain(..skip);
+            if samples.is_empty() {
+                continue
+            }
+
+            self.decoded = samples;
+            self.decoded_offset = 0;
+            return true
+        }
+    }
+
+    // Decodes and throws away samples until the next one to be written is the start of `frame`
+    fn skip_to(&mut self, frame: u64) {
+        let target = frame * self.channels as u64;
+        while self.position < target {
+            if self.decoded_offset == self.decoded.len() && !self.decode_next() {
+                break
+            }
+            let available = self.decoded.len() - self.decoded_offset;
+            let count = (target - self.position).min(available as u64);
+            self.decoded_offset += count as usize;
+            self.position += count;
+        }
+    }
+
+    // Seeks to the end of a page at or before granule position `goal`, which is a point where we know exactly which
+    // frame comes next. Returns false if the reader couldn't seek.
+    fn seek_before(&mut self, granule: u64) -> bool {
+        let mut goal = granule;
+        let mut step = MAX_PACKET_FRAMES as u64;
+        loop {
+            // This lands on the first page which finishes at or after `goal`
+            match self.packets.seek_absgp(Some(self.stream_serial), goal) {
+                Ok(true) => (),
+                _ => return false,
+            }
+
+            // The first packet may be the end of one which started on an earlier page, so skip to the end of the
+            // page, where the next packet's audio starts at the page's granule position
+            let page_end = loop {
+                match self.read_packet() {
+                    Some(packet) if packet.last_in_page() => break packet.absgp_page(),
+                    Some(_) => (),
+                    None => return false,
+                }
+            };
+
+            if page_end <= granule {
+                self.decoded.clear();
+                self.decoded_offset = 0;
+                self.granule = page_end;
+                self.position = page_end.saturating_sub(self.pre_skip) * self.channels as u64;
+                return true
+            }
+            if goal == 0 {
+                return false
+            }
+
+            // That page finished too late, so aim further back, and further still if that doesn't work either
+            goal = goal.saturating_sub(step.max(page_end.saturating_sub(goal)));
+            step *= 2;
+        }
+    }
+
+    // Goes back to the start of the file
+    fn rewind(&mut self) {
+        self.decoded.clear();
+        self.decoded_offset = 0;
+        self.granule = 0;
+        self.position = 0;
+        let _ = self.packets.seek_bytes(SeekFrom::Start(0));
+    }
+}
+
+impl<R: Read + Seek> Source for OpusPlayer<R> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.decoded_offset == self.decoded.len() {
+                if !self.decode_next() {
+                    break
+                }
+                continue
+            }
+
+            let samples = &self.decoded[self.decoded_offset..];
+            let count = samples.len().min(buffer.len() - written);
+            buffer[written..(written + count)].copy_from_slice(&samples[..count]);
+            self.decoded_offset += count;
+            self.position += count as u64;
+            written += count;
+        }
+        written
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(SAMPLE_RATE)
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.length {
+            Some(length) => Length::Exact(length),
+            None => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.length {
+            Some(length) => Length::Exact(length.saturating_sub(self.position / self.channels as u64)),
+            None => Length::Unknown,
+        }
+    }
+}
+
+impl<R: Read + Seek> Seekable for OpusPlayer<R> {
+    fn seek_frame(&mut self, frame: u64) {
+        let frame = self.length.map_or(frame, |length| frame.min(length));
+
+        // Frames are counted from the end of the pre-skip, and the decoder needs a run-up to get back in sync
+        if !self.seek_before((frame + self.pre_skip).saturating_sub(SEEK_PREROLL)) {
+            self.rewind();
+        }
+        let _ = self.decoder.reset_state();
+        self.skip_to(frame);
+    }
+}
+
+// The parts of an identification header we need
+struct Head {
+    channels: usize,
+    pre_skip: u64,
+
+    // How much to amplify the output by, in 1/256ths of a decibel
+    output_gain: i16,
+}
+
+fn parse_head(data: &[u8]) -> Result<Head, Error> {
+    // The major version is the top 4 bits of the version, and only version 0 exists so far
+    if data.len() < 19 || !data.starts_with(b"OpusHead") || data[8] >> 4 != 0 {
+        return Err(Error::InvalidFile)
+    }
+    let channels = usize::from(data[9]);
+    let pre_skip = u64::from(u16::from_le_bytes([data[10], data[11]]));
+    let output_gain = i16::from_le_bytes([data[16], data[17]]);
+
+    // Mapping family 0 is always one mono or stereo stream. The other families say how many streams there are and
+    // which channels go where, which is fine as long as it's still one stream in the usual order.
+    let mapping_family = data[18];
+    let supported = match mapping_family {
+        0 => (1..=2).contains(&channels),
+        _ => match data.get(19..(21 + channels)) {
+            Some(mapping) => {
+                let (streams, coupled) = (mapping[0], mapping[1]);
+                let order = &mapping[2..];
+                streams == 1
+                    && match channels {
+                        1 => coupled == 0 && order == [0],
+                        2 => coupled == 1 && order == [0, 1],
+                        _ => false,
+                    }
+            },
+            None => return Err(Error::InvalidFile),
+        },
+    };
+    if !supported {
+        return Err(Error::UnsupportedChannels)
+    }
+    Ok(Head { channels, pre_skip, output_gain })
+}
@@ -0,0 +1,119 @@
+mod engine;
+mod it;
+mod module;
+mod protracker;
+mod s3m;
+mod xm;
+
+use self::engine::Engine;
+use super::Source;
+
+// The sample rate modules are rendered at, unless they're given another one
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// A Source which plays a tracker module: a MOD, S3M, XM or IT file.
+/// Modules are rendered as they're played, always in stereo. Since songs can jump around and loop back on
+/// themselves, a TrackerPlayer doesn't know its length ahead of time; it ends once the song reaches its end or
+/// starts repeating itself, unless it's set to loop.
+pub struct TrackerPlayer {
+    engine: Engine,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// This does not appear to be a MOD, S3M, XM or IT file
+    InvalidFile,
+
+    /// The module data in this file is malformed
+    MalformedData,
+}
+
+/// The file format a module was loaded from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A ProTracker MOD file, or one from a tracker which copied it
+    Mod,
+
+    /// A Scream Tracker 3 file
+    S3m,
+
+    /// A FastTracker 2 file
+    Xm,
+
+    /// An Impulse Tracker file
+    It,
+}
+
+/// The point in the song a TrackerPlayer is playing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    /// The index in the song's order list
+    pub order: usize,
+
+    /// The pattern that order plays
+    pub pattern: usize,
+
+    /// The row within that pattern
+    pub row: usize,
+}
+
+impl TrackerPlayer {
+    pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        let file = file.into();
+        let module = if xm::detect(&file) {
+            xm::load(&file)?
+        } else if it::detect(&file) {
+            it::load(&file)?
+        } else if s3m::detect(&file) {
+            s3m::load(&file)?
+        } else if protracker::detect(&file) {
+            protracker::load(&file)?
+        } else {
+            return Err(Error::InvalidFile)
+        };
+        Ok(Self { engine: Engine::new(module, DEFAULT_SAMPLE_RATE) })
+    }
+
+    /// Sets the sample rate to render the module at (eg. 44100). This is 48000 by default.
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.engine.set_sample_rate(sample_rate.max(1));
+        self
+    }
+
+    /// Returns the format of the file this module was loaded from.
+    pub fn format(&self) -> Format {
+        self.engine.module().format
+    }
+
+    /// Returns the title of the song, which may be empty.
+    pub fn title(&self) -> &str {
+        &self.engine.module().title
+    }
+
+    /// Returns the point in the song currently being played, which is useful for syncing things up to the music.
+    /// This is the row being rendered, so it's ahead of what can be heard by however much the output buffers.
+    pub fn position(&self) -> Position {
+        let (order, pattern, row) = self.engine.position();
+        Position { order, pattern, row }
+    }
+
+    /// Sets whether the song should go back to its restart point once it ends, or once it jumps back to somewhere
+    /// it's already played, rather than stopping there. This is off by default.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.engine.set_looping(looping);
+    }
+}
+
+impl Source for TrackerPlayer {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        self.engine.render(buffer)
+    }
+
+    fn channel_count(&self) -> usize {
+        2
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.engine.sample_rate())
+    }
+}
@@ -0,0 +1,1216 @@
+// Plays a Module one tick at a time. Each channel of the module plays its notes on a voice, and voices can carry on
+// playing in the background after a channel moves on to a new note, so that notes can ring out or fade.
+
+use super::{
+    module::{
+        pan_from_byte, Cell, Effect, Envelope, Loop, Module, NewNoteAction, Note, Order, Sample, VolumeCommand,
+        BASE_NOTE,
+    },
+    Format,
+};
+use std::{collections::HashSet, f64::consts::TAU};
+
+// Amiga periods are kept 4 times finer than on the Amiga, like Scream Tracker does, so that fine slides can move
+// them by less than a whole period. This turns them into frequencies, and gives C-4 a period of 1712 at 8363Hz.
+const AMIGA_CLOCK: f64 = 8363.0 * 1712.0;
+
+// Linear periods go down by 64 for every semitone, and this is the period of `BASE_NOTE`
+const LINEAR_BASE_PERIOD: f64 = 4608.0;
+
+// The most voices which can play at once. Past this, the quietest background voice is stopped for a new one.
+const MAX_VOICES: usize = 256;
+
+// How long it takes a voice to change its volume or pan, so that changes don't click
+const RAMP_SECONDS: f64 = 0.002;
+
+// The fade-out level a voice starts at
+const FADE_MAX: u32 = 65536;
+
+pub(super) struct Engine {
+    module: Module,
+    sample_rate: u32,
+    channels: Vec<Channel>,
+    voices: Vec<Voice>,
+
+    // Where playback is in the song
+    order: usize,
+    pattern: usize,
+    row: usize,
+    tick: u32,
+
+    // The number of ticks the current row lasts, including any delays
+    row_ticks: u32,
+    speed: u32,
+    tempo: u32,
+
+    // From 0 to 128
+    global_volume: i32,
+
+    // Where the current row's effects have asked to go next
+    jump_order: Option<usize>,
+    jump_row: Option<usize>,
+    loop_row: Option<usize>,
+
+    // Every order and row the song has jumped to or moved on to, so that it can tell once it's repeating itself
+    visited: HashSet<(usize, usize)>,
+    looping: bool,
+    finished: bool,
+
+    // Whether the first tick has been processed yet
+    started: bool,
+
+    // Frames left to render in the current tick, and the fraction of a frame left over from the last tick
+    tick_frames: usize,
+    frame_remainder: f64,
+    ramp_frames: usize,
+    random: u32,
+}
+
+// The playing state of one of the module's channels
+#[derive(Default)]
+struct Channel {
+    // The voice playing this channel's current note, if any
+    voice: Option<usize>,
+    muted: bool,
+
+    // The last instrument played (starting from 1, with 0 meaning none), and the sample and note it played
+    instrument: usize,
+    sample: Option<usize>,
+    note: u8,
+
+    // The sample rate of the note's sample, which Amiga periods already include
+    rate: f64,
+    period: f64,
+    target_period: f64,
+
+    // From 0 to 64
+    volume: i32,
+    channel_volume: i32,
+
+    // From 0 to 256
+    pan: i32,
+
+    // This row's cell, with its effect's parameter filled in from memory if it was left out
+    cell: Cell,
+    effect: Effect,
+    memory: Memory,
+
+    // Changes made by effects which only last for the current tick
+    vibrato_delta: f64,
+    tremolo_delta: i32,
+    panbrello_delta: i32,
+    arpeggio: i32,
+
+    vibrato: Oscillator,
+    tremolo: Oscillator,
+    panbrello: Oscillator,
+    tremor_ticks: u32,
+    retrigger_ticks: u32,
+
+    // IT's SAx, which sets the part of the sample offset above 0xFFFF
+    high_offset: usize,
+
+    // The row a pattern loop goes back to, and how many times it has left to go back
+    loop_start: usize,
+    loop_count: u8,
+}
+
+// The last parameters given to effects which can be left out to mean "the same as last time"
+#[derive(Default)]
+struct Memory {
+    volume_slide: u8,
+    portamento_up: u8,
+    portamento_down: u8,
+    fine_portamento_up: u8,
+    fine_portamento_down: u8,
+    extra_fine_portamento_up: u8,
+    extra_fine_portamento_down: u8,
+    tone_portamento: u8,
+    tremor: u8,
+    arpeggio: u8,
+    retrigger: u8,
+    sample_offset: u8,
+    panning_slide: u8,
+    channel_volume_slide: u8,
+    global_volume_slide: u8,
+}
+
+// The state of a vibrato, tremolo or panbrello effect
+#[derive(Clone, Copy, Default)]
+struct Oscillator {
+    // From 0 to 63
+    position: u8,
+    speed: u8,
+    depth: u8,
+
+    // The waveform (see `waveform`), plus 4 if the position shouldn't go back to the start for new notes
+    waveform: u8,
+}
+
+impl Oscillator {
+    // Sets the speed and depth from an effect's parameter, leaving either alone if it's 0
+    fn set(&mut self, param: u8) {
+        if param >> 4 != 0 {
+            self.speed = param >> 4;
+        }
+        if param & 0x0F != 0 {
+            self.depth = param & 0x0F;
+        }
+    }
+
+    fn value(&self, random: &mut u32) -> i32 {
+        waveform(self.waveform & 3, self.position, random)
+    }
+
+    fn advance(&mut self) {
+        self.position = (self.position + self.speed) & 63;
+    }
+
+    fn retrigger(&mut self) {
+        if self.waveform & 4 == 0 {
+            self.position = 0;
+        }
+    }
+}
+
+// A sample being played
+#[derive(Default)]
+struct Voice {
+    active: bool,
+
+    // The channel which played this voice, which may since have moved on to another voice
+    channel: usize,
+    sample: usize,
+    instrument: Option<usize>,
+
+    // The position in the sample, and whether it's playing backwards through a ping-pong loop
+    position: f64,
+    backwards: bool,
+
+    // Set by the channel for as long as this is its current voice. Volume is from 0 to 1, and pan from 0 to 256.
+    frequency: f64,
+    volume: f32,
+    pan: i32,
+
+    // Whether the note has been released, letting it leave its sustain loops, and whether it's fading out
+    released: bool,
+    fading: bool,
+    fade: u32,
+
+    // Whether the voice is ramping down to silence before it stops
+    stopping: bool,
+    new_note_action: Option<NewNoteAction>,
+
+    // The volume, panning and pitch envelopes' positions, and whether each is turned on
+    envelope_ticks: [u16; 3],
+    envelopes_enabled: [bool; 3],
+    vibrato_position: u8,
+    vibrato_ticks: u32,
+
+    // How far to move through the sample each frame, and the left and right gains to ramp towards
+    step: f64,
+    gains: [f32; 2],
+    targets: [f32; 2],
+    ramp: usize,
+}
+
+impl Engine {
+    pub fn new(module: Module, sample_rate: u32) -> Self {
+        let channels = module
+            .channels
+            .iter()
+            .map(|settings| Channel {
+                muted: settings.muted,
+                channel_volume: i32::from(settings.volume),
+                pan: i32::from(settings.pan),
+                rate: 8363.0,
+                ..Default::default()
+            })
+            .collect();
+        let mut engine = Self {
+            sample_rate,
+            channels,
+            voices: Vec::new(),
+            order: 0,
+            pattern: 0,
+            row: 0,
+            tick: 0,
+            row_ticks: 1,
+            speed: u32::from(module.speed),
+            tempo: u32::from(module.tempo),
+            global_volume: i32::from(module.global_volume),
+            jump_order: None,
+            jump_row: None,
+            loop_row: None,
+            visited: HashSet::new(),
+            looping: false,
+            finished: false,
+            started: false,
+            tick_frames: 0,
+            frame_remainder: 0.0,
+            ramp_frames: 1,
+            random: 0x1234_5678,
+            module,
+        };
+        engine.set_sample_rate(sample_rate);
+        engine.finished = !engine.go_to(0, 0, true);
+        engine
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.ramp_frames = ((f64::from(sample_rate) * RAMP_SECONDS) as usize).max(1);
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    // Returns the order, pattern and row being played
+    pub fn position(&self) -> (usize, usize, usize) {
+        (self.order, self.pattern, self.row)
+    }
+
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    // Renders interleaved stereo frames into the buffer, returning the number of samples written.
+    // This only returns less than the buffer's length once the song has ended.
+    pub fn render(&mut self, buffer: &mut [f32]) -> usize {
+        let len = buffer.len() / 2 * 2;
+        let buffer = &mut buffer[..len];
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.tick_frames == 0 {
+                if self.finished || !self.process_tick() {
+                    self.finished = true;
+                    break
+                }
+                continue
+            }
+            let frames = self.tick_frames.min((buffer.len() - written) / 2);
+            let output = &mut buffer[written..(written + frames * 2)];
+            output.iter_mut().for_each(|sample| *sample = 0.0);
+            for voice in self.voices.iter_mut().filter(|voice| voice.active) {
+                voice.mix(&self.module.samples[voice.sample], output);
+            }
+            self.tick_frames -= frames;
+            written += frames * 2;
+        }
+        written
+    }
+
+    // Moves on to the next tick, processing the row if it's the first tick of one, and works out how long it
+    // lasts. Returns false if the song has ended.
+    fn process_tick(&mut self) -> bool {
+        if self.started {
+            self.tick += 1;
+            if self.tick >= self.row_ticks {
+                self.tick = 0;
+                if !self.next_row() {
+                    return false
+                }
+            }
+        }
+        self.started = true;
+
+        if self.tick == 0 {
+            self.read_row();
+        }
+        for index in 0..self.channels.len() {
+            self.update_channel(index);
+        }
+        self.update_voices();
+
+        let frames = f64::from(self.sample_rate) * 2.5 / f64::from(self.tempo) + self.frame_remainder;
+        self.tick_frames = frames as usize;
+        self.frame_remainder = frames.fract();
+        true
+    }
+
+    // Moves to the next row, following any jumps. Returns false if the song has ended.
+    fn next_row(&mut self) -> bool {
+        if self.jump_order.is_some() || self.jump_row.is_some() {
+            let order = self.jump_order.take().unwrap_or(self.order + 1);
+            let row = self.jump_row.take().unwrap_or(0);
+            self.loop_row = None;
+            return self.go_to(order, row, true)
+        }
+        if let Some(row) = self.loop_row.take() {
+            self.row = row;
+            return true
+        }
+        if self.row + 1 < self.module.patterns[self.pattern].rows {
+            self.row += 1;
+            true
+        } else {
+            self.go_to(self.order + 1, 0, true)
+        }
+    }
+
+    // Moves to a row of an order, passing over any orders which don't play a pattern. If the song ends there, this
+    // goes back to the restart order when looping, and otherwise returns false. The same goes for going back to
+    // somewhere the song has already been.
+    fn go_to(&mut self, order: usize, row: usize, check_visited: bool) -> bool {
+        let (mut order, mut row) = (order, row);
+        let mut restarted = false;
+        loop {
+            match self.module.orders.get(order) {
+                Some(Order::Pattern(pattern)) if *pattern < self.module.patterns.len() => {
+                    self.order = order;
+                    self.pattern = *pattern;
+                    break
+                },
+                Some(Order::Pattern(_)) | Some(Order::Skip) => order += 1,
+                Some(Order::End) | None => {
+                    if !self.looping || restarted {
+                        return false
+                    }
+                    restarted = true;
+                    order = self.module.restart;
+                    row = 0;
+                },
+            }
+        }
+        self.row = if row < self.module.patterns[self.pattern].rows { row } else { 0 };
+        for channel in self.channels.iter_mut() {
+            channel.loop_start = 0;
+        }
+        self.visited.insert((self.order, self.row)) || !check_visited || self.looping
+    }
+
+    // Reads the current row's cells, and processes the effects which change the song's position or timing
+    fn read_row(&mut self) {
+        let pattern = &self.module.patterns[self.pattern];
+        let channel_count = self.channels.len();
+        let cells = &pattern.cells[(self.row * channel_count)..((self.row + 1) * channel_count)];
+        let mut pattern_delay = None;
+        let mut fine_delay = 0;
+        for (channel, cell) in self.channels.iter_mut().zip(cells) {
+            channel.cell = *cell;
+            match cell.effect {
+                Effect::SetSpeed(speed) if speed != 0 => self.speed = u32::from(speed),
+                Effect::SetTempo(tempo) if tempo >= 0x20 => self.tempo = u32::from(tempo),
+                Effect::PositionJump(order) => {
+                    self.jump_order = Some(usize::from(order));
+                    self.jump_row = self.jump_row.or(Some(0));
+                },
+                Effect::PatternBreak(row) => {
+                    self.jump_order = self.jump_order.or(Some(self.order + 1));
+                    self.jump_row = Some(usize::from(row));
+                },
+                Effect::PatternLoop(0) => channel.loop_start = self.row,
+                Effect::PatternLoop(count) => {
+                    if channel.loop_count == 0 {
+                        channel.loop_count = count;
+                        self.loop_row = Some(channel.loop_start);
+                    } else {
+                        channel.loop_count -= 1;
+                        if channel.loop_count != 0 {
+                            self.loop_row = Some(channel.loop_start);
+                        }
+                    }
+                },
+                // Only the first pattern delay in a row counts
+                Effect::PatternDelay(delay) => pattern_delay = pattern_delay.or(Some(u32::from(delay))),
+                Effect::FinePatternDelay(delay) => fine_delay += u32::from(delay),
+                Effect::SetGlobalVolume(volume) => self.global_volume = i32::from(volume),
+                _ => (),
+            }
+        }
+        self.row_ticks = self.speed * (1 + pattern_delay.unwrap_or(0)) + fine_delay;
+    }
+
+    // Processes a channel's note and effects for the current tick, then passes what it's playing to its voice
+    fn update_channel(&mut self, index: usize) {
+        let format = self.module.format;
+        let tick = self.tick;
+        let first = tick == 0;
+        let cell = self.channels[index].cell;
+        {
+            let channel = &mut self.channels[index];
+            channel.vibrato_delta = 0.0;
+            channel.tremolo_delta = 0;
+            channel.panbrello_delta = 0;
+            channel.arpeggio = 0;
+            if first {
+                channel.effect = channel.recall(cell.effect, format);
+            }
+        }
+
+        // A note delay holds back the note and volume column until the given tick
+        let note_tick = match cell.effect {
+            Effect::NoteDelay(delay) => u32::from(delay),
+            _ => 0,
+        };
+        if tick == note_tick {
+            self.start_note(index, cell);
+        }
+        self.apply_volume_command(index, cell.volume, tick == note_tick);
+        self.apply_effect(index, first);
+
+        // Pass the channel's pitch, volume and pan on to its voice
+        let linear = self.module.linear_slides;
+        let channel = &mut self.channels[index];
+        if let Some(voice) = channel.voice {
+            let voice = &mut self.voices[voice];
+            let period = clamp_period(channel.period + channel.vibrato_delta, linear);
+            voice.frequency = frequency(period, channel.rate, linear) * 2f64.powf(f64::from(channel.arpeggio) / 12.0);
+            let volume = (channel.volume + channel.tremolo_delta).clamp(0, 64);
+            voice.volume = if channel.muted { 0.0 } else { (volume * channel.channel_volume) as f32 / 4096.0 };
+            voice.pan = (channel.pan + channel.panbrello_delta).clamp(0, 256);
+        }
+    }
+
+    // Starts, stops or releases a channel's note, and sets its instrument
+    fn start_note(&mut self, index: usize, cell: Cell) {
+        let format = self.module.format;
+        let linear = self.module.linear_slides;
+        let tone_portamento = matches!(cell.effect, Effect::TonePortamento(_) | Effect::TonePortamentoVolumeSlide(_))
+            || matches!(cell.volume, VolumeCommand::TonePortamento(_));
+        if cell.instrument != 0 {
+            self.channels[index].instrument = usize::from(cell.instrument);
+        }
+        let module = &self.module;
+        let instrument_index = self.channels[index].instrument.checked_sub(1);
+        let instrument = instrument_index.and_then(|instrument| module.instruments.get(instrument));
+        let instrument_pan = instrument.and_then(|instrument| instrument.pan);
+
+        match cell.note {
+            Note::On(note) => {
+                let (note, sample) = match instrument {
+                    Some(instrument) => instrument.keymap[usize::from(note)],
+                    None => return,
+                };
+                let sample_index = usize::from(sample).wrapping_sub(1);
+                let rate = match module.samples.get(sample_index) {
+                    Some(sample) if !sample.data.is_empty() => sample.rate,
+                    _ => return,
+                };
+                let new_note_action =
+                    if format == Format::It { instrument.map(|instrument| instrument.new_note_action) } else { None };
+
+                let playing = self.channels[index].voice.map_or(false, |voice| self.voices[voice].active);
+                if tone_portamento && playing {
+                    // Slide to the new note instead of playing it
+                    let channel = &mut self.channels[index];
+                    channel.target_period = period(note, channel.rate, linear);
+                } else {
+                    let channel = &mut self.channels[index];
+                    channel.sample = Some(sample_index);
+                    channel.note = note;
+                    channel.rate = rate;
+                    channel.period = period(note, rate, linear);
+                    channel.target_period = channel.period;
+                    channel.vibrato.retrigger();
+                    channel.tremolo.retrigger();
+                    channel.retrigger_ticks = 0;
+                    let offset = match channel.effect {
+                        Effect::SampleOffset(offset) => (channel.high_offset << 16) + (usize::from(offset) << 8),
+                        _ => 0,
+                    };
+                    self.play_voice(index, sample_index, instrument_index, new_note_action, offset as f64);
+                }
+            },
+            Note::Off => self.release(index),
+            Note::Cut => self.stop_voice(index),
+            Note::Fade => {
+                if let Some(voice) = self.channels[index].voice {
+                    self.voices[voice].fading = true;
+                }
+            },
+            Note::None => (),
+        }
+
+        // An instrument number resets the volume and panning to the sample's defaults
+        if cell.instrument != 0 {
+            let samples = &self.module.samples;
+            let channel = &mut self.channels[index];
+            if let Some(sample) = channel.sample.and_then(|sample| samples.get(sample)) {
+                channel.volume = i32::from(sample.volume);
+                if let Some(pan) = sample.pan.or(instrument_pan) {
+                    channel.pan = i32::from(pan);
+                }
+            }
+        }
+    }
+
+    // Starts a new voice for a channel. The channel's old voice is cut, unless its instrument says otherwise.
+    fn play_voice(
+        &mut self,
+        index: usize,
+        sample: usize,
+        instrument: Option<usize>,
+        new_note_action: Option<NewNoteAction>,
+        offset: f64,
+    ) {
+        if let Some(old) = self.channels[index].voice.take() {
+            let old = &mut self.voices[old];
+            match old.new_note_action.unwrap_or(NewNoteAction::Cut) {
+                NewNoteAction::Cut => old.stopping = true,
+                NewNoteAction::Continue => (),
+                NewNoteAction::Off => old.released = true,
+                NewNoteAction::Fade => old.fading = true,
+            }
+        }
+
+        let voice_index = match self.voices.iter().position(|voice| !voice.active) {
+            Some(free) => free,
+            None if self.voices.len() < MAX_VOICES => {
+                self.voices.push(Voice::default());
+                self.voices.len() - 1
+            },
+            None => {
+                // Steal the quietest voice which isn't any channel's current note
+                let current: Vec<usize> = self.channels.iter().filter_map(|channel| channel.voice).collect();
+                let quietest = self
+                    .voices
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| !current.contains(i))
+                    .min_by(|(_, a), (_, b)| (a.gains[0] + a.gains[1]).total_cmp(&(b.gains[0] + b.gains[1])))
+                    .map(|(i, _)| i);
+                match quietest {
+                    Some(quietest) => quietest,
+                    None => return,
+                }
+            },
+        };
+
+        for channel in self.channels.iter_mut().filter(|channel| channel.voice == Some(voice_index)) {
+            channel.voice = None;
+        }
+        let envelopes_enabled = match instrument.and_then(|instrument| self.module.instruments.get(instrument)) {
+            Some(instrument) => [
+                instrument.volume_envelope.enabled,
+                instrument.panning_envelope.enabled,
+                instrument.pitch_envelope.enabled,
+            ],
+            None => [false; 3],
+        };
+        let length = self.module.samples[sample].data.len() as f64;
+        self.voices[voice_index] = Voice {
+            active: true,
+            channel: index,
+            sample,
+            instrument,
+            position: offset.min(length),
+            fade: FADE_MAX,
+            new_note_action,
+            envelopes_enabled,
+            pan: 128,
+            ..Default::default()
+        };
+        self.channels[index].voice = Some(voice_index);
+    }
+
+    // Releases a channel's note, letting it leave its sustain loops and start to fade out
+    fn release(&mut self, index: usize) {
+        let module = &self.module;
+        let channel = &mut self.channels[index];
+        let voice = match channel.voice {
+            Some(voice) => &mut self.voices[voice],
+            None => return,
+        };
+        voice.released = true;
+        let envelope = voice.instrument.and_then(|instrument| module.instruments.get(instrument));
+        let envelope = envelope.map(|instrument| &instrument.volume_envelope);
+        let has_envelope = voice.envelopes_enabled[0] && envelope.map_or(false, |envelope| envelope.enabled);
+        match module.format {
+            // Without an envelope, FastTracker 2 silences the note straight away
+            Format::Xm if has_envelope => voice.fading = true,
+            Format::Xm => channel.volume = 0,
+            Format::It => {
+                if !has_envelope || envelope.map_or(false, |envelope| envelope.looped.is_some()) {
+                    voice.fading = true;
+                }
+            },
+            Format::Mod | Format::S3m => voice.stopping = true,
+        }
+    }
+
+    // Stops a channel's note, ramping it down to silence first
+    fn stop_voice(&mut self, index: usize) {
+        if let Some(voice) = self.channels[index].voice.take() {
+            self.voices[voice].stopping = true;
+        }
+    }
+
+    // Processes the volume column. `first` is whether this is the tick the note started on.
+    fn apply_volume_command(&mut self, index: usize, command: VolumeCommand, first: bool) {
+        let linear = self.module.linear_slides;
+        let channel = &mut self.channels[index];
+        match command {
+            VolumeCommand::SetVolume(volume) if first => channel.volume = i32::from(volume),
+            VolumeCommand::SlideUp(amount) if !first => channel.slide_volume(i32::from(amount)),
+            VolumeCommand::SlideDown(amount) if !first => channel.slide_volume(-i32::from(amount)),
+            VolumeCommand::FineSlideUp(amount) if first => channel.slide_volume(i32::from(amount)),
+            VolumeCommand::FineSlideDown(amount) if first => channel.slide_volume(-i32::from(amount)),
+            VolumeCommand::SetPanning(pan) if first => channel.pan = i32::from(pan_from_byte(pan)),
+            VolumeCommand::PanningSlideLeft(amount) if !first => channel.slide_pan(-i32::from(amount)),
+            VolumeCommand::PanningSlideRight(amount) if !first => channel.slide_pan(i32::from(amount)),
+            VolumeCommand::VibratoSpeed(speed) => {
+                if first {
+                    channel.vibrato.set(speed << 4);
+                } else {
+                    channel.vibrate(5, &mut self.random);
+                }
+            },
+            VolumeCommand::VibratoDepth(depth) => {
+                if first {
+                    channel.vibrato.set(depth);
+                } else {
+                    channel.vibrate(5, &mut self.random);
+                }
+            },
+            VolumeCommand::TonePortamento(speed) => {
+                if first && speed != 0 {
+                    channel.memory.tone_portamento = speed;
+                } else if !first {
+                    channel.tone_portamento(channel.memory.tone_portamento);
+                }
+            },
+            VolumeCommand::PortamentoUp(amount) if !first => channel.slide_pitch(-4.0 * f64::from(amount), linear),
+            VolumeCommand::PortamentoDown(amount) if !first => channel.slide_pitch(4.0 * f64::from(amount), linear),
+            _ => (),
+        }
+    }
+
+    // Processes the effect column
+    fn apply_effect(&mut self, index: usize, first: bool) {
+        let format = self.module.format;
+        let linear = self.module.linear_slides;
+        let fast_slides = self.module.fast_volume_slides;
+        let s3m_like = matches!(format, Format::S3m | Format::It);
+        let tick = self.tick;
+        let row_tick = tick % self.speed.max(1);
+        let channel = &mut self.channels[index];
+        match channel.effect {
+            Effect::Arpeggio(param) => {
+                channel.arpeggio = match tick % 3 {
+                    1 => i32::from(param >> 4),
+                    2 => i32::from(param & 0x0F),
+                    _ => 0,
+                };
+            },
+            Effect::PortamentoUp(param) => channel.slide_pitch(-portamento(param, first, s3m_like), linear),
+            Effect::PortamentoDown(param) => channel.slide_pitch(portamento(param, first, s3m_like), linear),
+            Effect::FinePortamentoUp(param) if first => channel.slide_pitch(-4.0 * f64::from(param), linear),
+            Effect::FinePortamentoDown(param) if first => channel.slide_pitch(4.0 * f64::from(param), linear),
+            Effect::ExtraFinePortamentoUp(param) if first => channel.slide_pitch(-f64::from(param), linear),
+            Effect::ExtraFinePortamentoDown(param) if first => channel.slide_pitch(f64::from(param), linear),
+            Effect::TonePortamento(speed) if !first => channel.tone_portamento(speed),
+            Effect::TonePortamentoVolumeSlide(param) => {
+                if !first {
+                    channel.tone_portamento(channel.memory.tone_portamento);
+                }
+                channel.slide_volume(volume_slide(param, first, s3m_like, fast_slides));
+            },
+            // Vibrato is applied on the first tick in S3M and IT files, but doesn't move on until the next one
+            Effect::Vibrato(_) | Effect::FineVibrato(_) | Effect::VibratoVolumeSlide(_) if first && !s3m_like => (),
+            Effect::Vibrato(_) => channel.vibrate(5, &mut self.random),
+            Effect::FineVibrato(_) => channel.vibrate(7, &mut self.random),
+            Effect::VibratoVolumeSlide(param) => {
+                channel.vibrate(5, &mut self.random);
+                channel.slide_volume(volume_slide(param, first, s3m_like, fast_slides));
+            },
+            Effect::Tremolo(_) if !first => {
+                channel.tremolo_delta =
+                    (channel.tremolo.value(&mut self.random) * i32::from(channel.tremolo.depth)) >> 6;
+                channel.tremolo.advance();
+            },
+            Effect::Tremor(param) => {
+                // FastTracker 2 adds one to both times, and the others treat 0 as 1
+                let (on, off) = if format == Format::Xm {
+                    (u32::from(param >> 4) + 1, u32::from(param & 0x0F) + 1)
+                } else {
+                    (u32::from(param >> 4).max(1), u32::from(param & 0x0F).max(1))
+                };
+                if channel.tremor_ticks % (on + off) >= on {
+                    channel.tremolo_delta = -64;
+                }
+                channel.tremor_ticks += 1;
+            },
+            Effect::Panbrello(param) => {
+                if first {
+                    channel.panbrello.set(param);
+                }
+                channel.panbrello_delta =
+                    (channel.panbrello.value(&mut self.random) * i32::from(channel.panbrello.depth)) >> 4;
+                channel.panbrello.advance();
+            },
+            Effect::SetPanning(pan) if first => channel.pan = i32::from(pan_from_byte(pan)),
+            Effect::PanningSlide(param) if format == Format::Xm && !first => {
+                channel.slide_pan(i32::from(param >> 4) - i32::from(param & 0x0F));
+            },
+            Effect::PanningSlide(_) if format == Format::Xm => (),
+            // Panning slides the other way to volume, and in steps of a quarter of the range
+            Effect::PanningSlide(param) => channel.slide_pan(-4 * volume_slide(param, first, true, false)),
+            Effect::HighSampleOffset(offset) if first => channel.high_offset = usize::from(offset),
+            Effect::VolumeSlide(param) => channel.slide_volume(volume_slide(param, first, s3m_like, fast_slides)),
+            Effect::FineVolumeSlideUp(amount) if first => channel.slide_volume(i32::from(amount)),
+            Effect::FineVolumeSlideDown(amount) if first => channel.slide_volume(-i32::from(amount)),
+            Effect::SetVolume(volume) if first => channel.volume = i32::from(volume),
+            Effect::SetChannelVolume(volume) if first => channel.channel_volume = i32::from(volume),
+            Effect::ChannelVolumeSlide(param) => {
+                let slide = volume_slide(param, first, true, false);
+                channel.channel_volume = (channel.channel_volume + slide).clamp(0, 64);
+            },
+            Effect::GlobalVolumeSlide(param) => {
+                // IT's global volume has twice the steps of the others'
+                let slide = volume_slide(param, first, s3m_like, false) * if format == Format::It { 1 } else { 2 };
+                self.global_volume = (self.global_volume + slide).clamp(0, 128);
+            },
+            Effect::SetTempo(param) if param < 0x20 && !first => {
+                let slide = i32::from(param & 0x0F) * if param & 0xF0 == 0 { -1 } else { 1 };
+                self.tempo = (self.tempo as i32 + slide).clamp(32, 255) as u32;
+            },
+            Effect::Retrigger(param) => {
+                let interval = u32::from(param & 0x0F);
+                if interval != 0 && !first {
+                    channel.retrigger_ticks += 1;
+                    if channel.retrigger_ticks >= interval {
+                        channel.retrigger_ticks = 0;
+                        channel.volume = retrigger_volume(channel.volume, param >> 4);
+                        if let Some(voice) = channel.voice {
+                            self.voices[voice].retrigger();
+                        }
+                    }
+                }
+            },
+            Effect::NoteCut(cut_tick) if row_tick == u32::from(cut_tick) => {
+                if format == Format::It {
+                    self.stop_voice(index);
+                } else {
+                    channel.volume = 0;
+                }
+            },
+            Effect::KeyOff(off_tick) if row_tick == u32::from(off_tick) => self.release(index),
+            Effect::SetEnvelopePosition(position) if first => {
+                if let Some(voice) = channel.voice {
+                    let voice = &mut self.voices[voice];
+                    voice.envelope_ticks[0] = u16::from(position);
+                    voice.envelope_ticks[1] = u16::from(position);
+                }
+            },
+            Effect::SetVibratoWaveform(waveform) if first => channel.vibrato.waveform = waveform & 7,
+            Effect::SetTremoloWaveform(waveform) if first => channel.tremolo.waveform = waveform & 7,
+            Effect::SetPanbrelloWaveform(waveform) if first => channel.panbrello.waveform = waveform & 7,
+            Effect::SetFinetune(finetune) if first => {
+                let samples = &self.module.samples;
+                let rate = match format {
+                    // In eighths of a semitone, from -8 to 7
+                    Format::Mod => Some(8363.0 * 2f64.powf(f64::from((finetune << 4) as i8 >> 4) / 96.0)),
+                    Format::S3m => Some(S3M_FINETUNES[usize::from(finetune & 0x0F)]),
+                    // In sixteenths of a semitone, from -8 to 7, replacing the sample's own finetune
+                    Format::Xm => channel.sample.and_then(|sample| samples.get(sample)).map(|sample| {
+                        let change = (i32::from(finetune & 0x0F) - 8) * 16 - i32::from(sample.finetune);
+                        sample.rate * 2f64.powf(f64::from(change) / (128.0 * 12.0))
+                    }),
+                    Format::It => None,
+                };
+                if let Some(rate) = rate {
+                    channel.rate = rate;
+                    channel.period = period(channel.note, rate, linear);
+                    channel.target_period = channel.period;
+                }
+            },
+            Effect::InstrumentControl(control) if first => self.instrument_control(index, control),
+            _ => (),
+        }
+    }
+
+    // Processes IT's S7x, which acts on the channel's background voices, or changes how its current voice plays
+    fn instrument_control(&mut self, index: usize, control: u8) {
+        let current = self.channels[index].voice;
+        if control <= 2 {
+            let background = self
+                .voices
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, voice)| voice.active && voice.channel == index && Some(*i) != current);
+            for (_, voice) in background {
+                match control {
+                    0 => voice.stopping = true,
+                    1 => voice.released = true,
+                    _ => voice.fading = true,
+                }
+            }
+            return
+        }
+        let voice = match current {
+            Some(voice) => &mut self.voices[voice],
+            None => return,
+        };
+        match control {
+            3 => voice.new_note_action = Some(NewNoteAction::Cut),
+            4 => voice.new_note_action = Some(NewNoteAction::Continue),
+            5 => voice.new_note_action = Some(NewNoteAction::Off),
+            6 => voice.new_note_action = Some(NewNoteAction::Fade),
+            7..=12 => voice.envelopes_enabled[usize::from((control - 7) / 2)] = control % 2 == 0,
+            _ => (),
+        }
+    }
+
+    // Works out each voice's envelopes, fade-out and auto-vibrato for this tick, and from them its gains
+    fn update_voices(&mut self) {
+        let preamp = self.module.mix_volume / (self.channels.len().max(4) as f32 / 4.0).sqrt();
+        let global_volume = self.global_volume as f32 / 128.0 * preamp;
+        let module = &self.module;
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            if !voice.active {
+                continue
+            }
+            // A voice which isn't its channel's current note any more has to find its own way to silence
+            if self.channels[voice.channel].voice != Some(index) && voice.new_note_action.is_none() {
+                voice.stopping = true;
+            }
+
+            let sample = &module.samples[voice.sample];
+            let mut volume = voice.volume * f32::from(sample.global_volume) / 64.0 * global_volume;
+            let mut pan = voice.pan as f32;
+            let mut frequency = voice.frequency;
+            if let Some(instrument) = voice.instrument.and_then(|instrument| module.instruments.get(instrument)) {
+                volume *= f32::from(instrument.global_volume) / 128.0;
+                let envelopes = [&instrument.volume_envelope, &instrument.panning_envelope, &instrument.pitch_envelope];
+                for (which, envelope) in envelopes.iter().enumerate() {
+                    if !envelope.enabled || !voice.envelopes_enabled[which] {
+                        continue
+                    }
+                    let tick = &mut voice.envelope_ticks[which];
+                    let value = envelope_value(envelope, *tick);
+                    let ended = advance_envelope(envelope, tick, voice.released);
+                    match which {
+                        0 => {
+                            volume *= value / 64.0;
+                            if ended && value == 0.0 {
+                                voice.stopping = true;
+                            }
+                        },
+                        1 => pan += value * (128.0 - (pan - 128.0).abs()) / 32.0,
+                        _ => frequency *= 2f64.powf(f64::from(value) / 24.0),
+                    }
+                }
+
+                if voice.fading {
+                    voice.fade = voice.fade.saturating_sub(instrument.fadeout);
+                    if voice.fade == 0 {
+                        voice.stopping = true;
+                    }
+                }
+                volume *= voice.fade as f32 / FADE_MAX as f32;
+            }
+
+            // Auto-vibrato is in 64ths of a semitone, and builds up over its sweep. Its depth is in quarters of that.
+            let vibrato = sample.vibrato;
+            if vibrato.depth != 0 {
+                let depth = if u32::from(vibrato.sweep) > voice.vibrato_ticks {
+                    u32::from(vibrato.depth) * voice.vibrato_ticks / u32::from(vibrato.sweep)
+                } else {
+                    u32::from(vibrato.depth)
+                };
+                let wave = waveform(vibrato.waveform, voice.vibrato_position >> 2, &mut self.random);
+                let delta = (wave * depth as i32) >> 10;
+                frequency *= 2f64.powf(-f64::from(delta) / 768.0);
+                voice.vibrato_position = voice.vibrato_position.wrapping_add(vibrato.speed);
+                voice.vibrato_ticks += 1;
+            }
+
+            // Constant-power panning, so notes don't get quieter in the middle
+            let pan = pan.clamp(0.0, 256.0) / 256.0;
+            voice.targets = if voice.stopping { [0.0; 2] } else { [volume * (1.0 - pan).sqrt(), volume * pan.sqrt()] };
+            voice.ramp = self.ramp_frames;
+            voice.step = frequency / f64::from(self.sample_rate);
+        }
+    }
+}
+
+impl Channel {
+    // Fills in an effect's parameter from memory if it was left out
+    fn recall(&mut self, effect: Effect, format: Format) -> Effect {
+        let memory = &mut self.memory;
+        let recall = |slot: &mut u8, param: u8| {
+            if param != 0 {
+                *slot = param;
+            }
+            *slot
+        };
+        match effect {
+            Effect::TonePortamento(speed) => Effect::TonePortamento(recall(&mut memory.tone_portamento, speed)),
+            Effect::Vibrato(param) | Effect::FineVibrato(param) => {
+                self.vibrato.set(param);
+                effect
+            },
+            Effect::Tremolo(param) => {
+                self.tremolo.set(param);
+                effect
+            },
+            Effect::SampleOffset(offset) => Effect::SampleOffset(recall(&mut memory.sample_offset, offset)),
+
+            // MOD files don't remember any other effects
+            _ if format == Format::Mod => effect,
+            Effect::VolumeSlide(param) => Effect::VolumeSlide(recall(&mut memory.volume_slide, param)),
+            Effect::TonePortamentoVolumeSlide(param) => {
+                Effect::TonePortamentoVolumeSlide(recall(&mut memory.volume_slide, param))
+            },
+            Effect::VibratoVolumeSlide(param) => Effect::VibratoVolumeSlide(recall(&mut memory.volume_slide, param)),
+            // S3M and IT share the memory between sliding up and down
+            Effect::PortamentoUp(param) => Effect::PortamentoUp(recall(&mut memory.portamento_up, param)),
+            Effect::PortamentoDown(param) if format == Format::Xm => {
+                Effect::PortamentoDown(recall(&mut memory.portamento_down, param))
+            },
+            Effect::PortamentoDown(param) => Effect::PortamentoDown(recall(&mut memory.portamento_up, param)),
+            Effect::FinePortamentoUp(param) => Effect::FinePortamentoUp(recall(&mut memory.fine_portamento_up, param)),
+            Effect::FinePortamentoDown(param) => {
+                Effect::FinePortamentoDown(recall(&mut memory.fine_portamento_down, param))
+            },
+            Effect::ExtraFinePortamentoUp(param) => {
+                Effect::ExtraFinePortamentoUp(recall(&mut memory.extra_fine_portamento_up, param))
+            },
+            Effect::ExtraFinePortamentoDown(param) => {
+                Effect::ExtraFinePortamentoDown(recall(&mut memory.extra_fine_portamento_down, param))
+            },
+            Effect::Tremor(param) => Effect::Tremor(recall(&mut memory.tremor, param)),
+            Effect::Arpeggio(param) => Effect::Arpeggio(recall(&mut memory.arpeggio, param)),
+            Effect::Retrigger(param) => Effect::Retrigger(recall(&mut memory.retrigger, param)),
+            Effect::PanningSlide(param) => Effect::PanningSlide(recall(&mut memory.panning_slide, param)),
+            Effect::ChannelVolumeSlide(param) => {
+                Effect::ChannelVolumeSlide(recall(&mut memory.channel_volume_slide, param))
+            },
+            Effect::GlobalVolumeSlide(param) => {
+                Effect::GlobalVolumeSlide(recall(&mut memory.global_volume_slide, param))
+            },
+            _ => effect,
+        }
+    }
+
+    fn slide_volume(&mut self, amount: i32) {
+        self.volume = (self.volume + amount).clamp(0, 64);
+    }
+
+    fn slide_pan(&mut self, amount: i32) {
+        self.pan = (self.pan + amount).clamp(0, 256);
+    }
+
+    // Slides the period by an amount, where a positive amount lowers the pitch
+    fn slide_pitch(&mut self, amount: f64, linear: bool) {
+        self.period = clamp_period(self.period + amount, linear);
+    }
+
+    fn tone_portamento(&mut self, speed: u8) {
+        let speed = f64::from(speed) * 4.0;
+        self.period = if self.period < self.target_period {
+            (self.period + speed).min(self.target_period)
+        } else {
+            (self.period - speed).max(self.target_period)
+        };
+    }
+
+    // Applies vibrato for this tick, where `shift` says how fine it is
+    fn vibrate(&mut self, shift: u32, random: &mut u32) {
+        let delta = (self.vibrato.value(random) * i32::from(self.vibrato.depth)) >> shift;
+        self.vibrato_delta = f64::from(delta);
+        self.vibrato.advance();
+    }
+}
+
+impl Voice {
+    // Starts the voice's sample again from the beginning
+    fn retrigger(&mut self) {
+        self.position = 0.0;
+        self.backwards = false;
+    }
+
+    // Adds this voice to interleaved stereo output, ramping its gains towards their targets
+    fn mix(&mut self, sample: &Sample, output: &mut [f32]) {
+        let data = &sample.data;
+        for frame in output.chunks_exact_mut(2) {
+            if self.ramp > 0 {
+                for (gain, target) in self.gains.iter_mut().zip(self.targets) {
+                    *gain += (target - *gain) / self.ramp as f32;
+                }
+                self.ramp -= 1;
+            } else if self.stopping {
+                self.active = false;
+                return
+            }
+
+            // Loops only apply once the position has got into them, and sustain loops stop once the note's released
+            let active_loop = sample.sustain.filter(|_| !self.released).or(sample.looped);
+            let index = self.position as usize;
+            let value = match data.get(index) {
+                Some(&current) => {
+                    let next = match active_loop {
+                        Some(Loop { start, end, ping_pong: false }) if index + 1 >= end => data[start],
+                        Some(Loop { end, ping_pong: true, .. }) if index + 1 >= end => current,
+                        _ => data.get(index + 1).copied().unwrap_or(0.0),
+                    };
+                    let fraction = self.position.fract() as f32;
+                    current + (next - current) * fraction
+                },
+                None => 0.0,
+            };
+            frame[0] += value * self.gains[0];
+            frame[1] += value * self.gains[1];
+
+            if self.backwards {
+                self.position -= self.step;
+            } else {
+                self.position += self.step;
+            }
+            match active_loop {
+                Some(Loop { start, end, ping_pong: true })
+                    if (self.backwards && self.position < start as f64) || self.position >= end as f64 =>
+                {
+                    // Fold the position back into the loop, as if it had bounced back and forth through it
+                    let (start, length) = (start as f64, (end - start) as f64);
+                    let unfolded =
+                        if self.backwards { 2.0 * length - (self.position - start) } else { self.position - start };
+                    let unfolded = unfolded.rem_euclid(2.0 * length);
+                    self.backwards = unfolded >= length;
+                    self.position = if self.backwards { start + 2.0 * length - unfolded } else { start + unfolded };
+                },
+                Some(Loop { start, end, ping_pong: false }) if self.position >= end as f64 => {
+                    let (start, length) = (start as f64, (end - start) as f64);
+                    self.position = start + (self.position - start).rem_euclid(length);
+                    self.backwards = false;
+                },
+                _ if self.position >= data.len() as f64 || self.position < 0.0 => {
+                    self.active = false;
+                    return
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+// The rate each of S3M's finetune values plays C-4 at
+const S3M_FINETUNES: [f64; 16] = [
+    8363.0, 8413.0, 8463.0, 8529.0, 8581.0, 8651.0, 8723.0, 8757.0, 7895.0, 7941.0, 7985.0, 8046.0, 8107.0, 8169.0,
+    8232.0, 8280.0,
+];
+
+// Returns the period of a note played with a sample with the given rate
+fn period(note: u8, rate: f64, linear: bool) -> f64 {
+    let semitones = f64::from(note) - f64::from(BASE_NOTE);
+    if linear { LINEAR_BASE_PERIOD - semitones * 64.0 } else { AMIGA_CLOCK / (rate * 2f64.powf(semitones / 12.0)) }
+}
+
+// Returns the frequency to play a sample at for a period
+fn frequency(period: f64, rate: f64, linear: bool) -> f64 {
+    if linear { rate * 2f64.powf((LINEAR_BASE_PERIOD - period) / 768.0) } else { AMIGA_CLOCK / period }
+}
+
+// Keeps a period within about 10 octaves either side of `BASE_NOTE`
+fn clamp_period(period: f64, linear: bool) -> f64 {
+    if linear {
+        period.clamp(LINEAR_BASE_PERIOD - 7680.0, LINEAR_BASE_PERIOD + 7680.0)
+    } else {
+        period.clamp(1712.0 / 1024.0, 1712.0 * 1024.0)
+    }
+}
+
+// Returns how far a pitch slide moves the period on this tick, where S3M and IT can give it a fine (xFy) or extra
+// fine (xEy) slide instead, which only moves on the first tick
+fn portamento(param: u8, first: bool, s3m_like: bool) -> f64 {
+    let amount = match param >> 4 {
+        0xF if s3m_like => return if first { f64::from(param & 0x0F) * 4.0 } else { 0.0 },
+        0xE if s3m_like => return if first { f64::from(param & 0x0F) } else { 0.0 },
+        _ => f64::from(param) * 4.0,
+    };
+    if first { 0.0 } else { amount }
+}
+
+// Returns how far a volume slide (Axy in MOD and XM, or Dxy in S3M and IT) moves the volume on this tick. S3M and IT
+// can give it a fine slide instead (DxF or DFy), which only moves on the first tick, and S3M files made with old
+// versions of Scream Tracker slide on the first tick as well.
+fn volume_slide(param: u8, first: bool, s3m_like: bool, fast: bool) -> i32 {
+    let (up, down) = (i32::from(param >> 4), i32::from(param & 0x0F));
+    let slides_now = !first || fast;
+    if !s3m_like {
+        return match (slides_now, up) {
+            (false, _) => 0,
+            (true, 0) => -down,
+            (true, up) => up,
+        }
+    }
+
+    // Fine slides only move on the first tick, and the rest move whenever a normal slide would
+    let (amount, fine) = match (up, down) {
+        (0xF, 0) => (15, false),
+        (0, 0xF) => (-15, false),
+        (0xF, down) => (-down, true),
+        (up, 0xF) => (up, true),
+        (up, 0) => (up, false),
+        (_, down) => (-down, false),
+    };
+    if (fine && first) || (!fine && slides_now) { amount } else { 0 }
+}
+
+// Changes a volume after a retrigger, based on the top half of the effect's parameter
+fn retrigger_volume(volume: i32, change: u8) -> i32 {
+    let volume = match change {
+        0x1..=0x5 => volume - (1 << (change - 1)),
+        0x6 => volume * 2 / 3,
+        0x7 => volume / 2,
+        0x9..=0xD => volume + (1 << (change - 9)),
+        0xE => volume * 3 / 2,
+        0xF => volume * 2,
+        _ => volume,
+    };
+    volume.clamp(0, 64)
+}
+
+// Returns the value of a waveform, from -255 to 255, at a position from 0 to 63. The waveforms are sine, ramp
+// down, square, random, and ramp up.
+fn waveform(kind: u8, position: u8, random: &mut u32) -> i32 {
+    let position = i32::from(position & 63);
+    match kind {
+        1 => 255 - position * 8,
+        2 if position < 32 => 255,
+        2 => -255,
+        3 => {
+            *random = random.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (*random >> 16) as i32 % 511 - 255
+        },
+        4 => position * 8 - 255,
+        _ => ((f64::from(position) * TAU / 64.0).sin() * 255.0).round() as i32,
+    }
+}
+
+// Returns an envelope's value at a tick, interpolating between its points
+fn envelope_value(envelope: &Envelope, tick: u16) -> f32 {
+    let points = &envelope.points;
+    match points.iter().position(|&(point_tick, _)| point_tick > tick) {
+        Some(0) => f32::from(points[0].1),
+        Some(next) => {
+            let (start_tick, start) = points[next - 1];
+            let (end_tick, end) = points[next];
+            let fraction = f32::from(tick - start_tick) / f32::from(end_tick - start_tick);
+            f32::from(start) + (f32::from(end) - f32::from(start)) * fraction
+        },
+        None => points.last().map_or(0.0, |point| f32::from(point.1)),
+    }
+}
+
+// Moves an envelope on by a tick, keeping it within its sustain loop until the note's released, and then within its
+// loop. Returns whether it's gone past its last point.
+fn advance_envelope(envelope: &Envelope, tick: &mut u16, released: bool) -> bool {
+    let points = &envelope.points;
+    *tick = tick.saturating_add(1);
+    let active_loop = envelope.sustain.filter(|_| !released).or(envelope.looped);
+    match active_loop {
+        Some((start, end)) if *tick > points[end].0 => {
+            *tick = points[start].0;
+            false
+        },
+        Some(_) => false,
+        None => points.last().map_or(true, |point| *tick > point.0),
+    }
+}
@@ -0,0 +1,466 @@
+// Loads Impulse Tracker (IT) files, which have up to 64 channels, instruments with three envelopes, and notes
+// which can carry on in the background after a new one starts
+
+use super::{
+    module::{
+        downmix_split_stereo, read_name, samples_i16, samples_i8, AutoVibrato, Cell, ChannelSettings, Envelope,
+        Instrument, Loop, Module, NewNoteAction, Note, Order, Pattern, ReadLe, Sample, VolumeCommand, NOTE_COUNT,
+    },
+    s3m, Error, Format,
+};
+
+const MAX_CHANNELS: usize = 64;
+
+// The most points an envelope can have
+const ENVELOPE_POINTS: usize = 25;
+
+// Tone portamento speeds for each step of the volume column's tone portamento
+const TONE_PORTAMENTO_SPEEDS: [u8; 10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+
+// Returns whether a file looks like an IT file
+pub(super) fn detect(file: &[u8]) -> bool {
+    file.starts_with(b"IMPM")
+}
+
+pub(super) fn load(file: &[u8]) -> Result<Module, Error> {
+    if !detect(file) || file.len() < 0xC0 {
+        return Err(Error::InvalidFile)
+    }
+    let order_count = usize::from(file.u16_at(0x20).ok_or(Error::InvalidFile)?);
+    let instrument_count = usize::from(file.u16_at(0x22).ok_or(Error::InvalidFile)?);
+    let sample_count = usize::from(file.u16_at(0x24).ok_or(Error::InvalidFile)?);
+    let pattern_count = usize::from(file.u16_at(0x26).ok_or(Error::InvalidFile)?);
+    let compatible_version = file.u16_at(0x2A).ok_or(Error::InvalidFile)?;
+    let flags = file.u16_at(0x2C).ok_or(Error::InvalidFile)?;
+    let (stereo, use_instruments, linear_slides) = (flags & 1 != 0, flags & 4 != 0, flags & 8 != 0);
+
+    // After the header comes the order list, then pointers to each instrument, sample and pattern
+    let orders_start = 0xC0;
+    let instruments_start = orders_start + order_count;
+    let samples_start = instruments_start + instrument_count * 4;
+    let patterns_start = samples_start + sample_count * 4;
+    let pointer = |table: usize, index: usize| {
+        file.u32_at(table + index * 4).map(|pointer| pointer as usize).ok_or(Error::MalformedData)
+    };
+
+    let orders = file
+        .bytes_at(orders_start, order_count)
+        .ok_or(Error::MalformedData)?
+        .iter()
+        .map(|&order| match order {
+            0xFF => Order::End,
+            0xFE => Order::Skip,
+            pattern => Order::Pattern(usize::from(pattern)),
+        })
+        .collect();
+
+    let samples = (0..sample_count)
+        .map(|index| read_sample(file, pointer(samples_start, index)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let instruments = if use_instruments {
+        (0..instrument_count)
+            .map(|index| read_instrument(file, pointer(instruments_start, index)?, compatible_version >= 0x200))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        (1..=sample_count as u16).map(Instrument::for_sample).collect()
+    };
+
+    // Patterns are read with all 64 channels, then trimmed down to the ones which are actually used
+    let mut patterns = (0..pattern_count)
+        .map(|index| read_pattern(file, pointer(patterns_start, index)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let channel_count = patterns
+        .iter()
+        .filter_map(|pattern| {
+            pattern
+                .cells
+                .iter()
+                .enumerate()
+                .filter(|(_, cell)| !is_empty(cell))
+                .map(|(i, _)| i % MAX_CHANNELS + 1)
+                .max()
+        })
+        .max()
+        .unwrap_or(1);
+    for pattern in patterns.iter_mut() {
+        pattern.cells =
+            pattern.cells.chunks_exact(MAX_CHANNELS).flat_map(|row| &row[..channel_count]).copied().collect();
+    }
+
+    // Channel panning goes from 0 to 64, with 100 meaning surround (which is played in the middle here), and the
+    // top bit set meaning the channel is disabled
+    let channels = (0..channel_count)
+        .map(|index| {
+            let pan = file[0x40 + index];
+            ChannelSettings {
+                pan: if stereo && pan & 0x7F <= 64 { u16::from(pan & 0x7F) * 4 } else { 128 },
+                volume: file[0x80 + index].min(64),
+                muted: pan & 0x80 != 0,
+            }
+        })
+        .collect();
+
+    Ok(Module {
+        format: Format::It,
+        title: read_name(&file[0x04..0x1E]),
+        channels,
+        orders,
+        restart: 0,
+        patterns,
+        instruments,
+        samples,
+        speed: Some(file[0x32]).filter(|&speed| speed != 0).unwrap_or(6),
+        tempo: Some(file[0x33]).filter(|&tempo| tempo >= 0x20).unwrap_or(125),
+        global_volume: file[0x30].min(128),
+        // The mix volume goes up to 128, and is usually 48
+        mix_volume: f32::from(file[0x31].min(128)) / 48.0,
+        linear_slides,
+        fast_volume_slides: false,
+    })
+}
+
+fn read_sample(file: &[u8], offset: usize) -> Result<Sample, Error> {
+    let header = file.bytes_at(offset, 0x50).ok_or(Error::MalformedData)?;
+    if &header[0..4] != b"IMPS" {
+        return Err(Error::MalformedData)
+    }
+    let flags = header[0x12];
+    let conversion = header[0x2E];
+    let length = header.u32_at(0x30).unwrap_or(0) as usize;
+    let (stereo, is_16_bit, compressed) = (flags & 4 != 0, flags & 2 != 0, flags & 8 != 0);
+
+    let data = if flags & 1 == 0 || length == 0 {
+        Vec::new()
+    } else {
+        let data_offset = header.u32_at(0x48).unwrap_or(0) as usize;
+        let file_data = file.get(data_offset..).unwrap_or(&[]);
+        let channels = if stereo { 2 } else { 1 };
+        let mut data = if compressed {
+            let mut data = Vec::with_capacity(length * channels);
+            let mut input = file_data;
+            for _ in 0..channels {
+                input = decompress(input, length, is_16_bit, conversion & 4 != 0, &mut data);
+            }
+            data
+        } else {
+            let bytes = length * channels * if is_16_bit { 2 } else { 1 };
+            let raw = &file_data[..bytes.min(file_data.len())];
+            let signed = conversion & 1 != 0;
+            match (is_16_bit, signed) {
+                (false, true) => samples_i8(raw),
+                (false, false) => samples_i8(&raw.iter().map(|b| b ^ 0x80).collect::<Vec<_>>()),
+                (true, true) => samples_i16(raw),
+                (true, false) => {
+                    samples_i16(&raw.chunks_exact(2).flat_map(|b| [b[0], b[1] ^ 0x80]).collect::<Vec<_>>())
+                },
+            }
+        };
+        if stereo {
+            data = downmix_split_stereo(data);
+        }
+        data
+    };
+
+    let make_loop = |start: usize, end: usize, enabled: u8, ping_pong: u8| {
+        let start = header.u32_at(start).unwrap_or(0) as usize;
+        let end = header.u32_at(end).unwrap_or(0) as usize;
+        if flags & enabled != 0 { Loop::new(start, end, flags & ping_pong != 0, data.len()) } else { None }
+    };
+    let looped = make_loop(0x34, 0x38, 0x10, 0x40);
+    let sustain = make_loop(0x40, 0x44, 0x20, 0x80);
+
+    // The rate is for C-5, which is an octave above `BASE_NOTE`. The auto-vibrato's sweep is how much its depth
+    // goes up by each tick, out of 256.
+    let rate = f64::from(header.u32_at(0x3C).unwrap_or(8363)) / 2.0;
+    let (depth, sweep_rate) = (header[0x4D].min(64), header[0x4E]);
+    let sweep = if sweep_rate == 0 { 255 } else { (u32::from(depth) * 256 / u32::from(sweep_rate)).min(255) as u8 };
+    Ok(Sample {
+        looped,
+        sustain,
+        rate: if rate > 0.0 { rate } else { 4181.5 },
+        finetune: 0,
+        volume: header[0x13].min(64),
+        global_volume: header[0x11].min(64),
+        pan: if header[0x2F] & 0x80 != 0 { Some(u16::from(header[0x2F] & 0x7F).min(64) * 4) } else { None },
+        vibrato: AutoVibrato { waveform: header[0x4F] & 3, speed: header[0x4C], depth, sweep },
+        data,
+    })
+}
+
+fn read_instrument(file: &[u8], offset: usize, new_format: bool) -> Result<Instrument, Error> {
+    // Old instruments are 554 bytes long, and new ones are 550, but the pitch envelope at the end is optional
+    let min_len = if new_format { 0x1D4 } else { 0x22A };
+    let header = file
+        .get(offset..)
+        .map(|header| &header[..header.len().min(0x22A)])
+        .filter(|header| header.len() >= min_len)
+        .ok_or(Error::MalformedData)?;
+    if &header[0..4] != b"IMPI" {
+        return Err(Error::MalformedData)
+    }
+    let mut instrument = Instrument::for_sample(0);
+    for (entry, key) in instrument.keymap.iter_mut().zip(header[0x40..0x130].chunks_exact(2)) {
+        *entry = (key[0].min(NOTE_COUNT as u8 - 1), u16::from(key[1]));
+    }
+    let new_note_action = |action: u8| match action {
+        1 => NewNoteAction::Continue,
+        2 => NewNoteAction::Off,
+        3 => NewNoteAction::Fade,
+        _ => NewNoteAction::Cut,
+    };
+
+    if new_format {
+        // Fadeout counts down from 1024
+        instrument.fadeout = u32::from(header.u16_at(0x14).unwrap_or(0)) * 64;
+        instrument.new_note_action = new_note_action(header[0x11]);
+        instrument.global_volume = header[0x18].min(128);
+        if header[0x19] & 0x80 == 0 {
+            instrument.pan = Some(u16::from(header[0x19]).min(64) * 4);
+        }
+        instrument.volume_envelope = read_envelope(&header[0x130..0x182]);
+        instrument.panning_envelope = read_envelope(&header[0x182..0x1D4]);
+        if let Some(envelope) = header.get(0x1D4..0x226) {
+            // The top bit means it's a filter envelope, which isn't supported
+            if envelope[0] & 0x80 == 0 {
+                instrument.pitch_envelope = read_envelope(envelope);
+            }
+        }
+    } else {
+        // Instruments from before IT 2.0 only have a volume envelope, stored as (tick, value) pairs which end with
+        // a tick of 0xFF, and fade out from 512
+        instrument.fadeout = u32::from(header.u16_at(0x18).unwrap_or(0)) * 128;
+        instrument.new_note_action = new_note_action(header[0x1A]);
+        let flags = header[0x11];
+        let points: Vec<(u16, i8)> = header[0x1F8..0x22A]
+            .chunks_exact(2)
+            .take_while(|point| point[0] != 0xFF)
+            .map(|point| (u16::from(point[0]), point[1].min(64) as i8))
+            .collect();
+        let point = |index: u8| Some(usize::from(index)).filter(|&index| index < points.len());
+        instrument.volume_envelope = Envelope {
+            enabled: flags & 1 != 0 && !points.is_empty(),
+            looped: if flags & 2 != 0 { point(header[0x12]).zip(point(header[0x13])) } else { None },
+            sustain: if flags & 4 != 0 { point(header[0x14]).zip(point(header[0x15])) } else { None },
+            points,
+        };
+    }
+    Ok(instrument)
+}
+
+// Reads an envelope, which starts with its flags, point count, and loop and sustain points, then has 25 points
+// of a value followed by a tick
+fn read_envelope(envelope: &[u8]) -> Envelope {
+    let flags = envelope[0];
+    let count = usize::from(envelope[1]).min(ENVELOPE_POINTS);
+    let points: Vec<(u16, i8)> = envelope[6..]
+        .chunks_exact(3)
+        .take(count)
+        .map(|point| (u16::from_le_bytes([point[1], point[2]]), (point[0] as i8).clamp(-32, 64)))
+        .collect();
+    let point = |index: u8| Some(usize::from(index)).filter(|&index| index < points.len());
+    Envelope {
+        enabled: flags & 1 != 0 && !points.is_empty(),
+        looped: if flags & 2 != 0 { point(envelope[2]).zip(point(envelope[3])) } else { None },
+        sustain: if flags & 4 != 0 { point(envelope[4]).zip(point(envelope[5])) } else { None },
+        points,
+    }
+}
+
+// Reads a pattern, which is packed so that empty cells take up no space. Each cell starts with a byte saying which
+// channel it's in, and whether a byte follows saying which parts of the cell are there. Otherwise, the same parts
+// as last time are there, and each part may also be marked as being the same as last time.
+fn read_pattern(file: &[u8], offset: usize) -> Result<Pattern, Error> {
+    if offset == 0 {
+        return Ok(Pattern::new(64, MAX_CHANNELS))
+    }
+    let length = usize::from(file.u16_at(offset).ok_or(Error::MalformedData)?);
+    let rows = usize::from(file.u16_at(offset + 2).ok_or(Error::MalformedData)?).clamp(1, 256);
+    let mut data = file.get((offset + 8)..).map(|data| &data[..length.min(data.len())]).unwrap_or(&[]).iter();
+    let mut next = || data.next().copied();
+
+    let mut pattern = Pattern::new(rows, MAX_CHANNELS);
+    let mut masks = [0u8; MAX_CHANNELS];
+    let mut last = [Cell::default(); MAX_CHANNELS];
+    let mut row = 0;
+    while row < rows {
+        let channel_byte = match next() {
+            Some(0) => {
+                row += 1;
+                continue
+            },
+            Some(channel_byte) => channel_byte,
+            None => break,
+        };
+        let channel = usize::from((channel_byte - 1) & 0x3F);
+        if channel_byte & 0x80 != 0 {
+            masks[channel] = next().unwrap_or(0);
+        }
+        let mask = masks[channel];
+        let last = &mut last[channel];
+
+        let mut cell = Cell::default();
+        if mask & 0x01 != 0 {
+            last.note = convert_note(next().unwrap_or(0xFF));
+        }
+        if mask & 0x02 != 0 {
+            last.instrument = next().unwrap_or(0);
+        }
+        if mask & 0x04 != 0 {
+            last.volume = convert_volume(next().unwrap_or(0xFF));
+        }
+        if mask & 0x08 != 0 {
+            let command = next().unwrap_or(0);
+            let param = next().unwrap_or(0);
+            last.effect = s3m::convert_effect(command, param, Format::It);
+        }
+        if mask & 0x11 != 0 {
+            cell.note = last.note;
+        }
+        if mask & 0x22 != 0 {
+            cell.instrument = last.instrument;
+        }
+        if mask & 0x44 != 0 {
+            cell.volume = last.volume;
+        }
+        if mask & 0x88 != 0 {
+            cell.effect = last.effect;
+        }
+        pattern.cells[row * MAX_CHANNELS + channel] = cell;
+    }
+    Ok(pattern)
+}
+
+fn is_empty(cell: &Cell) -> bool {
+    cell.note == Note::None
+        && cell.instrument == 0
+        && cell.volume == VolumeCommand::None
+        && cell.effect == Default::default()
+}
+
+fn convert_note(note: u8) -> Note {
+    match note {
+        0xFF => Note::Off,
+        0xFE => Note::Cut,
+        note if usize::from(note) < NOTE_COUNT => Note::On(note),
+        _ => Note::Fade,
+    }
+}
+
+fn convert_volume(volume: u8) -> VolumeCommand {
+    match volume {
+        0..=64 => VolumeCommand::SetVolume(volume),
+        65..=74 => VolumeCommand::FineSlideUp(volume - 65),
+        75..=84 => VolumeCommand::FineSlideDown(volume - 75),
+        85..=94 => VolumeCommand::SlideUp(volume - 85),
+        95..=104 => VolumeCommand::SlideDown(volume - 95),
+        // These slide 4 times as far as the effects with the same parameter
+        105..=114 => VolumeCommand::PortamentoDown((volume - 105) * 4),
+        115..=124 => VolumeCommand::PortamentoUp((volume - 115) * 4),
+        128..=192 => VolumeCommand::SetPanning(((u16::from(volume - 128) * 255) / 64) as u8),
+        193..=202 => VolumeCommand::TonePortamento(TONE_PORTAMENTO_SPEEDS[usize::from(volume - 193)]),
+        203..=212 => VolumeCommand::VibratoDepth(volume - 203),
+        _ => VolumeCommand::None,
+    }
+}
+
+// Decompresses a sample which was compressed with IT 2.14's compression, adding it to `output`, and returns the
+// data left over after it. The samples are split into blocks which are compressed separately, and each sample
+// is stored as the difference from the last one in a variable number of bits. Certain values change the number
+// of bits instead. IT 2.15 made it store the difference of the differences.
+fn decompress<'a>(
+    mut input: &'a [u8],
+    length: usize,
+    is_16_bit: bool,
+    is_it215: bool,
+    output: &mut Vec<f32>,
+) -> &'a [u8] {
+    let (block_samples, max_width, width_bits, scale) =
+        if is_16_bit { (0x4000, 17, 4, 32768.0) } else { (0x8000, 9, 3, 128.0) };
+    let mut remaining = length;
+    while remaining > 0 {
+        let block_len = match input.u16_at(0) {
+            Some(block_len) => usize::from(block_len),
+            None => break,
+        };
+        let block = &input[2..(2 + block_len).min(input.len())];
+        input = &input[(2 + block_len).min(input.len())..];
+        let mut bits = BitReader { data: block, position: 0 };
+
+        let count = remaining.min(block_samples);
+        let (mut width, mut delta, mut delta2) = (max_width, 0i32, 0i32);
+        let mut written = 0;
+        while written < count {
+            let value = match bits.read(width) {
+                Some(value) => value,
+                None => break,
+            };
+            if width < 7 {
+                // Low widths have one value which means the width follows
+                if value == 1 << (width - 1) {
+                    let new_width = bits.read(width_bits).unwrap_or(0) + 1;
+                    width = if new_width < width { new_width } else { new_width + 1 };
+                    continue
+                }
+            } else if width < max_width {
+                // Medium widths have a range of values just below the middle which give the new width
+                let border = ((if is_16_bit { 0xFFFF } else { 0xFF }) >> (max_width - width)) - (1 << (width_bits - 1));
+                if value > border && value <= border + (1 << width_bits) {
+                    let new_width = value - border;
+                    width = if new_width < width { new_width } else { new_width + 1 };
+                    continue
+                }
+            } else if width == max_width {
+                // The widest width has a bit which says this is a new width
+                if value & (1 << (max_width - 1)) != 0 {
+                    width = (value + 1) & 0xFF;
+                    continue
+                }
+            } else {
+                break
+            }
+
+            // Sign-extend the value to the sample size
+            let sample_bits = max_width - 1;
+            let value = if width < sample_bits {
+                let shift = 32 - width;
+                ((value << shift) as i32) >> shift
+            } else {
+                let shift = 32 - sample_bits;
+                ((value << shift) as i32) >> shift
+            };
+            delta = wrap(delta + value, sample_bits);
+            delta2 = wrap(delta2 + delta, sample_bits);
+            output.push((if is_it215 { delta2 } else { delta }) as f32 / scale);
+            written += 1;
+        }
+        // Fill in anything missing from a truncated block so the sample stays the right length
+        output.resize(output.len() + count - written, 0.0);
+        remaining -= count;
+    }
+    output.resize(output.len() + remaining, 0.0);
+    input
+}
+
+// Wraps a value around to fit in a signed integer with the given number of bits
+fn wrap(value: i32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    (value << shift) >> shift
+}
+
+// Reads bits from a byte slice, starting with the lowest bit of each byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self.data.get(self.position / 8)?;
+            value |= u32::from((byte >> (self.position % 8)) & 1) << i;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
@@ -0,0 +1,359 @@
+// The parts of a tracker module which every format has in common. Each format's loader converts its file into a
+// Module, so the player only has to understand this.
+
+use super::Format;
+
+// The note which plays a sample at its base rate (C-4)
+pub(super) const BASE_NOTE: u8 = 48;
+
+// The number of notes which can be played, from C-0 to B-9
+pub(super) const NOTE_COUNT: usize = 120;
+
+pub(super) struct Module {
+    pub format: Format,
+    pub title: String,
+    pub channels: Vec<ChannelSettings>,
+    pub orders: Vec<Order>,
+
+    // The order to go back to once the song ends, if it's looping
+    pub restart: usize,
+    pub patterns: Vec<Pattern>,
+    pub instruments: Vec<Instrument>,
+    pub samples: Vec<Sample>,
+
+    // The number of ticks per row and the tempo in BPM (where a tick is 2.5ms * 1000 / tempo long)
+    pub speed: u8,
+    pub tempo: u8,
+
+    // From 0 to 128
+    pub global_volume: u8,
+
+    // How much to amplify the whole mix by
+    pub mix_volume: f32,
+
+    // Whether pitch slides are in 64ths of a semitone, or in Amiga periods
+    pub linear_slides: bool,
+
+    // S3M files made with ST3.00 slide volume on the first tick of a row as well as the rest
+    pub fast_volume_slides: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ChannelSettings {
+    // From 0 (left) to 256 (right)
+    pub pan: u16,
+
+    // From 0 to 64
+    pub volume: u8,
+    pub muted: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Order {
+    Pattern(usize),
+
+    // A placeholder which is passed over
+    Skip,
+
+    // Marks the end of the song, even if there are more orders after it
+    End,
+}
+
+pub(super) struct Pattern {
+    pub rows: usize,
+
+    // Each row is stored one after another, with a cell for every channel
+    pub cells: Vec<Cell>,
+}
+
+impl Pattern {
+    // Returns an empty pattern with the given number of rows
+    pub fn new(rows: usize, channels: usize) -> Self {
+        Self { rows, cells: vec![Cell::default(); rows * channels] }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct Cell {
+    pub note: Note,
+
+    // Starting from 1, with 0 meaning there's no instrument in this cell
+    pub instrument: u8,
+    pub volume: VolumeCommand,
+    pub effect: Effect,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum Note {
+    #[default]
+    None,
+
+    // From 0 (C-0) to 119 (B-9)
+    On(u8),
+
+    // Releases the note, letting its envelopes move past their sustain points
+    Off,
+
+    // Stops the note immediately
+    Cut,
+
+    // Fades the note out
+    Fade,
+}
+
+// The volume column in XM and IT files, which holds a second, simpler effect
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum VolumeCommand {
+    #[default]
+    None,
+    SetVolume(u8),
+    SlideUp(u8),
+    SlideDown(u8),
+    FineSlideUp(u8),
+    FineSlideDown(u8),
+
+    // From 0 to 255
+    SetPanning(u8),
+    PanningSlideLeft(u8),
+    PanningSlideRight(u8),
+    VibratoSpeed(u8),
+    VibratoDepth(u8),
+
+    // These take the same parameters as the equivalent effects
+    TonePortamento(u8),
+    PortamentoUp(u8),
+    PortamentoDown(u8),
+}
+
+// The effect column. Loaders convert each format's effects into these, so the same effect in two formats
+// (such as MOD's E1x and S3M's EFx) ends up the same. Where formats read the parameter of an effect differently,
+// it's left as it is in the file, and the player interprets it based on the format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum Effect {
+    #[default]
+    None,
+    Arpeggio(u8),
+    PortamentoUp(u8),
+    PortamentoDown(u8),
+    FinePortamentoUp(u8),
+    FinePortamentoDown(u8),
+    ExtraFinePortamentoUp(u8),
+    ExtraFinePortamentoDown(u8),
+    TonePortamento(u8),
+    Vibrato(u8),
+    FineVibrato(u8),
+    TonePortamentoVolumeSlide(u8),
+    VibratoVolumeSlide(u8),
+    Tremolo(u8),
+    Tremor(u8),
+    Panbrello(u8),
+
+    // From 0 to 255
+    SetPanning(u8),
+    PanningSlide(u8),
+    SampleOffset(u8),
+
+    // IT's SAx, which sets the part of the offset above 0xFFFF
+    HighSampleOffset(u8),
+    VolumeSlide(u8),
+    FineVolumeSlideUp(u8),
+    FineVolumeSlideDown(u8),
+
+    // From 0 to 64
+    SetVolume(u8),
+    SetChannelVolume(u8),
+    ChannelVolumeSlide(u8),
+
+    // From 0 to 128
+    SetGlobalVolume(u8),
+    GlobalVolumeSlide(u8),
+    PositionJump(u8),
+    PatternBreak(u8),
+    PatternLoop(u8),
+
+    // Repeats the row this many more times
+    PatternDelay(u8),
+
+    // Adds this many ticks to the row
+    FinePatternDelay(u8),
+    SetSpeed(u8),
+
+    // Values below 0x20 slide the tempo in IT files
+    SetTempo(u8),
+    Retrigger(u8),
+    NoteCut(u8),
+    NoteDelay(u8),
+    KeyOff(u8),
+    SetEnvelopePosition(u8),
+    SetVibratoWaveform(u8),
+    SetTremoloWaveform(u8),
+    SetPanbrelloWaveform(u8),
+    SetFinetune(u8),
+
+    // IT's S7x, which acts on past notes or changes what happens to this one
+    InstrumentControl(u8),
+}
+
+pub(super) struct Instrument {
+    // The note and sample (starting from 1, with 0 meaning none) to play for each note
+    pub keymap: [(u8, u16); NOTE_COUNT],
+    pub volume_envelope: Envelope,
+    pub panning_envelope: Envelope,
+    pub pitch_envelope: Envelope,
+
+    // How much the volume drops each tick once the note is faded, out of 65536
+    pub fadeout: u32,
+    pub new_note_action: NewNoteAction,
+
+    // From 0 to 128
+    pub global_volume: u8,
+
+    // From 0 to 256, if the instrument overrides its samples' panning
+    pub pan: Option<u16>,
+}
+
+impl Instrument {
+    // Returns an instrument which plays a single sample with nothing else added
+    pub fn for_sample(sample: u16) -> Self {
+        let mut keymap = [(0, sample); NOTE_COUNT];
+        for (note, entry) in keymap.iter_mut().enumerate() {
+            entry.0 = note as u8;
+        }
+        Self {
+            keymap,
+            volume_envelope: Envelope::default(),
+            panning_envelope: Envelope::default(),
+            pitch_envelope: Envelope::default(),
+            fadeout: 0,
+            new_note_action: NewNoteAction::Cut,
+            global_volume: 128,
+            pan: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum NewNoteAction {
+    Cut,
+    Continue,
+    Off,
+    Fade,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct Envelope {
+    pub enabled: bool,
+
+    // (tick, value) pairs, sorted by tick. Volume envelopes go from 0 to 64, and panning and pitch envelopes go
+    // from -32 to 32.
+    pub points: Vec<(u16, i8)>,
+
+    // The first and last points of the loop to play until the note is released
+    pub sustain: Option<(usize, usize)>,
+
+    // The first and last points of the loop to play after that
+    pub looped: Option<(usize, usize)>,
+}
+
+pub(super) struct Sample {
+    // Mono samples, from -1 to 1
+    pub data: Vec<f32>,
+    pub looped: Option<Loop>,
+
+    // A loop which only plays until the note is released
+    pub sustain: Option<Loop>,
+
+    // The sample rate to play the sample at for `BASE_NOTE`
+    pub rate: f64,
+
+    // XM's finetune, in 128ths of a semitone, which is already part of `rate`
+    pub finetune: i8,
+
+    // From 0 to 64
+    pub volume: u8,
+    pub global_volume: u8,
+
+    // From 0 to 256, if the sample sets the channel's panning
+    pub pan: Option<u16>,
+    pub vibrato: AutoVibrato,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Loop {
+    pub start: usize,
+    pub end: usize,
+    pub ping_pong: bool,
+}
+
+impl Loop {
+    // Makes a loop out of a start and end point, leaving it out if it doesn't fit in the sample
+    pub fn new(start: usize, end: usize, ping_pong: bool, length: usize) -> Option<Self> {
+        let end = end.min(length);
+        if start < end { Some(Self { start, end, ping_pong }) } else { None }
+    }
+}
+
+// Vibrato which is applied to every note played with a sample
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct AutoVibrato {
+    // The same as the vibrato effect's waveforms (sine, ramp down, square, random), with 4 for ramp up
+    pub waveform: u8,
+    pub speed: u8,
+    pub depth: u8,
+
+    // How many ticks it takes to reach full depth
+    pub sweep: u8,
+}
+
+// Reads little-endian numbers out of a file, returning None for anything past the end
+pub(super) trait ReadLe {
+    fn u8_at(&self, offset: usize) -> Option<u8>;
+    fn u16_at(&self, offset: usize) -> Option<u16>;
+    fn u32_at(&self, offset: usize) -> Option<u32>;
+    fn bytes_at(&self, offset: usize, len: usize) -> Option<&[u8]>;
+}
+
+impl ReadLe for [u8] {
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.get(offset).copied()
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        self.bytes_at(offset, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.bytes_at(offset, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn bytes_at(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.get(offset..offset.checked_add(len)?)
+    }
+}
+
+// Reads a name stored in a fixed number of bytes, padded with zeroes or spaces
+pub(super) fn read_name(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim_end().to_owned()
+}
+
+// Converts a pan from 0 to 255 into one from 0 to 256
+pub(super) fn pan_from_byte(pan: u8) -> u16 {
+    u16::from(pan) * 256 / 255
+}
+
+// Converts signed 8-bit samples
+pub(super) fn samples_i8(data: &[u8]) -> Vec<f32> {
+    data.iter().map(|&b| f32::from(b as i8) / 128.0).collect()
+}
+
+// Converts signed 16-bit little-endian samples
+pub(super) fn samples_i16(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(2).map(|b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32768.0).collect()
+}
+
+// Mixes the two halves of a stereo sample stored one channel after the other down to mono
+pub(super) fn downmix_split_stereo(data: Vec<f32>) -> Vec<f32> {
+    let half = data.len() / 2;
+    data[..half].iter().zip(&data[half..]).map(|(l, r)| (l + r) * 0.5).collect()
+}
@@ -0,0 +1,186 @@
+// Loads ProTracker MOD files (and the many trackers which copied the format), which have 31 samples and no
+// instruments, and store notes as Amiga periods
+
+use super::{
+    module::{
+        read_name, samples_i8, Cell, ChannelSettings, Effect, Instrument, Loop, Module, Note, Order, Pattern, Sample,
+        BASE_NOTE,
+    },
+    Error, Format,
+};
+
+const SAMPLE_COUNT: usize = 31;
+const ROWS: usize = 64;
+
+// Where the 4-byte signature which says how many channels there are is
+const SIGNATURE_OFFSET: usize = 1080;
+
+// The period of `BASE_NOTE` with no finetune
+const BASE_PERIOD: f64 = 428.0;
+
+// The rate `BASE_NOTE` plays at
+const BASE_RATE: f64 = 8363.0;
+
+// Returns whether a file looks like a MOD file
+pub(super) fn detect(file: &[u8]) -> bool {
+    file.get(SIGNATURE_OFFSET..(SIGNATURE_OFFSET + 4)).and_then(channel_count).is_some()
+}
+
+pub(super) fn load(file: &[u8]) -> Result<Module, Error> {
+    let channels =
+        file.get(SIGNATURE_OFFSET..(SIGNATURE_OFFSET + 4)).and_then(channel_count).ok_or(Error::InvalidFile)?;
+
+    let song_length = usize::from(file[950]).clamp(1, 128);
+    let order_table = &file[952..1080];
+    let orders = order_table[..song_length].iter().map(|&pattern| Order::Pattern(usize::from(pattern))).collect();
+
+    // Every pattern in the order table is stored, even ones past the end of the song
+    let pattern_count = usize::from(order_table.iter().copied().max().unwrap_or(0)) + 1;
+    let pattern_bytes = ROWS * channels * 4;
+    let patterns_start = SIGNATURE_OFFSET + 4;
+    let patterns = (0..pattern_count)
+        .map(|index| {
+            let data = file.get((patterns_start + index * pattern_bytes)..).unwrap_or(&[]);
+            let mut pattern = Pattern::new(ROWS, channels);
+            for (cell, bytes) in pattern.cells.iter_mut().zip(data.chunks_exact(4)) {
+                *cell = read_cell(bytes);
+            }
+            pattern
+        })
+        .collect();
+
+    // The sample data comes after the patterns, one sample after another
+    let mut sample_start = patterns_start + pattern_count * pattern_bytes;
+    let samples: Vec<Sample> = (0..SAMPLE_COUNT)
+        .map(|index| {
+            let header = &file[(20 + index * 30)..(50 + index * 30)];
+            let length = usize::from(u16::from_be_bytes([header[22], header[23]])) * 2;
+            let finetune = ((header[24] & 0x0F) << 4) as i8 >> 4;
+            let loop_start = usize::from(u16::from_be_bytes([header[26], header[27]])) * 2;
+            let loop_length = usize::from(u16::from_be_bytes([header[28], header[29]])) * 2;
+
+            let data = file.get(sample_start..).map(|data| &data[..length.min(data.len())]).unwrap_or(&[]);
+            sample_start += length;
+            let data = samples_i8(data);
+
+            // A loop of one word is how a sample says it doesn't loop
+            let looped =
+                if loop_length > 2 { Loop::new(loop_start, loop_start + loop_length, false, data.len()) } else { None };
+            Sample {
+                looped,
+                sustain: None,
+                // Finetune is in eighths of a semitone
+                rate: BASE_RATE * 2f64.powf(f64::from(finetune) / 96.0),
+                finetune: 0,
+                volume: header[25].min(64),
+                global_volume: 64,
+                pan: None,
+                vibrato: Default::default(),
+                data,
+            }
+        })
+        .collect();
+
+    // Amiga channels are hard-panned left, right, right, left. That's a bit much for headphones, so they're only
+    // panned partway here.
+    let channels = (0..channels)
+        .map(|channel| ChannelSettings {
+            pan: if channel % 4 == 0 || channel % 4 == 3 { 64 } else { 192 },
+            volume: 64,
+            muted: false,
+        })
+        .collect();
+
+    Ok(Module {
+        format: Format::Mod,
+        title: read_name(&file[0..20]),
+        channels,
+        orders,
+        // Trackers put all sorts of things here, so anything out of range means the start
+        restart: Some(usize::from(file[951])).filter(|&restart| restart < song_length).unwrap_or(0),
+        patterns,
+        instruments: (1..=SAMPLE_COUNT as u16).map(Instrument::for_sample).collect(),
+        samples,
+        speed: 6,
+        tempo: 125,
+        global_volume: 128,
+        mix_volume: 1.0,
+        linear_slides: false,
+        fast_volume_slides: false,
+    })
+}
+
+// Works out how many channels a file has from its signature
+fn channel_count(signature: &[u8]) -> Option<usize> {
+    let digit = |b: u8| if b.is_ascii_digit() { Some(usize::from(b - b'0')) } else { None };
+    let channels = match signature {
+        b"M.K." | b"M!K!" | b"M&K!" | b"N.T." | b"FLT4" => 4,
+        b"FLT8" | b"OKTA" | b"OCTA" | b"CD81" => 8,
+        [n, b'C', b'H', b'N'] => digit(*n)?,
+        [b'T', b'D', b'Z', n] => digit(*n)?,
+        [a, b, b'C', b'H'] | [a, b, b'C', b'N'] => digit(*a)? * 10 + digit(*b)?,
+        _ => return None,
+    };
+    Some(channels).filter(|&channels| (1..=32).contains(&channels))
+}
+
+// Reads a cell, which is packed into 4 bytes: the sample number is split between the top 4 bits of the first and
+// third bytes, the period takes up the other 12 bits of the first two, and the effect takes up the rest
+fn read_cell(bytes: &[u8]) -> Cell {
+    let period = (u16::from(bytes[0] & 0x0F) << 8) | u16::from(bytes[1]);
+    let note = if period == 0 {
+        Note::None
+    } else {
+        let note = f64::from(BASE_NOTE) + 12.0 * (BASE_PERIOD / f64::from(period)).log2();
+        Note::On(note.round().clamp(0.0, 119.0) as u8)
+    };
+    Cell {
+        note,
+        instrument: (bytes[0] & 0xF0) | (bytes[2] >> 4),
+        volume: Default::default(),
+        effect: convert_effect(bytes[2] & 0x0F, bytes[3]),
+    }
+}
+
+// Converts one of the effects which MOD and XM files share
+pub(super) fn convert_effect(effect: u8, param: u8) -> Effect {
+    let (x, y) = (param >> 4, param & 0x0F);
+    match effect {
+        0x0 if param == 0 => Effect::None,
+        0x0 => Effect::Arpeggio(param),
+        0x1 => Effect::PortamentoUp(param),
+        0x2 => Effect::PortamentoDown(param),
+        0x3 => Effect::TonePortamento(param),
+        0x4 => Effect::Vibrato(param),
+        0x5 => Effect::TonePortamentoVolumeSlide(param),
+        0x6 => Effect::VibratoVolumeSlide(param),
+        0x7 => Effect::Tremolo(param),
+        0x8 => Effect::SetPanning(param),
+        0x9 => Effect::SampleOffset(param),
+        0xA => Effect::VolumeSlide(param),
+        0xB => Effect::PositionJump(param),
+        0xC => Effect::SetVolume(param.min(64)),
+        // The row is stored in decimal, as if it were hex
+        0xD => Effect::PatternBreak(x * 10 + y),
+        0xE => match x {
+            0x1 => Effect::FinePortamentoUp(y),
+            0x2 => Effect::FinePortamentoDown(y),
+            0x4 => Effect::SetVibratoWaveform(y),
+            0x5 => Effect::SetFinetune(y),
+            0x6 => Effect::PatternLoop(y),
+            0x7 => Effect::SetTremoloWaveform(y),
+            0x8 => Effect::SetPanning(y * 17),
+            0x9 => Effect::Retrigger(y),
+            0xA => Effect::FineVolumeSlideUp(y),
+            0xB => Effect::FineVolumeSlideDown(y),
+            0xC => Effect::NoteCut(y),
+            0xD => Effect::NoteDelay(y),
+            0xE => Effect::PatternDelay(y),
+            _ => Effect::None,
+        },
+        0xF if param == 0 => Effect::None,
+        0xF if param < 0x20 => Effect::SetSpeed(param),
+        0xF => Effect::SetTempo(param),
+        _ => Effect::None,
+    }
+}
@@ -0,0 +1,260 @@
+// Loads Scream Tracker 3 (S3M) files, which have up to 32 channels and samples without instruments
+
+use super::{
+    module::{
+        downmix_split_stereo, pan_from_byte, read_name, samples_i16, samples_i8, Cell, ChannelSettings, Effect,
+        Instrument, Loop, Module, Note, Order, Pattern, ReadLe, Sample, VolumeCommand,
+    },
+    Error, Format,
+};
+
+const ROWS: usize = 64;
+
+// The channel settings value for a channel which isn't used
+const UNUSED_CHANNEL: u8 = 0xFF;
+
+// Returns whether a file looks like an S3M file
+pub(super) fn detect(file: &[u8]) -> bool {
+    file.bytes_at(0x2C, 4) == Some(b"SCRM")
+}
+
+pub(super) fn load(file: &[u8]) -> Result<Module, Error> {
+    if !detect(file) || file.len() < 0x60 {
+        return Err(Error::InvalidFile)
+    }
+    let order_count = usize::from(file.u16_at(0x20).ok_or(Error::InvalidFile)?);
+    let sample_count = usize::from(file.u16_at(0x22).ok_or(Error::InvalidFile)?);
+    let pattern_count = usize::from(file.u16_at(0x24).ok_or(Error::InvalidFile)?);
+    let flags = file.u16_at(0x26).ok_or(Error::InvalidFile)?;
+    let tracker_version = file.u16_at(0x28).ok_or(Error::InvalidFile)?;
+    let unsigned_samples = file.u16_at(0x2A) == Some(2);
+    let stereo = file[0x33] & 0x80 != 0;
+
+    // After the header comes the order list, then pointers to each sample and pattern, stored divided by 16
+    let orders_start = 0x60;
+    let samples_start = orders_start + order_count;
+    let patterns_start = samples_start + sample_count * 2;
+    let pannings_start = patterns_start + pattern_count * 2;
+    let pointer = |table: usize, index: usize| {
+        file.u16_at(table + index * 2).map(|pointer| usize::from(pointer) * 16).ok_or(Error::MalformedData)
+    };
+
+    let orders = file
+        .bytes_at(orders_start, order_count)
+        .ok_or(Error::MalformedData)?
+        .iter()
+        .map(|&order| match order {
+            0xFF => Order::End,
+            0xFE => Order::Skip,
+            pattern => Order::Pattern(usize::from(pattern)),
+        })
+        .collect();
+
+    // Only the channels which are used are kept. Their settings put the first 8 on the left and the next 8 on
+    // the right, unless there's a panning table after the pointers.
+    let has_pannings = file[0x35] == 0xFC;
+    let mut channel_map = [None; 32];
+    let mut channels = Vec::new();
+    for (index, &setting) in file[0x40..0x60].iter().enumerate() {
+        if setting == UNUSED_CHANNEL || setting & 0x7F >= 16 {
+            continue
+        }
+        let mut pan = if !stereo {
+            128
+        } else if setting & 0x7F < 8 {
+            pan_from_byte(0x33)
+        } else {
+            pan_from_byte(0xCC)
+        };
+        match file.u8_at(pannings_start + index) {
+            Some(panning) if has_pannings && panning & 0x20 != 0 => pan = pan_from_byte((panning & 0x0F) * 17),
+            _ => (),
+        }
+        channel_map[index] = Some(channels.len());
+        channels.push(ChannelSettings { pan, volume: 64, muted: setting & 0x80 != 0 });
+    }
+
+    let samples = (0..sample_count)
+        .map(|index| read_sample(file, pointer(samples_start, index)?, unsigned_samples))
+        .collect::<Result<Vec<_>, _>>()?;
+    let patterns = (0..pattern_count)
+        .map(|index| read_pattern(file, pointer(patterns_start, index)?, &channel_map, channels.len()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Module {
+        format: Format::S3m,
+        title: read_name(&file[0..28]),
+        channels,
+        orders,
+        restart: 0,
+        patterns,
+        instruments: (1..=sample_count as u16).map(Instrument::for_sample).collect(),
+        samples,
+        speed: Some(file[0x31]).filter(|&speed| speed != 0 && speed != 0xFF).unwrap_or(6),
+        tempo: Some(file[0x32]).filter(|&tempo| tempo >= 0x20).unwrap_or(125),
+        global_volume: file[0x30].min(64) * 2,
+        mix_volume: 1.0,
+        linear_slides: false,
+        fast_volume_slides: flags & 0x40 != 0 || tracker_version == 0x1300,
+    })
+}
+
+fn read_sample(file: &[u8], offset: usize, unsigned: bool) -> Result<Sample, Error> {
+    let header = file.bytes_at(offset, 0x50).ok_or(Error::MalformedData)?;
+
+    // Anything other than a PCM sample (such as an AdLib instrument) is left silent
+    let data = if header[0] == 1 {
+        let data_offset = ((usize::from(header[0x0D]) << 16) | usize::from(header.u16_at(0x0E).unwrap_or(0))) * 16;
+        let length = header.u32_at(0x10).unwrap_or(0) as usize;
+        let flags = header[0x1F];
+        let (stereo, is_16_bit) = (flags & 2 != 0, flags & 4 != 0);
+        let bytes = length * if is_16_bit { 2 } else { 1 } * if stereo { 2 } else { 1 };
+        let raw = file.get(data_offset..).map(|data| &data[..bytes.min(data.len())]).unwrap_or(&[]);
+
+        let mut data = match (is_16_bit, unsigned) {
+            (false, false) => samples_i8(raw),
+            (false, true) => samples_i8(&raw.iter().map(|b| b ^ 0x80).collect::<Vec<_>>()),
+            (true, false) => samples_i16(raw),
+            (true, true) => samples_i16(&raw.chunks_exact(2).flat_map(|b| [b[0], b[1] ^ 0x80]).collect::<Vec<_>>()),
+        };
+        if stereo {
+            data = downmix_split_stereo(data);
+        }
+        data
+    } else {
+        Vec::new()
+    };
+
+    let looped = if header[0x1F] & 1 != 0 {
+        Loop::new(
+            header.u32_at(0x14).unwrap_or(0) as usize,
+            header.u32_at(0x18).unwrap_or(0) as usize,
+            false,
+            data.len(),
+        )
+    } else {
+        None
+    };
+    Ok(Sample {
+        looped,
+        sustain: None,
+        rate: f64::from(Some(header.u32_at(0x20).unwrap_or(0)).filter(|&rate| rate != 0).unwrap_or(8363)),
+        finetune: 0,
+        volume: header[0x1C].min(64),
+        global_volume: 64,
+        pan: None,
+        vibrato: Default::default(),
+        data,
+    })
+}
+
+// Reads a pattern, which is packed so that empty cells take up no space. Each cell starts with a byte which says
+// which channel it's in and which parts of the cell follow, and a zero byte ends the row.
+fn read_pattern(
+    file: &[u8],
+    offset: usize,
+    channel_map: &[Option<usize>; 32],
+    channels: usize,
+) -> Result<Pattern, Error> {
+    let mut pattern = Pattern::new(ROWS, channels);
+    if offset == 0 {
+        return Ok(pattern)
+    }
+    let length = usize::from(file.u16_at(offset).ok_or(Error::MalformedData)?);
+    let mut data = file.get((offset + 2)..).map(|data| &data[..length.min(data.len())]).unwrap_or(&[]).iter();
+    let mut next = || data.next().copied();
+
+    let mut row = 0;
+    while row < ROWS {
+        let what = match next() {
+            Some(0) => {
+                row += 1;
+                continue
+            },
+            Some(what) => what,
+            None => break,
+        };
+        let mut cell = Cell::default();
+        if what & 0x20 != 0 {
+            cell.note = match next().unwrap_or(0xFF) {
+                0xFF => Note::None,
+                0xFE => Note::Cut,
+                note if note & 0x0F < 12 => Note::On(((note >> 4) * 12 + (note & 0x0F)).min(119)),
+                _ => Note::None,
+            };
+            cell.instrument = next().unwrap_or(0);
+        }
+        if what & 0x40 != 0 {
+            let volume = next().unwrap_or(0xFF);
+            if volume <= 64 {
+                cell.volume = VolumeCommand::SetVolume(volume);
+            }
+        }
+        if what & 0x80 != 0 {
+            let command = next().unwrap_or(0);
+            let param = next().unwrap_or(0);
+            cell.effect = convert_effect(command, param, Format::S3m);
+        }
+        if let Some(channel) = channel_map[usize::from(what & 0x1F)] {
+            pattern.cells[row * channels + channel] = cell;
+        }
+    }
+    Ok(pattern)
+}
+
+// Converts one of the lettered effects which S3M and IT files share, where A is 1
+pub(super) fn convert_effect(command: u8, param: u8, format: Format) -> Effect {
+    let (x, y) = (param >> 4, param & 0x0F);
+    let is_it = format == Format::It;
+    match command {
+        1 if param == 0 => Effect::None,
+        1 => Effect::SetSpeed(param),
+        2 => Effect::PositionJump(param),
+        // S3M stores the row in decimal, as if it were hex
+        3 if is_it => Effect::PatternBreak(param),
+        3 => Effect::PatternBreak(x * 10 + y),
+        4 => Effect::VolumeSlide(param),
+        5 => Effect::PortamentoDown(param),
+        6 => Effect::PortamentoUp(param),
+        7 => Effect::TonePortamento(param),
+        8 => Effect::Vibrato(param),
+        9 => Effect::Tremor(param),
+        10 => Effect::Arpeggio(param),
+        11 => Effect::VibratoVolumeSlide(param),
+        12 => Effect::TonePortamentoVolumeSlide(param),
+        13 => Effect::SetChannelVolume(param.min(64)),
+        14 => Effect::ChannelVolumeSlide(param),
+        15 => Effect::SampleOffset(param),
+        16 => Effect::PanningSlide(param),
+        17 => Effect::Retrigger(param),
+        18 => Effect::Tremolo(param),
+        19 => match x {
+            0x2 => Effect::SetFinetune(y),
+            0x3 => Effect::SetVibratoWaveform(y),
+            0x4 => Effect::SetTremoloWaveform(y),
+            0x5 => Effect::SetPanbrelloWaveform(y),
+            0x6 => Effect::FinePatternDelay(y),
+            0x7 => Effect::InstrumentControl(y),
+            0x8 => Effect::SetPanning(y * 17),
+            0xA if is_it => Effect::HighSampleOffset(y),
+            0xB => Effect::PatternLoop(y),
+            0xC => Effect::NoteCut(y),
+            0xD => Effect::NoteDelay(y),
+            0xE => Effect::PatternDelay(y),
+            _ => Effect::None,
+        },
+        // Only IT can slide the tempo
+        20 if param < 0x20 && !is_it => Effect::None,
+        20 => Effect::SetTempo(param),
+        21 => Effect::FineVibrato(param),
+        // S3M's global volume goes up to 64, and IT's goes up to 128
+        22 if is_it => Effect::SetGlobalVolume(param.min(128)),
+        22 => Effect::SetGlobalVolume(param.min(64) * 2),
+        23 => Effect::GlobalVolumeSlide(param),
+        // S3M's panning goes up to 0x80
+        24 if is_it => Effect::SetPanning(param),
+        24 if param <= 0x80 => Effect::SetPanning((u16::from(param) * 2).min(255) as u8),
+        25 => Effect::Panbrello(param),
+        _ => Effect::None,
+    }
+}
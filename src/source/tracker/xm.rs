@@ -0,0 +1,274 @@
+// Loads FastTracker 2 (XM) files, which have instruments with envelopes, and a volume column
+
+use super::{
+    module::{
+        pan_from_byte, read_name, AutoVibrato, Cell, ChannelSettings, Effect, Envelope, Instrument, Loop, Module,
+        NewNoteAction, Note, Order, Pattern, ReadLe, Sample, VolumeCommand,
+    },
+    protracker, Error, Format,
+};
+
+const SIGNATURE: &[u8] = b"Extended Module: ";
+
+// The rate a sample with no relative note or finetune plays at for `BASE_NOTE`
+const BASE_RATE: f64 = 8363.0;
+
+// How many notes an instrument's keymap covers
+const KEYMAP_NOTES: usize = 96;
+
+// The highest note, with the one after it meaning key off
+const KEY_OFF: u8 = 97;
+
+// Returns whether a file looks like an XM file
+pub(super) fn detect(file: &[u8]) -> bool {
+    file.starts_with(SIGNATURE)
+}
+
+pub(super) fn load(file: &[u8]) -> Result<Module, Error> {
+    if !detect(file) || file.len() < 80 {
+        return Err(Error::InvalidFile)
+    }
+    let header_len = file.u32_at(60).ok_or(Error::InvalidFile)? as usize;
+    let song_length = usize::from(file.u16_at(64).ok_or(Error::InvalidFile)?).min(256);
+    let restart = usize::from(file.u16_at(66).ok_or(Error::InvalidFile)?);
+    let channel_count = usize::from(file.u16_at(68).ok_or(Error::InvalidFile)?);
+    let pattern_count = usize::from(file.u16_at(70).ok_or(Error::InvalidFile)?);
+    let instrument_count = usize::from(file.u16_at(72).ok_or(Error::InvalidFile)?);
+    let flags = file.u16_at(74).ok_or(Error::InvalidFile)?;
+    let speed = file.u16_at(76).ok_or(Error::InvalidFile)?;
+    let tempo = file.u16_at(78).ok_or(Error::InvalidFile)?;
+    if channel_count == 0 || channel_count > 64 {
+        return Err(Error::InvalidFile)
+    }
+    let orders = file
+        .bytes_at(80, song_length)
+        .ok_or(Error::MalformedData)?
+        .iter()
+        .map(|&pattern| Order::Pattern(usize::from(pattern)))
+        .collect();
+
+    // Patterns come straight after the header, then instruments straight after them
+    let mut offset = 60 + header_len;
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for _ in 0..pattern_count {
+        let (pattern, len) = read_pattern(file, offset, channel_count)?;
+        patterns.push(pattern);
+        offset += len;
+    }
+
+    let mut instruments = Vec::with_capacity(instrument_count);
+    let mut samples = Vec::new();
+    for _ in 0..instrument_count {
+        let (instrument, len) = read_instrument(file, offset, &mut samples)?;
+        instruments.push(instrument);
+        offset += len;
+    }
+
+    Ok(Module {
+        format: Format::Xm,
+        title: read_name(&file[17..37]),
+        channels: vec![ChannelSettings { pan: 128, volume: 64, muted: false }; channel_count],
+        orders,
+        restart: if restart < song_length { restart } else { 0 },
+        patterns,
+        instruments,
+        samples,
+        speed: Some(speed).filter(|&speed| speed != 0).unwrap_or(6).min(31) as u8,
+        tempo: Some(tempo).filter(|&tempo| tempo >= 32).unwrap_or(125).min(255) as u8,
+        global_volume: 128,
+        mix_volume: 1.0,
+        linear_slides: flags & 1 != 0,
+        fast_volume_slides: false,
+    })
+}
+
+// Reads a pattern, returning it along with the number of bytes it takes up
+fn read_pattern(file: &[u8], offset: usize, channels: usize) -> Result<(Pattern, usize), Error> {
+    let header_len = file.u32_at(offset).ok_or(Error::MalformedData)? as usize;
+    let rows = usize::from(file.u16_at(offset + 5).ok_or(Error::MalformedData)?);
+    let data_len = usize::from(file.u16_at(offset + 7).ok_or(Error::MalformedData)?);
+    let data = file.bytes_at(offset + header_len, data_len).ok_or(Error::MalformedData)?;
+
+    // An empty pattern has no data at all. Otherwise, each cell starts with a byte which either says which parts
+    // of the cell follow (if the top bit is set), or is the note, with everything else following.
+    let mut pattern = Pattern::new(rows.clamp(1, 256), channels);
+    let mut data = data.iter().copied();
+    for cell in pattern.cells.iter_mut().take(if data_len == 0 { 0 } else { rows * channels }) {
+        let first = match data.next() {
+            Some(first) => first,
+            None => break,
+        };
+        let (flags, note) = if first & 0x80 != 0 { (first, None) } else { (0x1E, Some(first)) };
+        let mut next = |bit: u8| if flags & bit != 0 { data.next().unwrap_or(0) } else { 0 };
+        let note = note.unwrap_or_else(|| next(0x01));
+        let instrument = next(0x02);
+        let volume = next(0x04);
+        let effect = next(0x08);
+        let param = next(0x10);
+
+        *cell = Cell {
+            note: match note {
+                0 => Note::None,
+                KEY_OFF => Note::Off,
+                note if note < KEY_OFF => Note::On(note - 1),
+                _ => Note::None,
+            },
+            instrument,
+            volume: convert_volume(volume),
+            effect: convert_effect(effect, param),
+        };
+    }
+    Ok((pattern, header_len + data_len))
+}
+
+// Reads an instrument, adding its samples to the list and returning it along with the number of bytes it and its
+// samples take up
+fn read_instrument(file: &[u8], offset: usize, samples: &mut Vec<Sample>) -> Result<(Instrument, usize), Error> {
+    let header_len = file.u32_at(offset).ok_or(Error::MalformedData)? as usize;
+    let sample_count = usize::from(file.u16_at(offset + 27).ok_or(Error::MalformedData)?);
+    let mut instrument = Instrument::for_sample(0);
+    if sample_count == 0 {
+        return Ok((instrument, header_len))
+    }
+    let header = file.bytes_at(offset, 241).ok_or(Error::MalformedData)?;
+    let sample_header_len = header.u32_at(29).unwrap_or(40) as usize;
+
+    // Keymap entries are relative to this instrument's first sample, and the module's sample numbers start at 1
+    let first_sample = samples.len() as u16 + 1;
+    for (entry, &sample) in instrument.keymap.iter_mut().zip(&header[33..(33 + KEYMAP_NOTES)]) {
+        entry.1 = if usize::from(sample) < sample_count { first_sample + u16::from(sample) } else { 0 };
+    }
+    instrument.volume_envelope = read_envelope(&header[129..177], &header[225..235], 0, 0);
+    instrument.panning_envelope = read_envelope(&header[177..225], &header[225..235], 1, 32);
+    // FastTracker 2 fades from 32768, rather than 65536
+    instrument.fadeout = u32::from(header.u16_at(239).unwrap_or(0)) * 2;
+    instrument.new_note_action = NewNoteAction::Cut;
+    let vibrato = AutoVibrato {
+        // FastTracker 2 numbers its waveforms differently to the vibrato effect
+        waveform: match header[235] {
+            1 => 2,
+            2 => 1,
+            3 => 4,
+            _ => 0,
+        },
+        sweep: header[236],
+        depth: header[237],
+        speed: header[238],
+    };
+
+    // The sample headers all come first, then the data for each sample
+    let headers_start = offset + header_len;
+    let mut data_start = headers_start + sample_count * sample_header_len;
+    for index in 0..sample_count {
+        let header = file.bytes_at(headers_start + index * sample_header_len, 40).ok_or(Error::MalformedData)?;
+        let byte_len = header.u32_at(0).unwrap_or(0) as usize;
+        let sample_type = header[14];
+        let is_16_bit = sample_type & 0x10 != 0;
+        let raw = file.get(data_start..).map(|data| &data[..byte_len.min(data.len())]).unwrap_or(&[]);
+        data_start += byte_len;
+
+        // Samples are stored as the difference between each one and the last
+        let data: Vec<f32> = if is_16_bit {
+            let mut last = 0i16;
+            raw.chunks_exact(2)
+                .map(|b| {
+                    last = last.wrapping_add(i16::from_le_bytes([b[0], b[1]]));
+                    f32::from(last) / 32768.0
+                })
+                .collect()
+        } else {
+            let mut last = 0i8;
+            raw.iter()
+                .map(|&b| {
+                    last = last.wrapping_add(b as i8);
+                    f32::from(last) / 128.0
+                })
+                .collect()
+        };
+
+        let bytes_per_sample = if is_16_bit { 2 } else { 1 };
+        let loop_start = header.u32_at(4).unwrap_or(0) as usize / bytes_per_sample;
+        let loop_len = header.u32_at(8).unwrap_or(0) as usize / bytes_per_sample;
+        let looped = match sample_type & 3 {
+            1 => Loop::new(loop_start, loop_start + loop_len, false, data.len()),
+            2 => Loop::new(loop_start, loop_start + loop_len, true, data.len()),
+            _ => None,
+        };
+
+        // The relative note is in semitones, and the finetune is in 128ths of one
+        let pitch = f64::from(header[16] as i8) + f64::from(header[13] as i8) / 128.0;
+        samples.push(Sample {
+            looped,
+            sustain: None,
+            rate: BASE_RATE * 2f64.powf(pitch / 12.0),
+            finetune: header[13] as i8,
+            volume: header[12].min(64),
+            global_volume: 64,
+            pan: Some(pan_from_byte(header[15])),
+            vibrato,
+            data,
+        });
+    }
+    Ok((instrument, data_start - offset))
+}
+
+// Reads an envelope from its points, and the settings for both envelopes (the point counts, sustain and loop points,
+// then the flags). `which` is 0 for the volume envelope and 1 for the panning envelope, and `centre` is subtracted
+// from each point's value.
+fn read_envelope(points: &[u8], settings: &[u8], which: usize, centre: i16) -> Envelope {
+    let count = usize::from(settings[which]).min(12);
+    let (sustain, loop_start, loop_end) = (3 * which + 2, 3 * which + 3, 3 * which + 4);
+    let flags = settings[8 + which];
+    let points: Vec<(u16, i8)> = points
+        .chunks_exact(4)
+        .take(count)
+        .map(|point| {
+            let value = i16::from_le_bytes([point[2], point[3]]).clamp(0, 64) - centre;
+            (u16::from_le_bytes([point[0], point[1]]), value as i8)
+        })
+        .collect();
+    let point = |index: usize| Some(usize::from(settings[index])).filter(|&point| point < points.len());
+    Envelope {
+        enabled: flags & 1 != 0 && !points.is_empty(),
+        sustain: if flags & 2 != 0 { point(sustain).map(|point| (point, point)) } else { None },
+        looped: if flags & 4 != 0 { point(loop_start).zip(point(loop_end)) } else { None },
+        points,
+    }
+}
+
+fn convert_volume(volume: u8) -> VolumeCommand {
+    let x = volume & 0x0F;
+    match volume >> 4 {
+        0x1..=0x4 => VolumeCommand::SetVolume(volume - 0x10),
+        0x5 if volume == 0x50 => VolumeCommand::SetVolume(64),
+        0x6 => VolumeCommand::SlideDown(x),
+        0x7 => VolumeCommand::SlideUp(x),
+        0x8 => VolumeCommand::FineSlideDown(x),
+        0x9 => VolumeCommand::FineSlideUp(x),
+        0xA => VolumeCommand::VibratoSpeed(x),
+        0xB => VolumeCommand::VibratoDepth(x),
+        0xC => VolumeCommand::SetPanning(x << 4),
+        0xD => VolumeCommand::PanningSlideLeft(x),
+        0xE => VolumeCommand::PanningSlideRight(x),
+        0xF => VolumeCommand::TonePortamento(x << 4),
+        _ => VolumeCommand::None,
+    }
+}
+
+fn convert_effect(effect: u8, param: u8) -> Effect {
+    let (x, y) = (param >> 4, param & 0x0F);
+    match effect {
+        0x0..=0xF => protracker::convert_effect(effect, param),
+        // The letters after F, where G is 16. XM's global volume only goes up to 64.
+        16 => Effect::SetGlobalVolume(param.min(64) * 2),
+        17 => Effect::GlobalVolumeSlide(param),
+        20 => Effect::KeyOff(param),
+        21 => Effect::SetEnvelopePosition(param),
+        25 => Effect::PanningSlide(param),
+        27 => Effect::Retrigger(param),
+        29 => Effect::Tremor(param),
+        33 if x == 1 => Effect::ExtraFinePortamentoUp(y),
+        33 if x == 2 => Effect::ExtraFinePortamentoDown(y),
+        _ => Effect::None,
+    }
+}
@@ -0,0 +1,534 @@
+use super::{xiph, Length, Seekable, Source};
+use lewton::{
+    audio::{read_audio_packet_generic, AudioReadError, PreviousWindowRight},
+    header::{read_header_comment, read_header_ident, read_header_setup, HeaderReadError, IdentHeader, SetupHeader},
+    samples::InterleavedSamples,
+    OggReadError,
+};
+use ogg::{Packet, PacketReader};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+/// A Source which decodes an Ogg Vorbis file as it's played.
+/// By default it decodes from a file in memory, but it can also stream from any reader (see `from_reader`).
+/// Only the first logical stream in the file is played.
+pub struct VorbisPlayer<R: Read + Seek = Cursor<Vec<u8>>> {
+    packets: PacketReader<R>,
+    stream_serial: u32,
+    ident_header: IdentHeader,
+    setup_header: SetupHeader,
+    comments: Vec<(String, String)>,
+    previous_window: PreviousWindowRight,
+
+    // Whether each mode in the setup header uses long blocks
+    mode_block_flags: Vec<bool>,
+
+    // The total number of frames in the stream, taken from the granule position of its last page
+    length: Option<u64>,
+
+    // Samples which have been decoded but not written yet, starting at `decoded[decoded_offset]`
+    decoded: Vec<f32>,
+    decoded_offset: usize,
+
+    // The index of the next sample to be written
+    position: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// This does not appear to be an Ogg Vorbis file
+    InvalidFile,
+
+    /// The audio data in this file is malformed
+    MalformedData,
+
+    /// The underlying reader returned an error while the file was being read
+    Io(io::ErrorKind),
+}
+
+impl From<OggReadError> for Error {
+    fn from(error: OggReadError) -> Self {
+        match error {
+            OggReadError::ReadError(e) => Error::Io(e.kind()),
+            OggReadError::NoCapturePatternFound | OggReadError::InvalidStreamStructVer(_) => Error::InvalidFile,
+            _ => Error::MalformedData,
+        }
+    }
+}
+
+impl From<HeaderReadError> for Error {
+    fn from(error: HeaderReadError) -> Self {
+        match error {
+            HeaderReadError::NotVorbisHeader | HeaderReadError::HeaderBadType(_) | HeaderReadError::HeaderIsAudio => {
+                Error::InvalidFile
+            },
+            _ => Error::MalformedData,
+        }
+    }
+}
+
+impl VorbisPlayer {
+    pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        Self::from_reader(Cursor::new(file.into()))
+    }
+}
+
+impl<R: Read + Seek> VorbisPlayer<R> {
+    /// Creates a VorbisPlayer which streams its file from a reader, only reading as much as it needs at a time.
+    pub fn from_reader(mut reader: R) -> Result<Self, Error> {
+        let length = xiph::read_ogg_length(&mut reader).map_err(|e| Error::Io(e.kind()))?;
+        let mut packets = PacketReader::new(reader);
+
+        // The first packet in the file starts the stream we'll play, and the stream starts with three headers
+        let ident_packet = packets.read_packet_expected()?;
+        let stream_serial = ident_packet.stream_serial();
+        let ident_header = read_header_ident(&ident_packet.data)?;
+        let comment_header = read_header_comment(&xiph::read_header_packet(&mut packets, stream_serial)?.data)?;
+        let setup_packet = xiph::read_header_packet(&mut packets, stream_serial)?;
+        let blocksizes = (ident_header.blocksize_0, ident_header.blocksize_1);
+        let setup_header = read_header_setup(&setup_packet.data, ident_header.audio_channels, blocksizes)?;
+        let mode_block_flags = read_mode_block_flags(&setup_packet.data, ident_header.audio_channels);
+
+        Ok(Self {
+            packets,
+            stream_serial,
+            ident_header,
+            setup_header,
+            comments: comment_header.comment_list,
+            previous_window: PreviousWindowRight::new(),
+            mode_block_flags,
+            length: length.filter(|&(serial, _)| serial == stream_serial).map(|(_, length)| length),
+            decoded: Vec::new(),
+            decoded_offset: 0,
+            position: 0,
+        })
+    }
+
+    /// Returns the total number of frames in this file, if it could be found.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Returns the comments stored in this file as (field, value) pairs, such as `("TITLE", "...")`.
+    /// Field names aren't case-sensitive, and the same field may appear more than once.
+    pub fn comments(&self) -> &[(String, String)] {
+        &self.comments
+    }
+
+    /// Returns the value of the first comment with the given field name, ignoring case.
+    pub fn comment(&self, field: &str) -> Option<&str> {
+        self.comments.iter().find(|(name, _)| name.eq_ignore_ascii_case(field)).map(|(_, value)| value.as_str())
+    }
+
+    /// Consumes this VorbisPlayer and returns the reader it was streaming from.
+    pub fn into_inner(self) -> R {
+        self.packets.into_inner()
+    }
+
+    // Reads the next packet in our stream, returning None at the end of the stream or if it can't be read
+    fn read_packet(&mut self) -> Option<Packet> {
+        loop {
+            match self.packets.read_packet() {
+                Ok(Some(packet)) if packet.stream_serial() == self.stream_serial => return Some(packet),
+                Ok(Some(_)) => (),
+                Ok(None) | Err(_) => return None,
+            }
+        }
+    }
+
+    // Decodes a packet into interleaved samples. The first packet after a seek only primes the decoder,
+    // so it decodes to nothing.
+    fn decode_packet(&mut self, packet: &Packet) -> Result<Vec<f32>, AudioReadError> {
+        let samples: InterleavedSamples<f32> =
+            read_audio_packet_generic(&self.ident_header, &self.setup_header, &packet.data, &mut self.previous_window)?;
+        Ok(samples.samples)
+    }
+
+    // Decodes the next packet into `decoded`, returning false once there's nothing left to decode
+    fn decode_next(&mut self) -> bool {
+        let channels = self.channel_count();
+        loop {
+            let packet = match self.read_packet() {
+                Some(packet) => packet,
+                None => return false,
+            };
+            let mut samples = match self.decode_packet(&packet) {
+                Ok(samples) => samples,
+                // We'll come across the header packets again after seeking back to the start of the file
+                Err(AudioReadError::AudioIsHeader) => continue,
+                Err(_) => return false,
+            };
+
+            // The last packet may be padded, in which case the granule position of its page says where it ends
+            if packet.last_in_stream() {
+                let end = (packet.absgp_page() * channels as u64).saturating_sub(self.position);
+                samples.truncate(end as usize);
+            }
+            self.decoded = samples;
+            self.decoded_offset = 0;
+            return true
+        }
+    }
+
+    // Decodes and throws away samples until the next one to be written is the start of `frame`
+    fn skip_to(&mut self, frame: u64) {
+        let target = frame * self.channel_count() as u64;
+        while self.position < target {
+            if self.decoded_offset == self.decoded.len() && !self.decode_next() {
+                break
+            }
+            let available = self.decoded.len() - self.decoded_offset;
+            let count = (target - self.position).min(available as u64);
+            self.decoded_offset += count as usize;
+            self.position += count;
+        }
+    }
+
+    // Seeks to the end of a page at or before `frame`, which is a point where we know exactly which frame comes
+    // next. Returns false if the reader couldn't seek, or we can't tell where packets end.
+    fn seek_before(&mut self, frame: u64) -> bool {
+        if self.mode_block_flags.is_empty() {
+            return false
+        }
+        let mut goal = frame;
+        let mut step = 1 << self.ident_header.blocksize_1;
+        loop {
+            // This lands on the first page which finishes at or after `goal`
+            match self.packets.seek_absgp(Some(self.stream_serial), goal) {
+                Ok(true) => (),
+                _ => return false,
+            }
+
+            // The first packet may be the end of one which started on an earlier page, so it can't be decoded,
+            // but every packet from there on decodes fine. Once a page is finished, the next packet's audio
+            // starts at the granule position of that page (give or take the last packet's overhang).
+            self.previous_window = PreviousWindowRight::new();
+            let page_end = loop {
+                let packet = match self.read_packet() {
+                    Some(packet) => packet,
+                    None => return false,
+                };
+                if self.decode_packet(&packet).is_err() {
+                    self.previous_window = PreviousWindowRight::new();
+                }
+                if packet.last_in_page() {
+                    break packet.absgp_page() + self.overhang(&packet.data)
+                }
+            };
+
+            if page_end <= frame {
+                self.decoded.clear();
+                self.decoded_offset = 0;
+                self.position = page_end * self.channel_count() as u64;
+                return true
+            }
+            if goal == 0 {
+                return false
+            }
+
+            // That page finished too late, so aim further back, and further still if that doesn't work either
+            goal = goal.saturating_sub(step.max(page_end.saturating_sub(goal)));
+            step *= 2;
+        }
+    }
+
+    // Returns how many frames past its granule position the decoder's output for an audio packet ends.
+    // The decoder writes out everything up to where the next packet's window starts overlapping, rather than
+    // stopping in the middle of the window like the granule position does, so a long block followed by a short one
+    // goes on for a bit longer.
+    fn overhang(&self, packet: &[u8]) -> u64 {
+        let bit = |i: usize| packet.get(i / 8).map_or(false, |byte| (byte >> (i % 8)) & 1 == 1);
+        if packet.is_empty() || bit(0) {
+            // This is a header packet, which has no audio
+            return 0
+        }
+
+        let mode_bits = (usize::BITS - (self.mode_block_flags.len() - 1).leading_zeros()) as usize;
+        let mode = (0..mode_bits).rev().fold(0, |mode, i| (mode << 1) | usize::from(bit(1 + i)));
+        let long_block = self.mode_block_flags.get(mode).copied().unwrap_or(false);
+
+        // Long blocks say whether the blocks either side of them are long too
+        let next_long_block = bit(1 + mode_bits + 1);
+        if long_block && !next_long_block {
+            ((1 << self.ident_header.blocksize_1) - (1 << self.ident_header.blocksize_0)) / 4
+        } else {
+            0
+        }
+    }
+
+    // Goes back to the start of the file
+    fn rewind(&mut self) {
+        self.previous_window = PreviousWindowRight::new();
+        self.decoded.clear();
+        self.decoded_offset = 0;
+        self.position = 0;
+        let _ = self.packets.seek_bytes(SeekFrom::Start(0));
+    }
+}
+
+impl<R: Read + Seek> Source for VorbisPlayer<R> {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.decoded_offset == self.decoded.len() {
+                if !self.decode_next() {
+                    break
+                }
+                continue
+            }
+
+            let samples = &self.decoded[self.decoded_offset..];
+            let count = samples.len().min(buffer.len() - written);
+            buffer[written..(written + count)].copy_from_slice(&samples[..count]);
+            self.decoded_offset += count;
+            self.position += count as u64;
+            written += count;
+        }
+        written
+    }
+
+    fn channel_count(&self) -> usize {
+        usize::from(self.ident_header.audio_channels)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.ident_header.audio_sample_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        match self.length {
+            Some(length) => Length::Exact(length),
+            None => Length::Unknown,
+        }
+    }
+
+    fn remaining_frames(&self) -> Length {
+        match self.length {
+            Some(length) => Length::Exact(length.saturating_sub(self.position / self.channel_count() as u64)),
+            None => Length::Unknown,
+        }
+    }
+}
+
+impl<R: Read + Seek> Seekable for VorbisPlayer<R> {
+    fn seek_frame(&mut self, frame: u64) {
+        let frame = self.length.map_or(frame, |length| frame.min(length));
+        if !self.seek_before(frame) {
+            self.rewind();
+        }
+        self.skip_to(frame);
+    }
+}
+
+// Reads whether each mode uses long blocks out of a setup header, returning nothing if they can't be found.
+// lewton doesn't make these public, and they're the last thing in the header, so this reads through everything
+// before them the same way lewton does, skipping over whatever isn't needed.
+fn read_mode_block_flags(setup: &[u8], channels: u8) -> Vec<bool> {
+    // The header starts with its packet type and "vorbis"
+    let mut bits = BitReader { data: setup, position: 7 * 8 };
+    read_modes(&mut bits, channels).unwrap_or_default()
+}
+
+fn read_modes(bits: &mut BitReader, channels: u8) -> Option<Vec<bool>> {
+    let codebook_count = bits.read(8)? + 1;
+    for _ in 0..codebook_count {
+        skip_codebook(bits)?;
+    }
+
+    // Time domain transforms are just placeholders
+    let time_count = bits.read(6)? + 1;
+    bits.skip(time_count * 16)?;
+
+    let floor_count = bits.read(6)? + 1;
+    for _ in 0..floor_count {
+        match bits.read(16)? {
+            0 => skip_floor_0(bits)?,
+            1 => skip_floor_1(bits)?,
+            _ => return None,
+        }
+    }
+
+    let residue_count = bits.read(6)? + 1;
+    for _ in 0..residue_count {
+        if bits.read(16)? > 2 {
+            return None
+        }
+        bits.skip(24 * 3)?;
+        let classifications = bits.read(6)? + 1;
+        bits.skip(8)?;
+
+        // Each classification has a bitmap of which passes have codebooks, and each of those has a codebook number
+        let mut books = 0;
+        for _ in 0..classifications {
+            let low_bits = bits.read(3)?;
+            let high_bits = if bits.read(1)? == 1 { bits.read(5)? } else { 0 };
+            books += ((high_bits << 3) | low_bits).count_ones() as usize;
+        }
+        bits.skip(books * 8)?;
+    }
+
+    let mapping_count = bits.read(6)? + 1;
+    let channel_bits = ilog(usize::from(channels).saturating_sub(1));
+    for _ in 0..mapping_count {
+        if bits.read(16)? != 0 {
+            return None
+        }
+        let submaps = if bits.read(1)? == 1 { bits.read(4)? + 1 } else { 1 };
+        if bits.read(1)? == 1 {
+            let coupling_steps = bits.read(8)? + 1;
+            bits.skip(coupling_steps * channel_bits * 2)?;
+        }
+        bits.skip(2)?;
+        if submaps > 1 {
+            bits.skip(usize::from(channels) * 4)?;
+        }
+        bits.skip(submaps * 24)?;
+    }
+
+    // Each mode is a block flag, a window type and transform type, and a mapping number
+    let mode_count = bits.read(6)? + 1;
+    let mut modes = Vec::with_capacity(mode_count);
+    for _ in 0..mode_count {
+        modes.push(bits.read(1)? == 1);
+        bits.skip(16 + 16 + 8)?;
+    }
+
+    // The header finishes with a framing bit
+    match bits.read(1)? {
+        1 => Some(modes),
+        _ => None,
+    }
+}
+
+fn skip_codebook(bits: &mut BitReader) -> Option<()> {
+    if bits.read(24)? != 0x564342 {
+        return None
+    }
+    let dimensions = bits.read(16)?;
+    let entries = bits.read(24)?;
+
+    // The length of each entry's codeword, which are either listed in order of length or given one by one
+    if bits.read(1)? == 1 {
+        let mut entry = 0;
+        bits.skip(5)?;
+        while entry < entries {
+            entry += bits.read(ilog(entries - entry))?;
+        }
+        if entry > entries {
+            return None
+        }
+    } else {
+        let sparse = bits.read(1)? == 1;
+        for _ in 0..entries {
+            if !sparse || bits.read(1)? == 1 {
+                bits.skip(5)?;
+            }
+        }
+    }
+
+    // The vector lookup table, which has a minimum and delta value followed by a list of multipliers
+    let values = match bits.read(4)? {
+        0 => return Some(()),
+        1 => lookup1_values(entries, dimensions)?,
+        2 => entries.checked_mul(dimensions)?,
+        _ => return None,
+    };
+    bits.skip(32 + 32)?;
+    let value_bits = bits.read(4)? + 1;
+    bits.skip(1)?;
+    bits.skip(values.checked_mul(value_bits)?)
+}
+
+fn skip_floor_0(bits: &mut BitReader) -> Option<()> {
+    bits.skip(8 + 16 + 16 + 6 + 8)?;
+    let books = bits.read(4)? + 1;
+    bits.skip(books * 8)
+}
+
+fn skip_floor_1(bits: &mut BitReader) -> Option<()> {
+    let partitions = bits.read(5)?;
+    let mut partition_classes = Vec::with_capacity(partitions);
+    for _ in 0..partitions {
+        partition_classes.push(bits.read(4)?);
+    }
+
+    // The number of values each class has in each partition it's used by
+    let class_count = partition_classes.iter().max().map_or(0, |&class| class + 1);
+    let mut class_dimensions = Vec::with_capacity(class_count);
+    for _ in 0..class_count {
+        class_dimensions.push(bits.read(3)? + 1);
+        let subclasses = bits.read(2)?;
+        if subclasses != 0 {
+            bits.skip(8)?;
+        }
+        bits.skip((1 << subclasses) * 8)?;
+    }
+
+    bits.skip(2)?;
+    let range_bits = bits.read(4)?;
+    let values: usize = partition_classes.iter().map(|&class| class_dimensions[class]).sum();
+    bits.skip(values * range_bits)
+}
+
+// The greatest number which can be raised to the power of `dimensions` without going over `entries`
+fn lookup1_values(entries: usize, dimensions: usize) -> Option<usize> {
+    if dimensions == 0 {
+        return None
+    }
+    let fits = |values: usize| {
+        (0..dimensions).try_fold(1usize, |product, _| product.checked_mul(values)).map_or(false, |n| n <= entries)
+    };
+    let mut values = (entries as f64).powf(1.0 / dimensions as f64) as usize;
+    while fits(values + 1) {
+        values += 1;
+    }
+    while values > 0 && !fits(values) {
+        values -= 1;
+    }
+    Some(values)
+}
+
+// The number of bits needed to store `n`
+fn ilog(n: usize) -> usize {
+    (usize::BITS - n.leading_zeros()) as usize
+}
+
+// Reads numbers from a packet, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: usize) -> Option<usize> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            value |= usize::from((byte >> (self.position % 8)) & 1) << i;
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.position = self.position.checked_add(count).filter(|&end| end <= self.data.len() * 8)?;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_block_flags() {
+        let file = &include_bytes!("../../tests/data/sine_440hz_stereo.ogg")[..];
+        let mut packets = PacketReader::new(Cursor::new(file));
+        packets.read_packet_expected().unwrap();
+        packets.read_packet_expected().unwrap();
+        let setup = packets.read_packet_expected().unwrap().data;
+        assert_eq!(read_mode_block_flags(&setup, 2), [false, true]);
+
+        // Running out of header partway through means the modes can't be found
+        assert!(read_mode_block_flags(&setup[..(setup.len() - 1)], 2).is_empty());
+    }
+}
@@ -1,14 +1,26 @@
-use super::Source;
+mod adpcm;
+mod metadata;
+mod write;
+
+pub use metadata::{BroadcastExtension, CuePoint, Metadata};
+pub use write::{encode, WavWriter};
+
+use self::adpcm::{Adpcm, BlockCache};
+use super::{Length, LoopRegion, Seekable, Source};
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
 
 #[derive(Clone, Debug)]
 pub struct WavPlayer {
     file: Vec<u8>,
-    channels: usize,
-    sample_rate: usize,
-    sample_bytes: usize,
-    next_sample_offset: usize,
-    format: Format,
-    length: usize,
+    header: Header,
+
+    // The index of the next sample to be decoded from the data chunk
+    position: usize,
+    blocks: BlockCache,
+    loop_region: Option<LoopRegion>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -21,6 +33,44 @@ pub enum Error {
 
     /// The audio data in this file is encoded in a way we don't support
     UnknownFormat,
+
+    /// The underlying reader or writer returned an error while the file was being read or written
+    Io(io::ErrorKind),
+
+    /// The source being written doesn't know its sample rate, so it can't be stored in a file
+    UnknownSampleRate,
+
+    /// The source being written doesn't have the same number of channels as the file it's being written to
+    ChannelCountMismatch,
+}
+
+// The format tag for a fmt chunk with the real format stored in a GUID in its extension
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// Every format GUID we can decode ends with these bytes
+const KSDATAFORMAT_SUBTYPE_SUFFIX: [u8; 14] =
+    [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+/// Speaker position bits which make up a channel mask, as defined by WAVE_FORMAT_EXTENSIBLE.
+pub mod speaker {
+    pub const FRONT_LEFT: u32 = 0x1;
+    pub const FRONT_RIGHT: u32 = 0x2;
+    pub const FRONT_CENTER: u32 = 0x4;
+    pub const LOW_FREQUENCY: u32 = 0x8;
+    pub const BACK_LEFT: u32 = 0x10;
+    pub const BACK_RIGHT: u32 = 0x20;
+    pub const FRONT_LEFT_OF_CENTER: u32 = 0x40;
+    pub const FRONT_RIGHT_OF_CENTER: u32 = 0x80;
+    pub const BACK_CENTER: u32 = 0x100;
+    pub const SIDE_LEFT: u32 = 0x200;
+    pub const SIDE_RIGHT: u32 = 0x400;
+    pub const TOP_CENTER: u32 = 0x800;
+    pub const TOP_FRONT_LEFT: u32 = 0x1000;
+    pub const TOP_FRONT_CENTER: u32 = 0x2000;
+    pub const TOP_FRONT_RIGHT: u32 = 0x4000;
+    pub const TOP_BACK_LEFT: u32 = 0x8000;
+    pub const TOP_BACK_CENTER: u32 = 0x10000;
+    pub const TOP_BACK_RIGHT: u32 = 0x20000;
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -30,130 +80,633 @@ pub enum Format {
     I24,
     I32,
     F32,
+    F64,
+
+    /// 8-bit G.711 A-law
+    ALaw,
+
+    /// 8-bit G.711 mu-law
+    MuLaw,
+}
+
+impl Format {
+    // The number of bytes each sample takes up in a file
+    pub(super) fn sample_bytes(self) -> usize {
+        match self {
+            Format::U8 | Format::ALaw | Format::MuLaw => 1,
+            Format::I16 => 2,
+            Format::I24 => 3,
+            Format::I32 | Format::F32 => 4,
+            Format::F64 => 8,
+        }
+    }
 }
 
 impl WavPlayer {
     pub fn new(file: impl Into<Vec<u8>>) -> Result<Self, Error> {
         let mut file = file.into();
-        if file.len() < 36 || file[0..4] != [b'R', b'I', b'F', b'F'] || file[8..12] != [b'W', b'A', b'V', b'E'] {
-            return Err(Error::InvalidFile)
-        }
+        let header = read_header(&mut Cursor::new(&file))?;
 
-        let audio_format = i16::from_le_bytes([file[20], file[21]]);
-        let channels = u16::from_le_bytes([file[22], file[23]]);
-        let sample_rate = u32::from_le_bytes([file[24], file[25], file[26], file[27]]);
-        let sample_bits = u16::from_le_bytes([file[34], file[35]]);
+        // Everything up to the end of the data chunk is kept, so offsets into the file stay the same
+        file.truncate((header.data_start + header.data_len) as usize);
 
-        let mut data_start: usize = 36;
-        let data_len = loop {
-            if file.len() < data_start + 8 {
-                return Err(Error::InvalidFile)
-            }
-            let is_data_chunk = file[data_start..(data_start + 4)] == [b'd', b'a', b't', b'a'];
-            let data_len = u32::from_le_bytes([
-                file[data_start + 4],
-                file[data_start + 5],
-                file[data_start + 6],
-                file[data_start + 7],
-            ]) as usize;
-            data_start += 8;
-            if is_data_chunk {
-                break data_len
-            } else {
-                data_start += data_len;
-            }
+        Ok(Self { file, header, position: 0, blocks: BlockCache::default(), loop_region: None })
+    }
+
+    /// Returns the total number of samples in this wav file
+    pub fn length(&self) -> usize {
+        self.header.length
+    }
+
+    /// Returns the sample rate of this wav file (eg. 44100)
+    #[deprecated(note = "use Source::sample_rate instead")]
+    pub fn sample_rate(&self) -> usize {
+        self.header.sample_rate as usize
+    }
+
+    /// Returns the speaker positions of this wav file's channels, if the file specifies them.
+    /// This is a combination of the bits in `wav::speaker`, with one bit set for each channel, in channel order.
+    pub fn channel_mask(&self) -> Option<u32> {
+        self.header.channel_mask
+    }
+
+    /// Returns the forward loops stored in this wav file's sampler (`smpl`) chunk, if it has one.
+    /// To play one of them, pass it to `set_loop`, or use `with_sampler_loop` to play the first one.
+    pub fn sampler_loops(&self) -> &[LoopRegion] {
+        &self.header.sampler_loops
+    }
+
+    /// Loops the first of this wav file's sampler loops, if it has any, like instrument samples are meant to be.
+    pub fn with_sampler_loop(mut self) -> Self {
+        self.loop_region = self.header.sampler_loops.first().copied();
+        self
+    }
+
+    /// Returns the metadata stored in this wav file: its INFO fields, cue points and broadcast extension.
+    pub fn metadata(&self) -> &Metadata {
+        &self.header.metadata
+    }
+
+    /// Returns the region this WavPlayer is currently looping, if any.
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        self.loop_region
+    }
+
+    /// Sets a region to loop. Once playback reaches the end of the region it'll jump back to the start of it,
+    /// forever. Pass None to stop looping and let playback continue to the end of the file.
+    pub fn set_loop(&mut self, region: Option<LoopRegion>) {
+        self.loop_region = region;
+    }
+
+    // Decodes samples into the buffer, stopping at the given sample index in the data chunk
+    fn decode_until(&mut self, buffer: &mut [f32], end: usize) -> usize {
+        let sample_count = buffer.len().min(end.saturating_sub(self.position));
+        let buffer = &mut buffer[..sample_count];
+        let data = &self.file[(self.header.data_start as usize)..];
+
+        let samples_written = match &self.header.encoding {
+            Encoding::Pcm(format) => {
+                let data = data.get((self.position * format.sample_bytes())..).unwrap_or(&[]);
+                decode_samples(*format, self.header.byte_order, data, buffer)
+            },
+            Encoding::Adpcm(adpcm) => {
+                self.blocks.read(adpcm, self.header.channels, self.position, buffer, |offset, len, bytes| {
+                    bytes.extend_from_slice(data.get(offset..).map(|d| &d[..len.min(d.len())]).unwrap_or(&[]));
+                    true
+                })
+            },
         };
+        self.position += samples_written;
+        samples_written
+    }
+}
 
-        let expected_file_length = data_len + data_start;
-        if expected_file_length > file.len() {
-            return Err(Error::MalformedData)
-        } else {
-            file.truncate(expected_file_length);
+impl Source for WavPlayer {
+    fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
+        let length = self.header.length;
+        match self.loop_region.map(|region| region.scaled_range(self.header.channels, length)) {
+            Some((start, end)) if start < end => {
+                let mut count = 0;
+                while count < buffer.len() {
+                    if self.position >= end {
+                        self.position = start;
+                    }
+                    count += self.decode_until(&mut buffer[count..], end);
+                }
+                count
+            },
+            _ => self.decode_until(buffer, length),
         }
+    }
 
-        let format = match (audio_format, sample_bits) {
-            (1, 8) => Format::U8,
-            (1, 16) => Format::I16,
-            (1, 24) => Format::I24,
-            (1, 32) => Format::I32,
-            (3, 32) => Format::F32,
-            _ => return Err(Error::UnknownFormat),
-        };
+    fn channel_count(&self) -> usize {
+        self.header.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.header.sample_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        Length::Exact((self.header.length / self.header.channels) as u64)
+    }
+
+    fn remaining_frames(&self) -> Length {
+        if self.loop_region.is_some() {
+            return Length::Unknown
+        }
+        Length::Exact((self.header.length.saturating_sub(self.position) / self.header.channels) as u64)
+    }
+}
+
+impl Seekable for WavPlayer {
+    fn seek_frame(&mut self, frame: u64) {
+        let sample = usize::try_from(frame).unwrap_or(usize::MAX).saturating_mul(self.header.channels);
+        self.position = sample.min(self.header.length);
+    }
+}
+
+/// A wav file player which streams its audio data from a reader (such as a std::fs::File) as it's needed,
+/// rather than keeping the whole file in memory like WavPlayer does. Otherwise, it behaves exactly like WavPlayer.
+/// If the reader returns an error partway through playback, the player will end there.
+pub struct StreamingWavPlayer<R: Read + Seek> {
+    reader: R,
+    header: Header,
+    bytes: Vec<u8>,
+    blocks: BlockCache,
+
+    // The index of the next sample to be read from the data chunk
+    position: usize,
 
-        let sample_bytes = usize::from(sample_bits / 8);
+    // Whether the reader needs to be moved to `position` before reading from it again
+    needs_seek: bool,
+    loop_region: Option<LoopRegion>,
+}
 
+impl<R: Read + Seek> StreamingWavPlayer<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let header = read_header(&mut reader)?;
         Ok(Self {
-            file,
-            channels: channels.into(),
-            sample_rate: sample_rate as usize,
-            sample_bytes,
-            next_sample_offset: data_start,
-            format,
-            length: data_len / sample_bytes,
+            reader,
+            header,
+            bytes: Vec::new(),
+            blocks: BlockCache::default(),
+            position: 0,
+            needs_seek: true,
+            loop_region: None,
         })
     }
 
     /// Returns the total number of samples in this wav file
     pub fn length(&self) -> usize {
-        self.length
+        self.header.length
     }
 
-    /// Returns the sample rate of this wav file (eg. 44100)
-    pub fn sample_rate(&self) -> usize {
-        self.sample_rate
+    /// Returns the speaker positions of this wav file's channels, if the file specifies them.
+    /// This is a combination of the bits in `wav::speaker`, with one bit set for each channel, in channel order.
+    pub fn channel_mask(&self) -> Option<u32> {
+        self.header.channel_mask
+    }
+
+    /// Returns the forward loops stored in this wav file's sampler (`smpl`) chunk, if it has one.
+    /// To play one of them, pass it to `set_loop`, or use `with_sampler_loop` to play the first one.
+    pub fn sampler_loops(&self) -> &[LoopRegion] {
+        &self.header.sampler_loops
+    }
+
+    /// Loops the first of this wav file's sampler loops, if it has any, like instrument samples are meant to be.
+    pub fn with_sampler_loop(mut self) -> Self {
+        self.loop_region = self.header.sampler_loops.first().copied();
+        self
+    }
+
+    /// Returns the metadata stored in this wav file: its INFO fields, cue points and broadcast extension.
+    pub fn metadata(&self) -> &Metadata {
+        &self.header.metadata
+    }
+
+    /// Returns the region this StreamingWavPlayer is currently looping, if any.
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        self.loop_region
+    }
+
+    /// Sets a region to loop. Once playback reaches the end of the region it'll jump back to the start of it,
+    /// forever. Pass None to stop looping and let playback continue to the end of the file.
+    pub fn set_loop(&mut self, region: Option<LoopRegion>) {
+        self.loop_region = region;
+    }
+
+    /// Consumes this StreamingWavPlayer, returning the reader it was streaming from.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    // Reads and decodes samples into the buffer, stopping at the given sample index in the data chunk
+    fn decode_until(&mut self, buffer: &mut [f32], end: usize) -> usize {
+        let sample_count = buffer.len().min(end.saturating_sub(self.position));
+        if sample_count == 0 {
+            return 0
+        }
+        let buffer = &mut buffer[..sample_count];
+
+        let samples_written = match &self.header.encoding {
+            Encoding::Pcm(format) => {
+                let sample_bytes = format.sample_bytes();
+                if self.needs_seek {
+                    let offset = self.header.data_start + (self.position * sample_bytes) as u64;
+                    if self.reader.seek(SeekFrom::Start(offset)).is_err() {
+                        return 0
+                    }
+                    self.needs_seek = false;
+                }
+
+                self.bytes.resize(sample_count * sample_bytes, 0);
+                let filled = read_fully(&mut self.reader, &mut self.bytes);
+                let samples_written = decode_samples(*format, self.header.byte_order, &self.bytes[..filled], buffer);
+                if filled != samples_written * sample_bytes {
+                    // We read part of a sample, so the reader is no longer where we think it is
+                    self.needs_seek = true;
+                }
+                samples_written
+            },
+            Encoding::Adpcm(adpcm) => {
+                let (reader, data_start) = (&mut self.reader, self.header.data_start);
+                self.blocks.read(adpcm, self.header.channels, self.position, buffer, |offset, len, bytes| {
+                    bytes.resize(len, 0);
+                    if reader.seek(SeekFrom::Start(data_start + offset as u64)).is_err() {
+                        return false
+                    }
+                    let filled = read_fully(reader, bytes);
+                    bytes.truncate(filled);
+                    true
+                })
+            },
+        };
+        self.position += samples_written;
+        samples_written
     }
 }
 
-impl Source for WavPlayer {
+impl<R: Read + Seek> Source for StreamingWavPlayer<R> {
     fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
-        use std::convert::TryInto;
-
-        if let Some(i) = self.file.get(self.next_sample_offset..) {
-            let output_iter = buffer.iter_mut();
-
-            let samples_written;
-            match self.format {
-                Format::U8 => {
-                    let iter = output_iter.zip(i.iter().copied());
-                    samples_written = iter.len();
-                    iter.for_each(|(out, b)| *out = get_sample_u8(b));
-                },
-                Format::I16 => {
-                    let iter =
-                        output_iter.zip(i.chunks_exact(2).map(|x| <&[u8] as TryInto<&[u8; 2]>>::try_into(x).unwrap()));
-                    samples_written = iter.len();
-                    iter.for_each(|(out, b)| *out = get_sample_i16(b));
-                },
-                Format::I24 => {
-                    let iter =
-                        output_iter.zip(i.chunks_exact(3).map(|x| <&[u8] as TryInto<&[u8; 3]>>::try_into(x).unwrap()));
-                    samples_written = iter.len();
-                    iter.for_each(|(out, b)| *out = get_sample_i24(b));
-                },
-                Format::I32 => {
-                    let iter =
-                        output_iter.zip(i.chunks_exact(4).map(|x| <&[u8] as TryInto<&[u8; 4]>>::try_into(x).unwrap()));
-                    samples_written = iter.len();
-                    iter.for_each(|(out, b)| *out = get_sample_i32(b));
-                },
-                Format::F32 => {
-                    let iter =
-                        output_iter.zip(i.chunks_exact(4).map(|x| <&[u8] as TryInto<&[u8; 4]>>::try_into(x).unwrap()));
-                    samples_written = iter.len();
-                    iter.for_each(|(out, b)| *out = get_sample_f32(b));
-                },
+        let length = self.header.length;
+        match self.loop_region.map(|region| region.scaled_range(self.header.channels, length)) {
+            Some((start, end)) if start < end => {
+                let mut count = 0;
+                while count < buffer.len() {
+                    if self.position >= end {
+                        self.position = start;
+                        self.needs_seek = true;
+                    }
+                    let written = self.decode_until(&mut buffer[count..], end);
+                    if written == 0 {
+                        // The reader failed on us, so don't spin here forever
+                        break
+                    }
+                    count += written;
+                }
+                count
+            },
+            _ => self.decode_until(buffer, length),
+        }
+    }
+
+    fn channel_count(&self) -> usize {
+        self.header.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.header.sample_rate)
+    }
+
+    fn total_frames(&self) -> Length {
+        Length::Exact((self.header.length / self.header.channels) as u64)
+    }
+
+    fn remaining_frames(&self) -> Length {
+        if self.loop_region.is_some() {
+            return Length::Unknown
+        }
+        Length::Exact((self.header.length.saturating_sub(self.position) / self.header.channels) as u64)
+    }
+}
+
+impl<R: Read + Seek> Seekable for StreamingWavPlayer<R> {
+    fn seek_frame(&mut self, frame: u64) {
+        let sample = usize::try_from(frame).unwrap_or(usize::MAX).saturating_mul(self.header.channels);
+        self.position = sample.min(self.header.length);
+        self.needs_seek = true;
+    }
+}
+
+// Everything we need to know about a wav file before we can start decoding it
+#[derive(Clone, Debug)]
+struct Header {
+    encoding: Encoding,
+    byte_order: ByteOrder,
+    channels: usize,
+    sample_rate: u32,
+    data_start: u64,
+    data_len: u64,
+
+    // The total number of samples in the data chunk
+    length: usize,
+    channel_mask: Option<u32>,
+    sampler_loops: Vec<LoopRegion>,
+    metadata: Metadata,
+}
+
+// How the samples in a data chunk are stored
+#[derive(Clone, Debug)]
+enum Encoding {
+    // Every sample is stored on its own, in the same number of bytes
+    Pcm(Format),
+
+    // Samples are compressed in fixed-size blocks
+    Adpcm(Adpcm),
+}
+
+// Walks through every chunk in a wav file, reading the ones we're interested in and skipping over the rest.
+// This leaves the reader at an unspecified position.
+fn read_header<R: Read + Seek>(reader: &mut R) -> Result<Header, Error> {
+    let mut riff_header = [0u8; 12];
+    match read_exact(reader, &mut riff_header) {
+        Ok(()) => (),
+        Err(Error::MalformedData) => return Err(Error::InvalidFile),
+        Err(e) => return Err(e),
+    }
+    let (byte_order, has_ds64) = match (&riff_header[0..4], &riff_header[8..12]) {
+        (b"RIFF", b"WAVE") => (ByteOrder::Little, false),
+        (b"RIFX", b"WAVE") => (ByteOrder::Big, false),
+        (b"RF64", b"WAVE") | (b"BW64", b"WAVE") => (ByteOrder::Little, true),
+        _ => return Err(Error::InvalidFile),
+    };
+
+    let mut fmt = None;
+    let mut data = None;
+    let mut fact_frames = None;
+    let mut sampler_loops = Vec::new();
+    let mut metadata_chunks = Vec::new();
+
+    // RF64 and BW64 files store the real size of any chunk bigger than 4 GiB in their ds64 chunk
+    let mut ds64_sizes: Vec<([u8; 4], u64)> = Vec::new();
+
+    let mut chunk_start: u64 = 12;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        match read_exact(reader, &mut chunk_header) {
+            Ok(()) => (),
+            Err(Error::MalformedData) => break,
+            Err(e) => return Err(e),
+        }
+        let chunk_id = [chunk_header[0], chunk_header[1], chunk_header[2], chunk_header[3]];
+        let mut chunk_len = u64::from(byte_order.u32(&chunk_header[4..]));
+        if chunk_len == u64::from(u32::MAX) {
+            if let Some(&(_, len)) = ds64_sizes.iter().find(|(id, _)| *id == chunk_id) {
+                chunk_len = len;
             }
+        }
+        let body_start = chunk_start + 8;
 
-            self.next_sample_offset += samples_written * self.sample_bytes;
-            samples_written
-        } else {
-            0
+        match &chunk_id {
+            b"ds64" if has_ds64 => ds64_sizes = parse_ds64(&read_body(reader, chunk_len)?)?,
+            b"fmt " => match read_body(reader, chunk_len)? {
+                body if body.len() >= 16 => fmt = Some(body),
+                _ => return Err(Error::InvalidFile),
+            },
+            b"data" => data = Some((body_start, chunk_len)),
+            b"fact" => fact_frames = read_body(reader, chunk_len)?.get(0..4).map(|frames| byte_order.u32(frames)),
+            b"smpl" => sampler_loops = parse_sampler_loops(&read_body(reader, chunk_len)?, byte_order),
+            b"LIST" | b"cue " | b"bext" => metadata_chunks.push((chunk_id, read_body(reader, chunk_len)?)),
+            _ => (),
         }
+
+        // Chunks are padded to an even number of bytes
+        chunk_start = body_start + chunk_len + (chunk_len & 1);
+        reader.seek(SeekFrom::Start(chunk_start)).map_err(|e| Error::Io(e.kind()))?;
     }
 
-    fn channel_count(&self) -> usize {
-        self.channels
+    let (fmt, (data_start, data_len)) = match (fmt, data) {
+        (Some(fmt), Some(data)) => (fmt, data),
+        _ => return Err(Error::InvalidFile),
+    };
+
+    let mut audio_format = byte_order.u16(&fmt[0..]);
+    let channels = byte_order.u16(&fmt[2..]);
+    let sample_rate = byte_order.u32(&fmt[4..]);
+    let sample_bits = byte_order.u16(&fmt[14..]);
+    if channels == 0 || sample_rate == 0 {
+        return Err(Error::InvalidFile)
+    }
+    let channels = usize::from(channels);
+
+    let mut channel_mask = None;
+    if audio_format == WAVE_FORMAT_EXTENSIBLE {
+        // The real format is stored in the first two bytes of a GUID in the fmt chunk's extension.
+        // Samples may have fewer valid bits than their container, but they're left-aligned in it,
+        // so they can be decoded as if the container was full.
+        if fmt.len() < 40 {
+            return Err(Error::InvalidFile)
+        }
+        let valid_bits = byte_order.u16(&fmt[18..]);
+        if fmt[26..40] != KSDATAFORMAT_SUBTYPE_SUFFIX || valid_bits > sample_bits {
+            return Err(Error::UnknownFormat)
+        }
+        channel_mask = Some(byte_order.u32(&fmt[20..])).filter(|&mask| mask != 0);
+        audio_format = byte_order.u16(&fmt[24..]);
+    }
+
+    let encoding = match (audio_format, sample_bits) {
+        (1, 8) => Encoding::Pcm(Format::U8),
+        (1, 16) => Encoding::Pcm(Format::I16),
+        (1, 24) => Encoding::Pcm(Format::I24),
+        (1, 32) => Encoding::Pcm(Format::I32),
+        (2, 4) => Encoding::Adpcm(Adpcm::microsoft(&fmt, channels)?),
+        (3, 32) => Encoding::Pcm(Format::F32),
+        (3, 64) => Encoding::Pcm(Format::F64),
+        (6, 8) => Encoding::Pcm(Format::ALaw),
+        (7, 8) => Encoding::Pcm(Format::MuLaw),
+        (0x11, 4) => Encoding::Adpcm(Adpcm::ima(&fmt, channels)?),
+        _ => return Err(Error::UnknownFormat),
+    };
+
+    let length = match &encoding {
+        Encoding::Pcm(format) => (data_len / format.sample_bytes() as u64) as usize,
+        // Compressed files should have a fact chunk saying how long they are, since the last block may be padded
+        Encoding::Adpcm(adpcm) => {
+            let length = adpcm.length(data_len, channels);
+            fact_frames.map_or(length, |frames| length.min(frames as usize * channels))
+        },
+    };
+
+    let file_length = reader.seek(SeekFrom::End(0)).map_err(|e| Error::Io(e.kind()))?;
+    if data_start + data_len > file_length {
+        return Err(Error::MalformedData)
+    }
+
+    Ok(Header {
+        encoding,
+        byte_order,
+        channels,
+        sample_rate,
+        data_start,
+        data_len,
+        length,
+        channel_mask,
+        sampler_loops,
+        metadata: Metadata::parse(&metadata_chunks, byte_order),
+    })
+}
+
+// Fills the buffer from the reader, returning MalformedData if the reader runs out first
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::MalformedData,
+        kind => Error::Io(kind),
+    })
+}
+
+// Reads from the reader until the buffer is full or the reader runs out, returning how many bytes were read.
+// Errors are treated as running out.
+pub(super) fn read_fully<R: Read>(reader: &mut R, buffer: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(_) => break,
+        }
+    }
+    filled
+}
+
+// Reads the body of a chunk, which may come up short if the file is truncated
+fn read_body<R: Read>(reader: &mut R, chunk_len: u64) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    reader.take(chunk_len).read_to_end(&mut body).map_err(|e| Error::Io(e.kind()))?;
+    Ok(body)
+}
+
+// Reads the table of 64-bit chunk sizes out of the body of a ds64 chunk
+fn parse_ds64(body: &[u8]) -> Result<Vec<([u8; 4], u64)>, Error> {
+    if body.len() < 28 {
+        return Err(Error::InvalidFile)
+    }
+    let order = ByteOrder::Little;
+
+    // The RIFF size and sample count aren't needed, but the data size is always here
+    let mut sizes = vec![(*b"data", order.u64(&body[8..]))];
+    let table_len = order.u32(&body[24..]) as usize;
+    sizes.extend(
+        body[28..]
+            .chunks_exact(12)
+            .take(table_len)
+            .map(|entry| ([entry[0], entry[1], entry[2], entry[3]], order.u64(&entry[4..]))),
+    );
+    Ok(sizes)
+}
+
+// Reads the forward loops out of the body of a sampler chunk
+fn parse_sampler_loops(body: &[u8], order: ByteOrder) -> Vec<LoopRegion> {
+    if body.len() < 36 {
+        return Vec::new()
+    }
+    let loop_count = order.u32(&body[28..]) as usize;
+    body[36..]
+        .chunks_exact(24)
+        .take(loop_count)
+        .filter(|l| order.u32(&l[4..]) == 0) // 0 is forward looping, the only kind we support
+        .map(|l| LoopRegion {
+            start: u64::from(order.u32(&l[8..])),
+            // The end point in a sampler chunk is the last frame to be played, not the one after it
+            end: u64::from(order.u32(&l[12..])) + 1,
+        })
+        .collect()
+}
+
+// The byte order of the numbers in a file. Almost all wav files are little-endian, but RIFX files are big-endian.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    #[inline(always)]
+    fn u16(self, data: &[u8]) -> u16 {
+        u16::from_le_bytes(self.le_bytes(data))
+    }
+
+    #[inline(always)]
+    fn u32(self, data: &[u8]) -> u32 {
+        u32::from_le_bytes(self.le_bytes(data))
+    }
+
+    #[inline(always)]
+    fn u64(self, data: &[u8]) -> u64 {
+        u64::from_le_bytes(self.le_bytes(data))
+    }
+
+    // Copies the first N bytes of `data`, putting them in little-endian order
+    #[inline(always)]
+    fn le_bytes<const N: usize>(self, data: &[u8]) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(&data[..N]);
+        if self == ByteOrder::Big {
+            bytes.reverse();
+        }
+        bytes
+    }
+}
+
+// Decodes as many whole samples from `data` as will fit in `buffer`, returning how many were written
+pub(super) fn decode_samples(format: Format, order: ByteOrder, data: &[u8], buffer: &mut [f32]) -> usize {
+    let output_iter = buffer.iter_mut();
+    let samples_written;
+    match format {
+        Format::U8 => {
+            let iter = output_iter.zip(data.iter().copied());
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_u8(b));
+        },
+        Format::I16 => {
+            let iter = output_iter.zip(data.chunks_exact(2).map(|x| order.le_bytes::<2>(x)));
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_i16(&b));
+        },
+        Format::I24 => {
+            let iter = output_iter.zip(data.chunks_exact(3).map(|x| order.le_bytes::<3>(x)));
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_i24(&b));
+        },
+        Format::I32 => {
+            let iter = output_iter.zip(data.chunks_exact(4).map(|x| order.le_bytes::<4>(x)));
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_i32(&b));
+        },
+        Format::F32 => {
+            let iter = output_iter.zip(data.chunks_exact(4).map(|x| order.le_bytes::<4>(x)));
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_f32(&b));
+        },
+        Format::F64 => {
+            let iter = output_iter.zip(data.chunks_exact(8).map(|x| order.le_bytes::<8>(x)));
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_f64(&b));
+        },
+        Format::ALaw => {
+            let iter = output_iter.zip(data.iter().copied());
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_alaw(b));
+        },
+        Format::MuLaw => {
+            let iter = output_iter.zip(data.iter().copied());
+            samples_written = iter.len();
+            iter.for_each(|(out, b)| *out = get_sample_mulaw(b));
+        },
     }
+    samples_written
 }
 
 #[inline(always)]
@@ -170,7 +723,7 @@ fn get_sample_i16(data: &[u8; 2]) -> f32 {
 
 #[inline(always)]
 fn get_sample_i24(data: &[u8; 3]) -> f32 {
-    let sample = i32::from_le_bytes([data[0], data[1], data[2], 0]);
+    let sample = i32::from_le_bytes([0, data[0], data[1], data[2]]) >> 8;
     (sample as f32) / 8388608.0 // 2^23, or the imaginary i24::MAX
 }
 
@@ -184,3 +737,30 @@ fn get_sample_i32(data: &[u8; 4]) -> f32 {
 fn get_sample_f32(data: &[u8; 4]) -> f32 {
     f32::from_le_bytes(*data)
 }
+
+#[inline(always)]
+fn get_sample_f64(data: &[u8; 8]) -> f32 {
+    f64::from_le_bytes(*data) as f32
+}
+
+#[inline(always)]
+fn get_sample_alaw(data: u8) -> f32 {
+    // Every other bit is inverted in A-law, and the sign bit is set for positive samples
+    let data = data ^ 0x55;
+    let exponent = (data >> 4) & 0x07;
+    let mantissa = i16::from(data & 0x0F) << 4;
+    let magnitude = if exponent == 0 { mantissa + 0x08 } else { (mantissa + 0x108) << (exponent - 1) };
+    let sample = if data & 0x80 != 0 { magnitude } else { -magnitude };
+    f32::from(sample) / f32::from(i16::MAX)
+}
+
+#[inline(always)]
+fn get_sample_mulaw(data: u8) -> f32 {
+    // Every bit is inverted in mu-law, and the sign bit is set for negative samples
+    let data = !data;
+    let exponent = (data >> 4) & 0x07;
+    let mantissa = i16::from(data & 0x0F) << 3;
+    let magnitude = ((mantissa + 0x84) << exponent) - 0x84;
+    let sample = if data & 0x80 != 0 { -magnitude } else { magnitude };
+    f32::from(sample) / f32::from(i16::MAX)
+}
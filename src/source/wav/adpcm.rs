@@ -0,0 +1,236 @@
+use super::Error;
+
+// How much the IMA step index moves by for each nibble value (the sign bit doesn't matter)
+const IMA_INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+// The IMA step sizes, indexed by step index
+const IMA_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66, 73, 80, 88, 97, 107,
+    118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449, 494, 544, 598, 658, 724, 796, 876, 963,
+    1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894,
+    6484, 7132, 7845, 8630, 9493, 10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794,
+    32767,
+];
+
+// How much the Microsoft ADPCM delta is scaled by (in 1/256ths) for each nibble value
+const MS_ADAPTATION_TABLE: [i32; 16] = [230, 230, 230, 230, 307, 409, 512, 614, 768, 614, 512, 409, 307, 230, 230, 230];
+
+// The predictor coefficients every Microsoft ADPCM file is supposed to start with
+const MS_DEFAULT_COEFFICIENTS: [[i32; 2]; 7] =
+    [[256, 0], [512, -256], [0, 0], [192, 64], [240, 0], [460, -208], [392, -232]];
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Ima,
+    Microsoft { coefficients: Vec<[i32; 2]> },
+}
+
+// The layout of 4-bit ADPCM audio data, which is compressed in fixed-size blocks
+#[derive(Clone, Debug)]
+pub(super) struct Adpcm {
+    kind: Kind,
+    block_align: usize,
+    frames_per_block: usize,
+}
+
+impl Adpcm {
+    // Reads the layout of IMA/DVI ADPCM (format 0x11) data from a fmt chunk
+    pub(super) fn ima(fmt: &[u8], channels: usize) -> Result<Self, Error> {
+        Self::from_fmt(Kind::Ima, fmt, channels)
+    }
+
+    // Reads the layout of Microsoft ADPCM (format 0x2) data from a fmt chunk, including its coefficient table
+    pub(super) fn microsoft(fmt: &[u8], channels: usize) -> Result<Self, Error> {
+        let coefficients = match fmt.get(20..22) {
+            Some(count) => {
+                let count = usize::from(u16::from_le_bytes([count[0], count[1]]));
+                fmt.get(22..)
+                    .unwrap_or(&[])
+                    .chunks_exact(4)
+                    .take(count)
+                    .map(|c| [i32::from(i16::from_le_bytes([c[0], c[1]])), i32::from(i16::from_le_bytes([c[2], c[3]]))])
+                    .collect()
+            },
+            None => Vec::new(),
+        };
+        let coefficients = if coefficients.is_empty() { MS_DEFAULT_COEFFICIENTS.to_vec() } else { coefficients };
+        Self::from_fmt(Kind::Microsoft { coefficients }, fmt, channels)
+    }
+
+    fn from_fmt(kind: Kind, fmt: &[u8], channels: usize) -> Result<Self, Error> {
+        let block_align = usize::from(u16::from_le_bytes([fmt[12], fmt[13]]));
+        let mut adpcm = Self { kind, block_align, frames_per_block: 0 };
+        if block_align <= adpcm.header_bytes(channels) {
+            return Err(Error::MalformedData)
+        }
+
+        // The number of frames per block should be in the fmt extension, but it can be worked out if it isn't
+        let max_frames = adpcm.frames_in_block(block_align, channels);
+        adpcm.frames_per_block = match fmt.get(18..20) {
+            Some(frames) => usize::from(u16::from_le_bytes([frames[0], frames[1]])).min(max_frames),
+            None => max_frames,
+        };
+        if adpcm.frames_per_block == 0 {
+            return Err(Error::MalformedData)
+        }
+        Ok(adpcm)
+    }
+
+    // The number of bytes taken up by the headers at the start of each block
+    fn header_bytes(&self, channels: usize) -> usize {
+        match self.kind {
+            Kind::Ima => 4 * channels,
+            Kind::Microsoft { .. } => 7 * channels,
+        }
+    }
+
+    // The number of frames which can be decoded from a block of the given size, which may be cut short
+    fn frames_in_block(&self, bytes: usize, channels: usize) -> usize {
+        let header_bytes = self.header_bytes(channels);
+        if bytes < header_bytes {
+            return 0
+        }
+        let frames = match self.kind {
+            // IMA nibbles come in 4-byte words for each channel, and the header holds one sample per channel
+            Kind::Ima => 1 + (bytes - header_bytes) / (4 * channels) * 8,
+            // Microsoft nibbles are interleaved by channel, and the header holds two samples per channel
+            Kind::Microsoft { .. } => 2 + (bytes - header_bytes) * 2 / channels,
+        };
+        if self.frames_per_block == 0 { frames } else { frames.min(self.frames_per_block) }
+    }
+
+    // The number of samples in a data chunk of the given size
+    pub(super) fn length(&self, data_len: u64, channels: usize) -> usize {
+        let full_blocks = (data_len / self.block_align as u64) as usize;
+        let last_block = (data_len % self.block_align as u64) as usize;
+        (full_blocks * self.frames_per_block + self.frames_in_block(last_block, channels)) * channels
+    }
+
+    // Decodes a single block into interleaved samples, replacing the contents of `out`
+    fn decode_block(&self, block: &[u8], channels: usize, out: &mut Vec<f32>) {
+        let frames = self.frames_in_block(block.len(), channels);
+        out.clear();
+        out.resize(frames * channels, 0.0);
+        if frames == 0 {
+            return
+        }
+
+        let to_f32 = |sample: i32| sample as f32 / f32::from(i16::MAX);
+        let data = &block[self.header_bytes(channels)..];
+        match &self.kind {
+            Kind::Ima => {
+                for channel in 0..channels {
+                    let header = &block[(channel * 4)..];
+                    let mut predictor = i32::from(i16::from_le_bytes([header[0], header[1]]));
+                    let mut step_index = i32::from(header[2]).min(88);
+                    out[channel] = to_f32(predictor);
+
+                    for frame in 1..frames {
+                        // Each channel gets 4 bytes (8 nibbles) at a time, low nibble first
+                        let i = frame - 1;
+                        let byte = data[((i / 8) * channels + channel) * 4 + (i % 8) / 2];
+                        let nibble = if i & 1 == 0 { byte & 0x0F } else { byte >> 4 };
+
+                        let step = IMA_STEP_TABLE[step_index as usize];
+                        let mut diff = step >> 3;
+                        if nibble & 1 != 0 {
+                            diff += step >> 2;
+                        }
+                        if nibble & 2 != 0 {
+                            diff += step >> 1;
+                        }
+                        if nibble & 4 != 0 {
+                            diff += step;
+                        }
+                        if nibble & 8 != 0 {
+                            diff = -diff;
+                        }
+                        predictor = (predictor + diff).clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+                        step_index = (step_index + IMA_INDEX_TABLE[usize::from(nibble)]).clamp(0, 88);
+                        out[frame * channels + channel] = to_f32(predictor);
+                    }
+                }
+            },
+            Kind::Microsoft { coefficients } => {
+                for channel in 0..channels {
+                    let read_i16 = |offset: usize| i32::from(i16::from_le_bytes([block[offset], block[offset + 1]]));
+                    let predictor = usize::from(block[channel]).min(coefficients.len() - 1);
+                    let [coefficient_1, coefficient_2] = coefficients[predictor];
+                    let mut delta = read_i16(channels + channel * 2);
+                    let mut sample_1 = read_i16(channels * 3 + channel * 2);
+                    let mut sample_2 = read_i16(channels * 5 + channel * 2);
+
+                    // The header's samples are stored newest first
+                    out[channel] = to_f32(sample_2);
+                    out[channels + channel] = to_f32(sample_1);
+
+                    for frame in 2..frames {
+                        // Nibbles are interleaved by channel, high nibble first
+                        let i = (frame - 2) * channels + channel;
+                        let nibble = if i & 1 == 0 { data[i / 2] >> 4 } else { data[i / 2] & 0x0F };
+                        let signed_nibble = i32::from(((nibble << 4) as i8) >> 4);
+
+                        let predicted = (sample_1 * coefficient_1 + sample_2 * coefficient_2) / 256;
+                        let sample =
+                            (predicted + signed_nibble * delta).clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+                        sample_2 = sample_1;
+                        sample_1 = sample;
+                        delta = ((MS_ADAPTATION_TABLE[usize::from(nibble)] * delta) >> 8).max(16);
+                        out[frame * channels + channel] = to_f32(sample);
+                    }
+                }
+            },
+        }
+    }
+}
+
+// Holds the most recently decoded block, so that a block only has to be decoded once no matter how many
+// write_samples calls it's spread across
+#[derive(Clone, Debug, Default)]
+pub(super) struct BlockCache {
+    block: Option<usize>,
+    bytes: Vec<u8>,
+    samples: Vec<f32>,
+}
+
+impl BlockCache {
+    // Decodes samples into the buffer starting from sample index `position`, returning how many were written.
+    // `fetch` is called with a block's byte offset in the data chunk, its length and an empty Vec, and should
+    // fill the Vec with as much of that block as there is, returning false if it couldn't be read.
+    pub(super) fn read<F>(
+        &mut self,
+        adpcm: &Adpcm,
+        channels: usize,
+        position: usize,
+        buffer: &mut [f32],
+        mut fetch: F,
+    ) -> usize
+    where
+        F: FnMut(usize, usize, &mut Vec<u8>) -> bool,
+    {
+        let block_samples = adpcm.frames_per_block * channels;
+        let mut written = 0;
+        while written < buffer.len() {
+            let sample = position + written;
+            let block = sample / block_samples;
+            if self.block != Some(block) {
+                self.bytes.clear();
+                if !fetch(block * adpcm.block_align, adpcm.block_align, &mut self.bytes) {
+                    self.block = None;
+                    break
+                }
+                adpcm.decode_block(&self.bytes, channels, &mut self.samples);
+                self.block = Some(block);
+            }
+
+            let samples = self.samples.get((sample - block * block_samples)..).unwrap_or(&[]);
+            let count = samples.len().min(buffer.len() - written);
+            if count == 0 {
+                break
+            }
+            buffer[written..(written + count)].copy_from_slice(&samples[..count]);
+            written += count;
+        }
+        written
+    }
+}
@@ -0,0 +1,197 @@
+use super::ByteOrder;
+
+/// Descriptive information stored alongside the audio in a wav file.
+/// Anything the file doesn't have is left empty.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// The text fields from the file's LIST/INFO chunk, in the order they appear, keyed by their four-character
+    /// ID. For example, `INAM` is the title and `IART` is the artist.
+    pub info: Vec<([u8; 4], String)>,
+
+    /// The markers from the file's cue chunk, sorted by position, along with any names given to them
+    pub cue_points: Vec<CuePoint>,
+
+    /// The broadcast extension (`bext`) chunk, which Broadcast Wave files have
+    pub broadcast: Option<BroadcastExtension>,
+}
+
+/// A marker in a wav file, which may have a name and may cover a region rather than a single point.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CuePoint {
+    /// The ID the file uses to refer to this marker
+    pub id: u32,
+
+    /// The frame this marker is at
+    pub frame: u64,
+
+    /// The number of frames this marker covers, if it's a region
+    pub length: Option<u64>,
+
+    /// The label given to this marker by a `labl` chunk
+    pub label: Option<String>,
+
+    /// The comment given to this marker by a `note` chunk
+    pub note: Option<String>,
+
+    /// The text given to this marker's region by an `ltxt` chunk
+    pub text: Option<String>,
+}
+
+/// The contents of a `bext` chunk, as defined by EBU Tech 3285.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BroadcastExtension {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+
+    /// The date the audio was recorded, formatted as `yyyy-mm-dd`
+    pub origination_date: String,
+
+    /// The time the audio was recorded, formatted as `hh:mm:ss`
+    pub origination_time: String,
+
+    /// The number of samples since midnight at the first frame of the audio
+    pub time_reference: u64,
+    pub version: u16,
+
+    /// The SMPTE UMID of the audio, if it has one (version 1 and up)
+    pub umid: Option<[u8; 64]>,
+
+    /// A record of the processes the audio has been through, one per line
+    pub coding_history: String,
+}
+
+impl Metadata {
+    /// Returns the text of the INFO field with the given ID, if the file has it.
+    pub fn info(&self, id: &[u8; 4]) -> Option<&str> {
+        self.info.iter().find(|(field, _)| field == id).map(|(_, text)| text.as_str())
+    }
+
+    /// Returns the title of the file (`INAM`).
+    pub fn title(&self) -> Option<&str> {
+        self.info(b"INAM")
+    }
+
+    /// Returns the artist of the file (`IART`).
+    pub fn artist(&self) -> Option<&str> {
+        self.info(b"IART")
+    }
+
+    /// Returns the comments on the file (`ICMT`).
+    pub fn comment(&self) -> Option<&str> {
+        self.info(b"ICMT")
+    }
+
+    // Builds the metadata from the bodies of every LIST, cue and bext chunk in a file.
+    // The cue chunk is read first, since the names of its markers may come in a LIST chunk before it.
+    pub(super) fn parse(chunks: &[([u8; 4], Vec<u8>)], order: ByteOrder) -> Self {
+        let mut metadata = Self::default();
+        for (_, body) in chunks.iter().filter(|(id, _)| id == b"cue ") {
+            metadata.parse_cue(body, order);
+        }
+        for (id, body) in chunks {
+            match id {
+                b"LIST" => metadata.parse_list(body, order),
+                b"bext" => metadata.broadcast = parse_bext(body, order),
+                _ => (),
+            }
+        }
+        metadata.cue_points.sort_by_key(|point| point.frame);
+        metadata
+    }
+
+    fn parse_cue(&mut self, body: &[u8], order: ByteOrder) {
+        if body.len() < 4 {
+            return
+        }
+        let count = order.u32(body) as usize;
+        self.cue_points.extend(body[4..].chunks_exact(24).take(count).map(|point| CuePoint {
+            id: order.u32(&point[0..]),
+            // This is the offset in frames from the start of the data chunk
+            frame: u64::from(order.u32(&point[20..])),
+            length: None,
+            label: None,
+            note: None,
+            text: None,
+        }));
+    }
+
+    fn parse_list(&mut self, body: &[u8], order: ByteOrder) {
+        if body.len() < 4 {
+            return
+        }
+        let list_type = &body[0..4];
+        for (id, data) in sub_chunks(&body[4..], order) {
+            match list_type {
+                b"INFO" => self.info.push((id, parse_text(data))),
+                // The associated data list holds the names of cue points
+                b"adtl" if data.len() >= 4 => {
+                    let cue_id = order.u32(data);
+                    let point = match self.cue_points.iter_mut().find(|point| point.id == cue_id) {
+                        Some(point) => point,
+                        None => continue,
+                    };
+                    match &id {
+                        b"labl" => point.label = Some(parse_text(&data[4..])),
+                        b"note" => point.note = Some(parse_text(&data[4..])),
+                        b"ltxt" if data.len() >= 20 => {
+                            point.length = Some(u64::from(order.u32(&data[4..])));
+                            let text = parse_text(&data[20..]);
+                            point.text = Some(text).filter(|text| !text.is_empty());
+                        },
+                        _ => (),
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+fn parse_bext(body: &[u8], order: ByteOrder) -> Option<BroadcastExtension> {
+    if body.len() < 348 {
+        return None
+    }
+    let version = order.u16(&body[346..]);
+    let umid = match body.get(348..412) {
+        Some(umid) if version >= 1 => {
+            let mut bytes = [0u8; 64];
+            bytes.copy_from_slice(umid);
+            Some(bytes).filter(|umid| umid.iter().any(|&b| b != 0))
+        },
+        _ => None,
+    };
+    Some(BroadcastExtension {
+        description: parse_text(&body[0..256]),
+        originator: parse_text(&body[256..288]),
+        originator_reference: parse_text(&body[288..320]),
+        origination_date: parse_text(&body[320..330]),
+        origination_time: parse_text(&body[330..338]),
+        time_reference: order.u64(&body[338..]),
+        version,
+        umid,
+        coding_history: parse_text(body.get(602..).unwrap_or(&[])),
+    })
+}
+
+// Splits the body of a LIST chunk into its sub-chunks, stopping at the first one which doesn't fit
+fn sub_chunks(mut data: &[u8], order: ByteOrder) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None
+        }
+        let id = [data[0], data[1], data[2], data[3]];
+        let len = order.u32(&data[4..]) as usize;
+        let body = data.get(8..(8 + len))?;
+
+        // Like every other chunk, these are padded to an even number of bytes
+        data = data.get((8 + len + (len & 1))..).unwrap_or(&[]);
+        Some((id, body))
+    })
+}
+
+// Text in wav files is usually null-terminated, and is supposed to be ASCII, but isn't always
+fn parse_text(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim_end().to_owned()
+}
@@ -0,0 +1,307 @@
+use super::{speaker, Error, Format, KSDATAFORMAT_SUBTYPE_SUFFIX, WAVE_FORMAT_EXTENSIBLE};
+use crate::Source;
+use std::{
+    convert::TryFrom,
+    io::{Cursor, Seek, SeekFrom, Write},
+};
+
+// How many frames to pull from a source at a time while writing it
+const BLOCK_FRAMES: usize = 4096;
+
+// The size of the JUNK chunk reserved at the start of every file, which becomes a ds64 chunk if the file turns out
+// to be too big for a RIFF header
+const DS64_LEN: usize = 28;
+
+/// Writes audio data to a wav file. The header is written as soon as this is constructed, and the sizes in it
+/// are filled in by `finish`, so a WavWriter which is dropped without being finished will leave an invalid file.
+/// Files with more than two channels or more than 16 bits per sample are written with a WAVE_FORMAT_EXTENSIBLE
+/// header, and files which end up with more than 4 GiB of audio data are turned into RF64 files when they're
+/// finished.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    format: Format,
+    channels: usize,
+    block_align: u16,
+
+    // Where the wav file begins in the writer, and where the data chunk's header is, relative to that
+    start: u64,
+    data_header: u64,
+    data_len: u64,
+    bytes: Vec<u8>,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes a wav header for audio in the given format to `writer`, ready for samples to be written after it.
+    /// Returns Error::UnknownFormat if a frame of the audio would be too big to describe in the header.
+    pub fn new(mut writer: W, format: Format, channels: u16, sample_rate: u32) -> Result<Self, Error> {
+        let start = writer.stream_position().map_err(|e| Error::Io(e.kind()))?;
+
+        let (audio_format, sample_bits): (u16, u16) = match format {
+            Format::U8 => (1, 8),
+            Format::I16 => (1, 16),
+            Format::I24 => (1, 24),
+            Format::I32 => (1, 32),
+            Format::F32 => (3, 32),
+            Format::F64 => (3, 64),
+            Format::ALaw => (6, 8),
+            Format::MuLaw => (7, 8),
+        };
+        let block_align = u16::try_from(u32::from(channels) * u32::from(sample_bits / 8))
+            .ok()
+            .filter(|&block_align| block_align != 0)
+            .ok_or(Error::UnknownFormat)?;
+        let byte_rate = sample_rate.checked_mul(u32::from(block_align)).ok_or(Error::UnknownFormat)?;
+        let extensible = channels > 2 || sample_bits > 16;
+
+        let mut header = Vec::with_capacity(80);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Filled in by finish()
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"JUNK");
+        header.extend_from_slice(&(DS64_LEN as u32).to_le_bytes());
+        header.extend_from_slice(&[0; DS64_LEN]);
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&(if extensible { 40u32 } else { 16 }).to_le_bytes());
+        header.extend_from_slice(&(if extensible { WAVE_FORMAT_EXTENSIBLE } else { audio_format }).to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&sample_bits.to_le_bytes());
+        if extensible {
+            header.extend_from_slice(&22u16.to_le_bytes());
+            header.extend_from_slice(&sample_bits.to_le_bytes());
+            header.extend_from_slice(&channel_mask(channels).to_le_bytes());
+            header.extend_from_slice(&audio_format.to_le_bytes());
+            header.extend_from_slice(&KSDATAFORMAT_SUBTYPE_SUFFIX);
+        }
+        let data_header = header.len() as u64;
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Filled in by finish()
+        writer.write_all(&header).map_err(|e| Error::Io(e.kind()))?;
+
+        Ok(Self {
+            writer,
+            format,
+            channels: channels.into(),
+            block_align,
+            start,
+            data_header,
+            data_len: 0,
+            bytes: Vec::new(),
+        })
+    }
+
+    /// Encodes and writes a buffer of interleaved samples to the file.
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), Error> {
+        self.bytes.clear();
+        self.bytes.reserve(samples.len() * self.format.sample_bytes());
+        match self.format {
+            Format::U8 => self.bytes.extend(samples.iter().copied().map(put_sample_u8)),
+            Format::I16 => samples.iter().for_each(|s| self.bytes.extend_from_slice(&put_sample_i16(*s))),
+            Format::I24 => samples.iter().for_each(|s| self.bytes.extend_from_slice(&put_sample_i24(*s))),
+            Format::I32 => samples.iter().for_each(|s| self.bytes.extend_from_slice(&put_sample_i32(*s))),
+            Format::F32 => samples.iter().for_each(|s| self.bytes.extend_from_slice(&s.to_le_bytes())),
+            Format::F64 => samples.iter().for_each(|s| self.bytes.extend_from_slice(&f64::from(*s).to_le_bytes())),
+            Format::ALaw => self.bytes.extend(samples.iter().copied().map(put_sample_alaw)),
+            Format::MuLaw => self.bytes.extend(samples.iter().copied().map(put_sample_mulaw)),
+        }
+
+        self.writer.write_all(&self.bytes).map_err(|e| Error::Io(e.kind()))?;
+        self.data_len += self.bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Writes everything from a source to the file, until the source is exhausted.
+    /// Sources which never end (such as looping ones) should be cut short first, with SourceExt::take_duration.
+    /// Returns Error::ChannelCountMismatch if the source doesn't have as many channels as the file.
+    pub fn write_source(&mut self, source: &mut impl Source) -> Result<(), Error> {
+        if source.channel_count() != self.channels {
+            return Err(Error::ChannelCountMismatch)
+        }
+        let mut buffer = vec![0.0; BLOCK_FRAMES * self.channels];
+        loop {
+            let count = source.write_samples(&mut buffer);
+            self.write_samples(&buffer[..count])?;
+            if count < buffer.len() {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Fills in the sizes in the file's header, then returns the writer.
+    /// If there's more than 4 GiB of audio data, the file is turned into an RF64 file, with its real sizes stored
+    /// in a ds64 chunk.
+    pub fn finish(mut self) -> Result<W, Error> {
+        // Chunks are padded to an even number of bytes
+        let padding = self.data_len & 1;
+        if padding != 0 {
+            self.writer.write_all(&[0]).map_err(|e| Error::Io(e.kind()))?;
+        }
+
+        let riff_len = self.data_header + 4 + self.data_len + padding;
+        let end = self.start + riff_len + 8;
+
+        let (start, data_header, data_len) = (self.start, self.data_header, self.data_len);
+        let writer = &mut self.writer;
+        let mut patch = |offset: u64, bytes: &[u8]| {
+            writer.seek(SeekFrom::Start(start + offset))?;
+            writer.write_all(bytes)
+        };
+        match (u32::try_from(riff_len), u32::try_from(data_len)) {
+            (Ok(riff_len), Ok(data_len)) => {
+                patch(4, &riff_len.to_le_bytes()).and_then(|_| patch(data_header + 4, &data_len.to_le_bytes()))
+            },
+            _ => {
+                // The JUNK chunk is replaced with a ds64 chunk, and the sizes it holds are marked as too big to fit
+                let mut ds64 = Vec::with_capacity(8 + DS64_LEN);
+                ds64.extend_from_slice(b"ds64");
+                ds64.extend_from_slice(&(DS64_LEN as u32).to_le_bytes());
+                ds64.extend_from_slice(&riff_len.to_le_bytes());
+                ds64.extend_from_slice(&data_len.to_le_bytes());
+                ds64.extend_from_slice(&(data_len / u64::from(self.block_align)).to_le_bytes());
+                ds64.extend_from_slice(&0u32.to_le_bytes());
+                patch(0, b"RF64")
+                    .and_then(|_| patch(4, &u32::MAX.to_le_bytes()))
+                    .and_then(|_| patch(12, &ds64))
+                    .and_then(|_| patch(data_header + 4, &u32::MAX.to_le_bytes()))
+            },
+        }
+        .and_then(|_| self.writer.seek(SeekFrom::Start(end)))
+        .and_then(|_| self.writer.flush())
+        .map_err(|e| Error::Io(e.kind()))?;
+
+        Ok(self.writer)
+    }
+}
+
+// The speakers which files with each number of channels are assumed to be for, in the same layouts as
+// ChannelMatrix::standard. Other channel counts aren't given any.
+fn channel_mask(channels: u16) -> u32 {
+    use speaker::*;
+    let stereo = FRONT_LEFT | FRONT_RIGHT;
+    match channels {
+        1 => FRONT_CENTER,
+        2 => stereo,
+        4 => stereo | BACK_LEFT | BACK_RIGHT,
+        6 => stereo | FRONT_CENTER | LOW_FREQUENCY | BACK_LEFT | BACK_RIGHT,
+        8 => stereo | FRONT_CENTER | LOW_FREQUENCY | BACK_LEFT | BACK_RIGHT | SIDE_LEFT | SIDE_RIGHT,
+        _ => 0,
+    }
+}
+
+/// Renders a source into an in-memory wav file, until the source is exhausted.
+/// The source must know its sample rate, or this will return Error::UnknownSampleRate.
+pub fn encode(mut source: impl Source, format: Format) -> Result<Vec<u8>, Error> {
+    let sample_rate = source.sample_rate().ok_or(Error::UnknownSampleRate)?;
+    let channels = u16::try_from(source.channel_count()).map_err(|_| Error::UnknownFormat)?;
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), format, channels, sample_rate)?;
+    writer.write_source(&mut source)?;
+    Ok(writer.finish()?.into_inner())
+}
+
+#[inline(always)]
+fn put_sample_u8(sample: f32) -> u8 {
+    let sample = (sample.clamp(-1.0, 1.0) * f32::from(i8::MAX)).round() as i16;
+    (sample + 0x80) as u8
+}
+
+#[inline(always)]
+fn put_sample_i16(sample: f32) -> [u8; 2] {
+    let sample = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)).round() as i16;
+    sample.to_le_bytes()
+}
+
+#[inline(always)]
+fn put_sample_i24(sample: f32) -> [u8; 3] {
+    let sample = (sample.clamp(-1.0, 1.0) * 8388608.0).round().min(8388607.0) as i32;
+    let bytes = sample.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+#[inline(always)]
+fn put_sample_i32(sample: f32) -> [u8; 4] {
+    let sample = (f64::from(sample.clamp(-1.0, 1.0)) * f64::from(i32::MAX)).round() as i32;
+    sample.to_le_bytes()
+}
+
+#[inline(always)]
+fn put_sample_alaw(sample: f32) -> u8 {
+    let sample = i32::from(i16::from_le_bytes(put_sample_i16(sample)));
+    let (sign, magnitude) = if sample >= 0 { (0x80, sample) } else { (0x00, -sample - 1) };
+    let data = if magnitude < 0x100 {
+        magnitude >> 4
+    } else {
+        // The exponent is one more than the position of the highest set bit above the lowest 8
+        let exponent = 32 - (magnitude >> 8).leading_zeros() as i32;
+        let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+        (exponent << 4) | mantissa
+    };
+    ((data | sign) ^ 0x55) as u8
+}
+
+#[inline(always)]
+fn put_sample_mulaw(sample: f32) -> u8 {
+    let sample = i32::from(i16::from_le_bytes(put_sample_i16(sample)));
+    let (sign, magnitude) = if sample >= 0 { (0x00, sample) } else { (0x80, -sample) };
+    let magnitude = magnitude.min(32635) + 0x84;
+    let exponent = 31 - (magnitude >> 7).leading_zeros() as i32;
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::wav::StreamingWavPlayer;
+    use std::io::{self, Read};
+
+    // A file which is all zeroes after the bytes it starts with, so huge files can be read without storing them
+    struct Sparse {
+        start: Vec<u8>,
+        len: u64,
+        position: u64,
+    }
+
+    impl Read for Sparse {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let count = (buffer.len() as u64).min(self.len.saturating_sub(self.position)) as usize;
+            for (offset, byte) in buffer[..count].iter_mut().enumerate() {
+                *byte = self.start.get(self.position as usize + offset).copied().unwrap_or(0);
+            }
+            self.position += count as u64;
+            Ok(count)
+        }
+    }
+
+    impl Seek for Sparse {
+        fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+            self.position = match from {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn rf64_when_too_big() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), Format::I16, 2, 44100).unwrap();
+        writer.write_samples(&[0.5; 8]).unwrap();
+
+        // Pretend the rest of the 5 GiB is there, rather than actually writing it
+        let data_len = 5 << 30;
+        writer.data_len = data_len;
+        let writer = writer.finish().unwrap();
+        let len = writer.position();
+        let file = writer.into_inner();
+        assert_eq!(&file[0..4], b"RF64");
+        assert_eq!(&file[12..16], b"ds64");
+        assert_eq!(u64::from_le_bytes(<[u8; 8]>::try_from(&file[28..36]).unwrap()), data_len);
+        assert_eq!(u64::from_le_bytes(<[u8; 8]>::try_from(&file[36..44]).unwrap()), data_len / 4);
+
+        let player = StreamingWavPlayer::new(Sparse { start: file, len, position: 0 }).unwrap();
+        assert_eq!(player.length() as u64, data_len / 2);
+    }
+}
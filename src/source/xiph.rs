@@ -0,0 +1,88 @@
+// Pieces shared between the Xiph.org formats: the Ogg container (used by Vorbis and Opus), and Vorbis comments
+// (used by all of Vorbis, Opus and FLAC).
+
+#[cfg(any(feature = "opus", feature = "vorbis"))]
+use ogg::{OggReadError, Packet, PacketReader};
+#[cfg(any(feature = "opus", feature = "vorbis"))]
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Reads a list of Vorbis comments, which starts with a vendor string and is followed by any number of comments in
+// the form "FIELD=value". Strings are prefixed with their length, and anything which doesn't fit is left out.
+#[cfg(any(feature = "flac", feature = "opus"))]
+pub(super) fn parse_comments(mut body: &[u8]) -> Vec<(String, String)> {
+    // The vendor string comes first, then the number of comments
+    let count = match take_string(&mut body).and_then(|_| take_u32(&mut body)) {
+        Some(count) => count,
+        None => return Vec::new(),
+    };
+    (0..count)
+        .map_while(|_| take_string(&mut body))
+        .filter_map(|comment| {
+            let comment = String::from_utf8_lossy(comment);
+            let (field, value) = comment.split_once('=')?;
+            Some((field.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+// Takes a little-endian u32 off the front of some data
+#[cfg(any(feature = "flac", feature = "opus"))]
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    let bytes = *data;
+    let value = bytes.get(0..4).map(|n| u32::from_le_bytes([n[0], n[1], n[2], n[3]]))?;
+    *data = &bytes[4..];
+    Some(value)
+}
+
+// Takes a string prefixed with its length off the front of some data
+#[cfg(any(feature = "flac", feature = "opus"))]
+fn take_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u32(data)? as usize;
+    let bytes = *data;
+    let string = bytes.get(..len)?;
+    *data = &bytes[len..];
+    Some(string)
+}
+
+// Finds the granule position of the last page in an Ogg file, along with the serial number of the stream that page
+// belongs to. For Vorbis that's the length of the stream in frames. This leaves the reader at the start of the file.
+#[cfg(any(feature = "opus", feature = "vorbis"))]
+pub(super) fn read_ogg_length<R: Read + Seek>(reader: &mut R) -> io::Result<Option<(u32, u64)>> {
+    // A page can't be bigger than this, so the end of the last one will be in here
+    const MAX_PAGE_BYTES: u64 = 27 + 255 + 255 * 255;
+
+    let file_length = reader.seek(SeekFrom::End(0))?;
+    let tail_start = file_length.saturating_sub(MAX_PAGE_BYTES);
+    reader.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    // Pages which don't finish a packet have a granule position of -1
+    let length = (0..tail.len().saturating_sub(26))
+        .rev()
+        .filter(|&i| &tail[i..(i + 4)] == b"OggS" && tail[i + 4] == 0)
+        .map(|i| {
+            let mut granule = [0u8; 8];
+            granule.copy_from_slice(&tail[(i + 6)..(i + 14)]);
+            let mut serial = [0u8; 4];
+            serial.copy_from_slice(&tail[(i + 14)..(i + 18)]);
+            (u32::from_le_bytes(serial), u64::from_le_bytes(granule))
+        })
+        .find(|&(_, granule)| granule != u64::MAX);
+    Ok(length)
+}
+
+// Reads the next packet in the given Ogg stream, which should be one of its headers
+#[cfg(any(feature = "opus", feature = "vorbis"))]
+pub(super) fn read_header_packet<R: Read + Seek>(
+    packets: &mut PacketReader<R>,
+    stream_serial: u32,
+) -> Result<Packet, OggReadError> {
+    loop {
+        let packet = packets.read_packet_expected()?;
+        if packet.stream_serial() == stream_serial {
+            return Ok(packet)
+        }
+    }
+}
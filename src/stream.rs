@@ -1,28 +1,42 @@
-use crate::{Error, Mixer, Source};
+use crate::{mixer::FinishedQueue, Controlled, Error, Mixer, Resampler, Source, SourceHandle, SourceOptions};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     BuildStreamError, PlayStreamError, SampleFormat, SupportedStreamConfigsError,
 };
-use std::sync::{Arc, Mutex};
+use crossbeam_queue::ArrayQueue;
+use std::{marker::PhantomData, sync::Arc};
+
+// How many commands can be waiting for the audio thread at once
+const COMMAND_CAPACITY: usize = 1024;
 
 /// An audio output stream which plays audio sources. Must be used with a Mixer + Source object.
 /// This object will be queried for samples to be played directly to the output device.
+/// The Mixer is owned by the audio thread, which is sent commands through a lock-free queue, so adding sources
+/// never blocks it. Sources the Mixer is finished with are sent back through its finished queue (if it has one),
+/// and dropped the next time a source is added, so that the audio thread never frees them.
 pub struct OutputStream<M>
 where
-    M: Mixer + Send + Sync + 'static,
+    M: Mixer + Send + 'static,
 {
     _stream: cpal::Stream,
-    source: Arc<Mutex<M>>,
+    commands: Arc<ArrayQueue<Command>>,
+    finished: Option<Arc<FinishedQueue>>,
+    _mixer: PhantomData<fn(M)>,
     pub sample_rate: u32,
     pub channel_count: u16,
 }
 
+// Something for the audio thread to do before it next writes samples
+enum Command {
+    AddSource(Controlled<Box<dyn Source + Send + Sync>>),
+}
+
 impl<M> OutputStream<M>
 where
-    M: Mixer + Send + Sync + 'static,
+    M: Mixer + Send + 'static,
 {
     /// Sets up and returns an OutputStream. Takes a closure which sets up a Mixer.
-    /// The Mixer must also be a Source, and must be thread-safe (Send + Sync)
+    /// The Mixer must also be a Source, and must be Send so that it can be moved to the audio thread.
     pub fn with<F>(mut mixer_setup: F) -> Result<Self, Error>
     where
         F: FnMut(u16) -> M,
@@ -50,11 +64,30 @@ where
         let sample_rate = supported_config.sample_rate().0;
         let channel_count: u16 = supported_config.channels();
 
-        let source: Arc<Mutex<M>> = Arc::new(Mutex::new(mixer_setup(channel_count)));
-        let closure_source = source.clone();
+        let mut mixer = mixer_setup(channel_count);
+        let commands = Arc::new(ArrayQueue::new(COMMAND_CAPACITY));
+        let closure_commands = commands.clone();
+        let finished = mixer.finished_queue();
+        let closure_finished = finished.clone();
 
+        // A source which the Mixer had no room for, waiting for room in the finished queue to be sent back.
+        // No more sources are added until it's gone.
+        let mut rejected = None;
         let write_f32 = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            closure_source.lock().unwrap().write_samples(data);
+            if let Some(source) = rejected.take() {
+                rejected = send_back(&closure_finished, source);
+            }
+            while rejected.is_none() {
+                match closure_commands.pop() {
+                    Some(Command::AddSource(source)) => {
+                        if let Err(source) = mixer.add_controlled(source) {
+                            rejected = send_back(&closure_finished, source);
+                        }
+                    },
+                    None => break,
+                }
+            }
+            mixer.write_samples(data);
         };
 
         let write_i16 = move |_data: &mut [i16], _: &cpal::OutputCallbackInfo| todo!("write_i16");
@@ -82,11 +115,49 @@ where
             _ => (),
         }
 
-        Ok(OutputStream { _stream: stream, source, sample_rate, channel_count })
+        Ok(OutputStream { _stream: stream, commands, finished, _mixer: PhantomData, sample_rate, channel_count })
+    }
+
+    /// Adds an audio source to the output stream, returning a handle which can control it.
+    /// The source will be played until it ends or is stopped.
+    /// If the source reports a sample rate which differs from the output device's, it will be resampled.
+    /// This fails if the audio thread has fallen so far behind that it has too many sources waiting to be added.
+    /// If the Mixer turns the source away once it gets there, it's never played, and its handle reports that it
+    /// isn't playing.
+    pub fn add_source(&self, source: impl Source + Send + Sync + 'static) -> Result<SourceHandle, Error> {
+        self.add_source_with(source, SourceOptions::default())
     }
 
-    /// Adds an audio source to the output stream. The source will be played until it ends.
-    pub fn add_source(&self, source: impl Source + Send + Sync + 'static) {
-        self.source.lock().unwrap().add_source(source);
+    /// Adds an audio source like add_source does, but with the given volume and pan position to start with.
+    pub fn add_source_with(
+        &self,
+        source: impl Source + Send + Sync + 'static,
+        options: SourceOptions,
+    ) -> Result<SourceHandle, Error> {
+        // Everything which allocates or frees memory is done here, so that the audio thread only has to move sources
+        // in and out
+        if let Some(finished) = &self.finished {
+            while finished.pop().is_some() {}
+        }
+        let source: Box<dyn Source + Send + Sync> = match source.sample_rate() {
+            Some(rate) if rate != self.sample_rate => Box::new(Resampler::new(source, rate, self.sample_rate)),
+            _ => Box::new(source),
+        };
+        let source = Controlled::new(source, options);
+        let handle = source.handle();
+        match self.commands.push(Command::AddSource(source)) {
+            Ok(()) => Ok(handle),
+            Err(_) => Err(Error::CommandQueueFull),
+        }
     }
 }
+
+// Sends a source back to be dropped off the audio thread, giving it back if there's no room for it yet.
+// Mixers without a finished queue drop their sources themselves, so this does too.
+fn send_back(
+    finished: &Option<Arc<FinishedQueue>>,
+    source: Controlled<Box<dyn Source + Send + Sync>>,
+) -> Option<Controlled<Box<dyn Source + Send + Sync>>> {
+    source.mark_finished();
+    finished.as_ref().and_then(|finished| finished.push(source).err())
+}
@@ -0,0 +1,119 @@
+use boop::{
+    source::wav::{StreamingWavPlayer, WavPlayer},
+    Source,
+};
+use std::io::Cursor;
+
+// The expected Microsoft ADPCM samples were decoded from the same blocks with Symphonia. Symphonia computes IMA steps
+// with FFmpeg's multiplication rather than the shifts and adds in the IMA reference algorithm, which rounds
+// differently, so the expected IMA samples come from a direct transcription of the reference algorithm instead.
+
+// Builds a wav file holding a single ADPCM block with the given header for each channel, followed by `data_len`
+// bytes of a simple pattern, masked to keep the decoded signal from running away
+fn adpcm_wav(ima: bool, channels: u16, headers: &[[u8; 7]], data_len: usize, mask: u8) -> Vec<u8> {
+    let header_len = if ima { 4 } else { 7 };
+    let block_align = header_len * channels + data_len as u16;
+    let frames_per_block = if ima { data_len as u16 * 2 / channels + 1 } else { data_len as u16 * 2 / channels + 2 };
+
+    let mut fmt = Vec::new();
+    fmt.extend_from_slice(&(if ima { 0x11u16 } else { 0x02 }).to_le_bytes());
+    fmt.extend_from_slice(&channels.to_le_bytes());
+    fmt.extend_from_slice(&8000u32.to_le_bytes());
+    fmt.extend_from_slice(&4000u32.to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&4u16.to_le_bytes());
+    if ima {
+        fmt.extend_from_slice(&2u16.to_le_bytes());
+        fmt.extend_from_slice(&frames_per_block.to_le_bytes());
+    } else {
+        let coefficients: [(i16, i16); 7] =
+            [(256, 0), (512, -256), (0, 0), (192, 64), (240, 0), (460, -208), (392, -232)];
+        fmt.extend_from_slice(&32u16.to_le_bytes());
+        fmt.extend_from_slice(&frames_per_block.to_le_bytes());
+        fmt.extend_from_slice(&7u16.to_le_bytes());
+        for (a, b) in coefficients.iter() {
+            fmt.extend_from_slice(&a.to_le_bytes());
+            fmt.extend_from_slice(&b.to_le_bytes());
+        }
+    }
+
+    // IMA headers are stored one channel after another, and MS headers one field after another
+    let mut data = Vec::new();
+    if ima {
+        headers.iter().for_each(|header| data.extend_from_slice(&header[..4]));
+    } else {
+        for &(start, len) in [(0, 1), (1, 2), (3, 2), (5, 2)].iter() {
+            headers.iter().for_each(|header| data.extend_from_slice(&header[start..start + len]));
+        }
+    }
+    data.extend((0..data_len).map(|i| (i * 37 + 11) as u8 & mask));
+
+    let mut file = b"RIFF".to_vec();
+    file.extend_from_slice(&(4 + 8 + fmt.len() as u32 + 8 + data.len() as u32).to_le_bytes());
+    file.extend_from_slice(b"WAVEfmt ");
+    file.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+    file.extend_from_slice(&fmt);
+    file.extend_from_slice(b"data");
+    file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    file.extend_from_slice(&data);
+    file
+}
+
+fn decode(file: Vec<u8>) -> Vec<i16> {
+    let mut wav = WavPlayer::new(file.clone()).unwrap();
+    let mut samples = vec![0.0; 256];
+    let count = wav.write_samples(&mut samples);
+    samples.truncate(count);
+
+    let mut streaming = StreamingWavPlayer::new(Cursor::new(file)).unwrap();
+    let mut streamed = vec![0.0; 256];
+    let streamed_count = streaming.write_samples(&mut streamed);
+    assert_eq!(samples[..], streamed[..streamed_count]);
+
+    samples.iter().map(|s| (s * f32::from(i16::MAX)).round() as i16).collect()
+}
+
+#[test]
+fn ima_mono() {
+    let file = adpcm_wav(true, 1, &[[0xE8, 0x03, 40, 0, 0, 0, 0]], 32, 0xBB);
+    let expected: [i16; 65] = [
+        1000, 706, 744, 778, 998, 1083, 1161, 1043, 1193, 1057, 1005, 1021, 1007, 968, 908, 853, 863, 926, 983, 976,
+        995, 977, 1015, 1040, 1018, 1047, 1044, 1041, 1026, 1033, 1040, 1051, 1065, 1051, 1055, 1056, 1055, 1058, 1052,
+        1047, 1046, 1042, 1039, 1039, 1040, 1039, 1043, 1040, 1041, 1045, 1045, 1045, 1042, 1041, 1041, 1044, 1040,
+        1044, 1045, 1045, 1049, 1050, 1053, 1056, 1056,
+    ];
+    assert_eq!(decode(file), expected);
+}
+
+#[test]
+fn ima_stereo() {
+    let file = adpcm_wav(true, 2, &[[0x18, 0xFC, 10, 0, 0, 0, 0], [0x00, 0x10, 40, 0, 0, 0, 0]], 32, 0xBB);
+    let expected: [i16; 66] = [
+        -1000, 4096, -1015, 3802, -1013, 3688, -1011, 3722, -1000, 3691, -996, 3606, -992, 3476, -998, 3358, -990,
+        3379, -983, 3515, -976, 3498, -976, 3482, -975, 3409, -976, 3448, -972, 3484, -969, 3539, -972, 3609, -976,
+        3546, -975, 3505, -975, 3512, -975, 3531, -974, 3513, -977, 3551, -980, 3526, -980, 3539, -976, 3568, -976,
+        3578, -976, 3575, -979, 3596, -980, 3603, -980, 3615, -977, 3626, -981, 3624,
+    ];
+    assert_eq!(decode(file), expected);
+}
+
+#[test]
+fn microsoft_mono() {
+    let file = adpcm_wav(false, 1, &[[1, 64, 0, 0x2C, 0x01, 0xC8, 0x00]], 14, 0x93);
+    let expected: [i16; 30] = [
+        200, 300, 400, 671, 993, 1315, 1677, 2074, 2502, 2984, 3298, 3783, 3860, 3937, 2918, 2310, 1702, 1756, 2107,
+        3256, 4643, 6030, 7608, 9357, 9882, 11325, 9472, 11327, 4302, -2723,
+    ];
+    assert_eq!(decode(file), expected);
+}
+
+#[test]
+fn microsoft_stereo() {
+    let headers = [[4, 0x00, 0x01, 0x10, 0x00, 0xF0, 0xFF], [6, 0x30, 0x00, 0x00, 0x08, 0x00, 0x07]];
+    let file = adpcm_wav(false, 2, &headers, 12, 0x93);
+    let expected: [i16; 28] = [
+        -16, 1792, 16, 2048, 15, 1656, 244, 679, 434, -423, 591, -1195, -608, -1356, -3754, -993, -13071, -268, -12254,
+        529, -8270, 1103, -4862, 1209, -1961, 867, -20502, 263,
+    ];
+    assert_eq!(decode(file), expected);
+}
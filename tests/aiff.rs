@@ -0,0 +1,186 @@
+use boop::{
+    source::aiff::{AiffPlayer, Error},
+    Length, Seekable, Source,
+};
+
+// 80-bit extended precision sample rates, as they'd be written by an encoder
+const RATE_44100: [u8; 10] = [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0];
+const RATE_8000: [u8; 10] = [0x40, 0x0B, 0xFA, 0, 0, 0, 0, 0, 0, 0];
+
+// The rate of old Macs, 22254.5454...
+const RATE_MAC: [u8; 10] = [0x40, 0x0D, 0xAD, 0xDD, 0x17, 0x45, 0xD1, 0x45, 0x82, 0x6B];
+
+// What goes into a test file's common chunk
+struct Comm {
+    channels: u16,
+    frames: u32,
+    bits: u16,
+    rate: [u8; 10],
+
+    // The compression type, which makes the file AIFF-C
+    compression: Option<&'static [u8; 4]>,
+}
+
+// Appends a chunk to `file`, padded to an even length
+fn chunk(file: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    file.extend_from_slice(id);
+    file.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    file.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        file.push(0);
+    }
+}
+
+// Builds a file with a common chunk and a sound data chunk, which starts `offset` bytes in
+fn aiff(comm: Comm, offset: u32, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&comm.channels.to_be_bytes());
+    body.extend_from_slice(&comm.frames.to_be_bytes());
+    body.extend_from_slice(&comm.bits.to_be_bytes());
+    body.extend_from_slice(&comm.rate);
+    if let Some(compression) = comm.compression {
+        // AIFF-C adds a compression type, then its name as a Pascal string
+        body.extend_from_slice(compression);
+        body.extend_from_slice(b"\x04name\x00");
+    }
+    let mut chunks = Vec::new();
+    chunk(&mut chunks, b"COMM", &body);
+
+    let mut ssnd = Vec::new();
+    ssnd.extend_from_slice(&offset.to_be_bytes());
+    ssnd.extend_from_slice(&0u32.to_be_bytes());
+    ssnd.extend((0..offset).map(|i| i as u8));
+    ssnd.extend_from_slice(data);
+    chunk(&mut chunks, b"SSND", &ssnd);
+
+    let mut file = b"FORM".to_vec();
+    file.extend_from_slice(&(4 + chunks.len() as u32).to_be_bytes());
+    file.extend_from_slice(if comm.compression.is_some() { b"AIFC" } else { b"AIFF" });
+    file.extend_from_slice(&chunks);
+    file
+}
+
+fn pcm(channels: u16, frames: u32, bits: u16) -> Comm {
+    Comm { channels, frames, bits, rate: RATE_44100, compression: None }
+}
+
+fn compressed(frames: u32, bits: u16, compression: &'static [u8; 4]) -> Comm {
+    Comm { channels: 1, frames, bits, rate: RATE_44100, compression: Some(compression) }
+}
+
+fn read_all(source: &mut impl Source) -> Vec<f32> {
+    let mut samples = vec![0.0; 4096];
+    let count = source.write_samples(&mut samples);
+    samples.truncate(count);
+    samples
+}
+
+fn decode(file: Vec<u8>) -> Vec<f32> {
+    read_all(&mut AiffPlayer::new(file).unwrap())
+}
+
+#[test]
+fn pcm_formats() {
+    // Plain AIFF is big-endian, and its 8-bit samples are signed
+    let mut player =
+        AiffPlayer::new(aiff(pcm(2, 2, 16), 0, &[0x7F, 0xFF, 0x80, 0x01, 0x00, 0x00, 0x40, 0x00])).unwrap();
+    assert_eq!((player.channel_count(), player.sample_rate()), (2, Some(44100)));
+    assert_eq!(player.total_frames(), Length::Exact(2));
+    assert_eq!(read_all(&mut player), [1.0, -1.0, 0.0, 16384.0 / 32767.0]);
+    assert_eq!(decode(aiff(pcm(1, 4, 8), 0, &[0x7F, 0x81, 0x00, 0xC0])), [1.0, -1.0, 0.0, -64.0 / 127.0]);
+    assert_eq!(decode(aiff(pcm(1, 2, 24), 0, &[0x7F, 0xFF, 0xFF, 0x80, 0x00, 0x00])), [8388607.0 / 8388608.0, -1.0]);
+    assert_eq!(decode(aiff(pcm(1, 2, 32), 0, &[0x7F, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00])), [1.0, 0.0]);
+
+    // Samples which don't fill their bytes are left-aligned in them
+    assert_eq!(decode(aiff(pcm(1, 2, 12), 0, &[0x40, 0x00, 0xC0, 0x00])), [16384.0 / 32767.0, -16384.0 / 32767.0]);
+    assert!(matches!(AiffPlayer::new(aiff(pcm(1, 1, 33), 0, &[0; 5])), Err(Error::UnknownFormat)));
+}
+
+#[test]
+fn compression_types() {
+    let samples = |compression, bits, data: &[u8]| decode(aiff(compressed(2, bits, compression), 0, data));
+    assert_eq!(samples(b"NONE", 16, &[0x40, 0x00, 0xC0, 0x00]), [16384.0 / 32767.0, -16384.0 / 32767.0]);
+    assert_eq!(samples(b"twos", 16, &[0x40, 0x00, 0xC0, 0x00]), [16384.0 / 32767.0, -16384.0 / 32767.0]);
+    assert_eq!(samples(b"sowt", 16, &[0x00, 0x40, 0x00, 0xC0]), [16384.0 / 32767.0, -16384.0 / 32767.0]);
+    assert_eq!(samples(b"sowt", 8, &[0x7F, 0x81]), [1.0, -1.0]);
+    assert_eq!(samples(b"raw ", 8, &[0xFF, 0x80]), [1.0, 0.0]);
+    assert_eq!(samples(b"in24", 24, &[0x40, 0x00, 0x00, 0xC0, 0x00, 0x00]), [0.5, -0.5]);
+    assert_eq!(samples(b"in32", 32, &[0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x01]), [0.0, -1.0]);
+
+    let mut data = 0.25f32.to_be_bytes().to_vec();
+    data.extend_from_slice(&(-1.5f32).to_be_bytes());
+    assert_eq!(samples(b"fl32", 32, &data), [0.25, -1.5]);
+    assert_eq!(samples(b"FL32", 32, &data), [0.25, -1.5]);
+    let mut data = 0.125f64.to_be_bytes().to_vec();
+    data.extend_from_slice(&(-0.75f64).to_be_bytes());
+    assert_eq!(samples(b"fl64", 64, &data), [0.125, -0.75]);
+
+    // The loudest and quietest positive codes, which are 0xAA/0xD5 in A-law and 0x80/0xFF in mu-law
+    assert_eq!(samples(b"alaw", 16, &[0xAA, 0xD5]), [32256.0 / 32767.0, 8.0 / 32767.0]);
+    assert_eq!(samples(b"ulaw", 16, &[0x80, 0xFF]), [32124.0 / 32767.0, 0.0]);
+    assert_eq!(samples(b"ULAW", 16, &[0x00, 0x7F]), [-32124.0 / 32767.0, 0.0]);
+
+    assert!(matches!(AiffPlayer::new(aiff(compressed(1, 16, b"ima4"), 0, &[0; 34])), Err(Error::UnknownFormat)));
+}
+
+#[test]
+fn sample_rates() {
+    let rate = |rate| {
+        let comm = Comm { channels: 1, frames: 1, bits: 16, rate, compression: None };
+        AiffPlayer::new(aiff(comm, 0, &[0, 0])).map(|player| player.sample_rate())
+    };
+    assert_eq!(rate(RATE_44100).unwrap(), Some(44100));
+    assert_eq!(rate(RATE_8000).unwrap(), Some(8000));
+    assert_eq!(rate(RATE_MAC).unwrap(), Some(22255));
+
+    // Rates which are zero, negative or too big to be a u32 aren't valid
+    assert!(matches!(rate([0; 10]), Err(Error::InvalidFile)));
+    assert!(matches!(rate([0xC0, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]), Err(Error::InvalidFile)));
+    assert!(matches!(rate([0x40, 0x20, 0x80, 0, 0, 0, 0, 0, 0, 0]), Err(Error::InvalidFile)));
+}
+
+#[test]
+fn layout() {
+    let data = [0x00, 0x01, 0x00, 0x02, 0x00, 0x03];
+    let expected = data.chunks(2).map(|b| f32::from(i16::from_be_bytes([b[0], b[1]])) / 32767.0).collect::<Vec<_>>();
+
+    // The sound data can start partway into its chunk
+    assert_eq!(decode(aiff(pcm(1, 3, 16), 5, &data)), expected);
+
+    // Chunks can come in any order, with others between them, and odd-length chunks are padded
+    let file = aiff(pcm(1, 3, 16), 0, &data);
+    let comm_end = 12 + 8 + 18;
+    let mut reordered = file[..12].to_vec();
+    chunk(&mut reordered, b"NAME", b"odd");
+    reordered.extend_from_slice(&file[comm_end..]);
+    chunk(&mut reordered, b"ANNO", b"a comment");
+    reordered.extend_from_slice(&file[12..comm_end]);
+    assert_eq!(decode(reordered), expected);
+
+    // Frames past the number the common chunk gives are ignored, but it can't make the sound data any longer
+    assert_eq!(decode(aiff(pcm(1, 2, 16), 0, &data)), expected[..2]);
+    let mut player = AiffPlayer::new(aiff(pcm(1, 10, 16), 0, &data)).unwrap();
+    assert_eq!(player.total_frames(), Length::Exact(3));
+    assert_eq!(read_all(&mut player), expected);
+
+    // Stereo files seek by whole frames
+    let mut player = AiffPlayer::new(aiff(pcm(2, 3, 8), 0, &[1, 2, 3, 4, 5, 6])).unwrap();
+    player.seek_frame(1);
+    assert_eq!(player.remaining_frames(), Length::Exact(2));
+    assert_eq!(read_all(&mut player), [3.0 / 127.0, 4.0 / 127.0, 5.0 / 127.0, 6.0 / 127.0]);
+    player.seek_frame(5);
+    assert_eq!(player.remaining_frames(), Length::Exact(0));
+}
+
+#[test]
+fn invalid_files() {
+    let file = aiff(pcm(1, 3, 16), 0, &[0; 6]);
+    assert!(matches!(AiffPlayer::new(&b"RIFF"[..]), Err(Error::InvalidFile)));
+    assert!(matches!(AiffPlayer::new(file[..(file.len() - 1)].to_vec()), Err(Error::MalformedData)));
+    assert!(matches!(AiffPlayer::new(aiff(pcm(0, 3, 16), 0, &[0; 6])), Err(Error::InvalidFile)));
+
+    // Without a sound data chunk, there's nothing to play
+    let mut file = file[..(12 + 8 + 18)].to_vec();
+    file[4..8].copy_from_slice(&(4 + 8 + 18u32).to_be_bytes());
+    assert!(matches!(AiffPlayer::new(file), Err(Error::InvalidFile)));
+}
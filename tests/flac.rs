@@ -0,0 +1,51 @@
+#![cfg(feature = "flac")]
+
+use boop::{source::flac::FlacPlayer, Seekable, Source};
+
+const FILES: [&[u8]; 3] = [
+    // One second of a 440 Hz sine wave in stereo, from the audrey crate's samples
+    include_bytes!("data/sine_440hz_stereo.flac"),
+    // These two come from claxon's test samples
+    include_bytes!("data/wasted_bits.flac"),
+    include_bytes!("data/non_subset.flac"),
+];
+
+// Decodes a whole file with claxon, scaled to the same range as FlacPlayer's samples
+fn reference(file: &[u8]) -> Vec<f32> {
+    let mut reader = claxon::FlacReader::new(file).unwrap();
+    let scale = (1u64 << (reader.streaminfo().bits_per_sample - 1)) as f32;
+    reader.samples().map(|sample| sample.unwrap() as f32 / scale).collect()
+}
+
+#[test]
+fn decode() {
+    for &file in FILES.iter() {
+        let reference = reference(file);
+        let mut player = FlacPlayer::new(file).unwrap();
+        let mut samples = vec![0.0; reference.len() + 100];
+        let count = player.write_samples(&mut samples);
+        assert_eq!(player.length(), Some((reference.len() / player.channel_count()) as u64));
+        assert_eq!(samples[..count], reference[..]);
+    }
+}
+
+#[test]
+fn seek() {
+    for &file in FILES.iter() {
+        let reference = reference(file);
+        let mut player = FlacPlayer::new(file).unwrap();
+        let channels = player.channel_count();
+        let length = player.length().unwrap();
+        let mut samples = vec![0.0; 512];
+
+        // Seek forwards and backwards, onto block boundaries and between them
+        let frames = [length / 2, 0, 1, 4095, 4096, 4097, length / 3, length - 1, length];
+        for &frame in frames.iter().filter(|&&frame| frame <= length) {
+            player.seek_frame(frame);
+            let count = player.write_samples(&mut samples);
+            let start = frame as usize * channels;
+            let expected = &reference[start..(start + 512).min(reference.len())];
+            assert_eq!(samples[..count], *expected, "seeking to frame {}", frame);
+        }
+    }
+}
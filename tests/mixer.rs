@@ -0,0 +1,223 @@
+use boop::{
+    mixer::{BufferedMixer, Bus, Effect, VoiceStealing},
+    Controlled, Error, Mixer, Player, Source, SourceExt, SourceOptions,
+};
+
+fn player(frames: usize, channels: usize) -> Player {
+    Player::new(vec![0.5; frames * channels].into_boxed_slice(), channels)
+}
+
+fn constant(value: f32, frames: usize) -> Player {
+    Player::new(vec![value; frames * 2].into_boxed_slice(), 2)
+}
+
+// Adds its value to every sample
+struct Offset(f32);
+
+impl Effect for Offset {
+    fn process(&mut self, buffer: &mut [f32], _channels: usize) {
+        buffer.iter_mut().for_each(|s| *s += self.0);
+    }
+}
+
+// Returns the first sample of the next block, once the one before has ramped any changes in
+fn settled(bus: &mut Bus) -> f32 {
+    let mut buffer = vec![0.0; 2 * 64];
+    bus.write_samples(&mut buffer);
+    bus.write_samples(&mut buffer);
+    buffer[0]
+}
+
+#[test]
+fn capacity() {
+    let mut mixer = BufferedMixer::new(2).with_capacity(2);
+    let first = mixer.add_source(player(100, 2));
+    let second = mixer.add_source(player(10_000, 2));
+    let third = mixer.add_source(player(100, 1));
+    assert!(first.is_playing() && second.is_playing());
+    assert!(!third.is_playing());
+
+    // There's no matrix for sources with this many channels
+    let wide = Controlled::new(Box::new(player(100, 12)) as Box<dyn Source + Send + Sync>, SourceOptions::default());
+    assert!(BufferedMixer::new(2).add_controlled(wide).is_err());
+
+    // Output buffers much longer than the mixer's own are mixed a block at a time
+    let mut buffer = vec![0.0; 2 * 5000];
+    assert_eq!(mixer.write_samples(&mut buffer), buffer.len());
+    assert_eq!(buffer[..200], [1.0; 200][..]);
+    assert_eq!(buffer[200..], [0.5; 9800][..]);
+}
+
+#[test]
+fn silent_sources_end() {
+    // Sources at no volume still play through and give up their voices, unlike paused ones
+    let mut mixer = BufferedMixer::new(2).with_capacity(2);
+    let silent = mixer.add_source_with(player(100, 2), SourceOptions { volume: 0.0, ..Default::default() });
+    let muted = mixer.add_source(player(100, 2));
+    muted.set_volume(0.0);
+    let mut buffer = vec![0.0; 2 * 40];
+    mixer.write_samples(&mut buffer);
+    mixer.write_samples(&mut buffer);
+    assert!(silent.is_playing() && muted.is_playing());
+    assert_eq!(buffer, [0.0; 2 * 40]);
+
+    mixer.write_samples(&mut buffer);
+    assert!(!silent.is_playing() && !muted.is_playing());
+    let next = mixer.add_source(player(100, 2));
+    assert!(next.is_playing());
+}
+
+#[test]
+fn finished_queue() {
+    let mut mixer = BufferedMixer::new(2).with_capacity(1);
+    let finished = mixer.finished_queue().unwrap();
+    let handle = mixer.add_source(player(10, 2));
+    let mut buffer = vec![0.0; 64];
+    mixer.write_samples(&mut buffer);
+
+    // The finished source is handed back rather than dropped, and its place is free again
+    assert!(!handle.is_playing());
+    assert_eq!(finished.len(), 1);
+    let next = mixer.add_source(player(10, 2));
+    assert!(next.is_playing());
+    assert!(finished.is_empty());
+}
+
+#[test]
+fn surround_pan() {
+    // Mono sources are panned across the front left, center and front right speakers of 5.1
+    let mut buffer = vec![0.0; 6 * 4];
+    for &(pan, expected) in [(-1.0, [1.0, 0.0, 0.0]), (0.0, [0.0, 0.0, 1.0]), (1.0, [0.0, 1.0, 0.0])].iter() {
+        let mut mixer = BufferedMixer::new(6);
+        mixer.add_source_with(player(4, 1), SourceOptions { pan, ..Default::default() });
+        mixer.write_samples(&mut buffer);
+        for frame in buffer.chunks_exact(6) {
+            let front = [frame[0], frame[1], frame[2]].map(|s| (s * 2.0 * 1000.0).round() / 1000.0);
+            assert_eq!(front, expected, "pan {}", pan);
+            assert_eq!(frame[3..], [0.0; 3]);
+        }
+    }
+
+    // Halfway to the left is a constant-power mix of the left and center speakers
+    let mut mixer = BufferedMixer::new(6);
+    mixer.add_source_with(player(4, 1), SourceOptions { pan: -0.5, ..Default::default() });
+    mixer.write_samples(&mut buffer);
+    assert!((buffer[0] - buffer[2]).abs() < 1e-6);
+    assert!((buffer[0].powi(2) + buffer[2].powi(2) - 0.25).abs() < 1e-6);
+
+    // NaN is the center, and doesn't spread to the output
+    let mut mixer = BufferedMixer::new(2);
+    let handle = mixer.add_source_with(player(4, 1), SourceOptions { pan: f32::NAN, ..Default::default() });
+    assert_eq!(handle.pan(), 0.0);
+    handle.set_pan(f32::NAN);
+    assert_eq!(handle.pan(), 0.0);
+    mixer.write_samples(&mut buffer[..8]);
+    assert!(buffer[..8].iter().all(|s| (s - 0.5 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6));
+}
+
+#[test]
+fn bus_solo() {
+    let mut root = Bus::new(BufferedMixer::new(2));
+    let handle = root.handle();
+    handle.add_source(constant(1.0, 10_000)).unwrap();
+    let a = handle.add_bus(BufferedMixer::new(2)).unwrap();
+    a.add_source(constant(2.0, 10_000)).unwrap();
+    let nested = a.add_bus(BufferedMixer::new(2)).unwrap();
+    nested.add_source(constant(4.0, 10_000)).unwrap();
+    let b = handle.add_bus(BufferedMixer::new(2)).unwrap();
+    b.add_source(constant(8.0, 10_000)).unwrap();
+    assert_eq!(settled(&mut root), 15.0);
+
+    // Soloing a nested bus silences everything else in the tree, including the sources on the buses above it
+    nested.set_soloed(true);
+    assert_eq!(settled(&mut root), 4.0);
+
+    // Soloing a bus lets through everything beneath it
+    nested.set_soloed(false);
+    a.set_soloed(true);
+    assert_eq!(settled(&mut root), 6.0);
+    b.set_soloed(true);
+    assert_eq!(settled(&mut root), 14.0);
+    a.set_soloed(false);
+    assert_eq!(settled(&mut root), 8.0);
+
+    // Removing a soloed bus stops it silencing the rest, and it can't be soloed again
+    b.remove();
+    assert!(!b.is_soloed());
+    assert_eq!(settled(&mut root), 7.0);
+    b.set_soloed(true);
+    assert!(!b.is_soloed());
+    assert_eq!(settled(&mut root), 7.0);
+
+    // Buses soloed while they're being removed don't leave the rest silenced
+    for _ in 0..100 {
+        let bus = handle.add_bus(BufferedMixer::new(2)).unwrap();
+        let soloing = bus.clone();
+        let thread = std::thread::spawn(move || soloing.set_soloed(true));
+        bus.remove();
+        thread.join().unwrap();
+        assert!(!bus.is_soloed());
+    }
+    assert_eq!(settled(&mut root), 7.0);
+
+    // The root can't be soloed
+    handle.set_soloed(true);
+    assert!(!handle.is_soloed());
+}
+
+#[test]
+fn bus_effects() {
+    let mut root = Bus::new(BufferedMixer::new(2)).with_effect(Offset(1.0));
+    let handle = root.handle();
+    for _ in 1..16 {
+        handle.insert_effect(Offset(1.0)).unwrap();
+    }
+    assert!(matches!(handle.insert_effect(Offset(1.0)), Err(Error::TooManyEffects)));
+    assert_eq!(settled(&mut root), 16.0);
+
+    // Clearing the effects makes room for new ones once the bus has cleared them
+    handle.clear_effects().unwrap();
+    assert!(matches!(handle.insert_effect(Offset(1.0)), Err(Error::TooManyEffects)));
+    assert_eq!(settled(&mut root), 0.0);
+    handle.insert_effect(Offset(0.5)).unwrap();
+    assert_eq!(settled(&mut root), 0.5);
+    for _ in 1..16 {
+        handle.insert_effect(Offset(0.5)).unwrap();
+    }
+    assert!(matches!(handle.insert_effect(Offset(1.0)), Err(Error::TooManyEffects)));
+}
+
+#[test]
+fn quietest_voice_stealing() {
+    let mut mixer = BufferedMixer::new(2).with_max_voices(2).with_voice_stealing(VoiceStealing::Quietest);
+    let loud = mixer.add_source(constant(1.0, 100_000));
+    let quiet = mixer.add_source(constant(0.1, 100_000));
+    let mut buffer = vec![0.0; 2 * 256];
+    mixer.write_samples(&mut buffer);
+
+    // A paused source is judged by how loud it was before it was paused
+    loud.pause();
+    mixer.write_samples(&mut buffer);
+    mixer.write_samples(&mut buffer);
+    let first = mixer.add_source(constant(0.5, 100_000));
+    assert!(!quiet.is_playing());
+    assert!(first.is_playing());
+
+    // Sources added in a burst don't steal each other before they've been heard
+    let second = mixer.add_source(constant(0.5, 100_000));
+    assert!(first.is_playing() && second.is_playing());
+    loud.resume();
+    assert!(!loud.is_playing());
+
+    // A source which was loud a moment ago isn't taken for quieter than one which is steady
+    let mut mixer = BufferedMixer::new(2).with_max_voices(2);
+    let mut burst = vec![0.0; 2 * 1024];
+    burst[..2].copy_from_slice(&[1.0, 1.0]);
+    let burst = mixer.add_source(Player::new(burst.into_boxed_slice(), 2).repeat());
+    let steady = mixer.add_source(constant(0.2, 100_000));
+    mixer.write_samples(&mut buffer);
+    mixer.write_samples(&mut buffer);
+    mixer.add_source(constant(0.5, 100_000));
+    assert!(burst.is_playing());
+    assert!(!steady.is_playing());
+}
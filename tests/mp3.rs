@@ -0,0 +1,130 @@
+#![cfg(feature = "mp3")]
+
+use boop::{source::mp3::Mp3Player, Length, Seekable, Source};
+use std::io::Cursor;
+use symphonia_bundle_mp3::{MpaDecoder, MpaReader};
+use symphonia_core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions},
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+};
+
+// Five seconds of mono audio at 44.1 kHz with a Xing ("Info") header and a LAME tag, from puremp3's test vectors
+const FILE: &[u8] = include_bytes!("data/mono_cbr_192.mp3");
+
+// The encoder's delay and padding, from the LAME tag, and the number of packets of audio, from the Info header
+const ENCODER_DELAY: usize = 576;
+const ENCODER_PADDING: usize = 1260;
+const PACKETS: usize = 193;
+
+// Where the Info header starts (after the frame header and side information), and where the LAME tag starts
+const INFO_OFFSET: usize = 4 + 17;
+const LAME_OFFSET: usize = 141;
+
+// Decodes a whole file with Symphonia's own MP3 reader, with or without cutting off the encoder's silence
+fn reference(file: &[u8], gapless: bool) -> Vec<f32> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(file.to_vec())), Default::default());
+    let options = FormatOptions { enable_gapless: gapless, ..Default::default() };
+    let mut reader = MpaReader::try_new(source, &options).unwrap();
+    let mut decoder = MpaDecoder::try_new(&reader.tracks()[0].codec_params, &DecoderOptions::default()).unwrap();
+    let mut samples = Vec::new();
+    while let Ok(packet) = reader.next_packet() {
+        let decoded = decoder.decode(&packet).unwrap();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buffer.samples());
+    }
+    samples
+}
+
+fn decode_all(mut player: Mp3Player) -> Vec<f32> {
+    let mut samples = vec![0.0; 400_000];
+    let count = player.write_samples(&mut samples);
+    samples.truncate(count);
+    samples
+}
+
+#[test]
+fn decode() {
+    let reference = reference(FILE, true);
+    let length = PACKETS * 1152 - ENCODER_DELAY - ENCODER_PADDING;
+    assert_eq!(reference.len(), length);
+
+    let player = Mp3Player::new(FILE).unwrap();
+    assert_eq!((player.channel_count(), player.sample_rate()), (1, Some(44100)));
+    assert_eq!(player.length(), Some(length as u64));
+    assert_eq!(player.total_frames(), Length::Exact(length as u64));
+    assert_eq!(decode_all(player), reference);
+}
+
+#[test]
+fn untrimmed() {
+    // Without a LAME tag, the Info header still gives the length, but nothing is cut off
+    let untrimmed = reference(FILE, false);
+    assert_eq!(untrimmed.len(), PACKETS * 1152);
+    let mut file = FILE.to_vec();
+    file[LAME_OFFSET..(LAME_OFFSET + 4)].copy_from_slice(b"XXXX");
+    let player = Mp3Player::new(file).unwrap();
+    assert_eq!(player.length(), Some(untrimmed.len() as u64));
+    assert_eq!(decode_all(player), untrimmed);
+
+    // VBRI headers only give the length
+    let mut file = FILE.to_vec();
+    file[INFO_OFFSET..(INFO_OFFSET + 4)].copy_from_slice(b"XXXX");
+    file[36..40].copy_from_slice(b"VBRI");
+    file[50..54].copy_from_slice(&(PACKETS as u32).to_be_bytes());
+    let player = Mp3Player::new(file).unwrap();
+    assert_eq!(player.length(), Some(untrimmed.len() as u64));
+    assert_eq!(decode_all(player), untrimmed);
+
+    // Without either, the length isn't known
+    let mut file = FILE.to_vec();
+    file[INFO_OFFSET..(INFO_OFFSET + 4)].copy_from_slice(b"XXXX");
+    let player = Mp3Player::new(file).unwrap();
+    assert_eq!(player.total_frames(), Length::Unknown);
+}
+
+#[test]
+fn id3v2() {
+    // Tags are skipped however many there are, including their footers
+    let mut file = Vec::new();
+    for &(flags, size) in [(0x00, 300u32), (0x10, 1000)].iter() {
+        let synchsafe = [21, 14, 7, 0].map(|shift| (size >> shift) as u8 & 0x7F);
+        file.extend_from_slice(b"ID3\x04\x00");
+        file.push(flags);
+        file.extend_from_slice(&synchsafe);
+
+        // Fill the tag with something which looks like an MPEG frame header, which shouldn't be mistaken for one
+        file.extend((0..size).map(|i| if i % 2 == 0 { 0xFF } else { 0xFB }));
+        if flags & 0x10 != 0 {
+            file.extend_from_slice(b"3DI\x04\x00\x10");
+            file.extend_from_slice(&synchsafe);
+        }
+    }
+    file.extend_from_slice(FILE);
+    let player = Mp3Player::new(file).unwrap();
+    assert_eq!(player.length(), Some((PACKETS * 1152 - ENCODER_DELAY - ENCODER_PADDING) as u64));
+    assert_eq!(decode_all(player), reference(FILE, true));
+}
+
+#[test]
+fn seek() {
+    let reference = reference(FILE, true);
+    let mut player = Mp3Player::new(FILE).unwrap();
+    let length = player.length().unwrap();
+    let mut samples = vec![0.0; 512];
+
+    // Frames within the first packet, on and around packet boundaries (which are offset by the encoder's delay),
+    // and near the end, in no particular order so that seeks go both forwards and backwards
+    let delay = (ENCODER_DELAY + 529) as u64;
+    let frames = [100_000, 0, 1, 1152 - delay, 2304 - delay, 2305 - delay, 50_000, 4000, length - 1, length];
+    for &frame in frames.iter() {
+        player.seek_frame(frame);
+        assert_eq!(player.remaining_frames(), Length::Exact(length - frame));
+        let count = player.write_samples(&mut samples);
+        let start = frame as usize;
+        let expected = &reference[start..(start + 512).min(reference.len())];
+        assert_eq!(samples[..count], *expected, "seeking to frame {}", frame);
+    }
+}
@@ -0,0 +1,154 @@
+#![cfg(feature = "opus")]
+
+use audiopus::{coder::Decoder, packet::Packet as OpusPacket, Channels, MutSignals, SampleRate};
+use boop::{
+    source::opus::{Error, OpusPlayer},
+    Length, Seekable, Source,
+};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use std::{convert::TryFrom, io::Cursor};
+
+// A second of 440 Hz on the left and 660 Hz on the right, and 12345 frames of 440 Hz in mono, both with 312 frames
+// of pre-skip and pages of 10 packets of 20ms, encoded with libopus for these tests
+const STEREO: &[u8] = include_bytes!("data/sine_stereo.opus");
+const MONO: &[u8] = include_bytes!("data/sine_mono.opus");
+const PRE_SKIP: usize = 312;
+
+// Decodes a whole file straight through with libopus, then cuts off the pre-skip and anything past the end
+fn reference(file: &[u8], gain: i32) -> Vec<f32> {
+    let mut packets = PacketReader::new(Cursor::new(file));
+    let head = packets.read_packet_expected().unwrap();
+    let channels = usize::from(head.data[9]);
+    let decoder = Decoder::new(SampleRate::Hz48000, if channels == 1 { Channels::Mono } else { Channels::Stereo });
+    let mut decoder = decoder.unwrap();
+    decoder.set_gain(gain).unwrap();
+
+    let mut samples = Vec::new();
+    let mut end = 0;
+    packets.read_packet_expected().unwrap();
+    while let Some(packet) = packets.read_packet().unwrap() {
+        let mut output = vec![0.0; 5760 * channels];
+        let signals = MutSignals::try_from(&mut output[..]).unwrap();
+        let frames = decoder.decode_float(Some(OpusPacket::try_from(&packet.data[..]).unwrap()), signals, false);
+        samples.extend_from_slice(&output[..(frames.unwrap() * channels)]);
+        end = packet.absgp_page() as usize;
+    }
+    samples.truncate(end * channels);
+    samples.drain(..(PRE_SKIP * channels));
+    samples
+}
+
+// Copies a file into new pages, changing its identification header on the way
+fn with_head(file: &[u8], change: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut packets = PacketReader::new(Cursor::new(file));
+    let mut output = Vec::new();
+    let mut writer = PacketWriter::new(&mut output);
+    let mut change = Some(change);
+    while let Some(packet) = packets.read_packet().unwrap() {
+        let info = match (packet.last_in_stream(), packet.last_in_page()) {
+            (true, _) => PacketWriteEndInfo::EndStream,
+            (false, true) => PacketWriteEndInfo::EndPage,
+            (false, false) => PacketWriteEndInfo::NormalPacket,
+        };
+        let (serial, granule) = (packet.stream_serial(), packet.absgp_page());
+        let mut data = packet.data;
+        if let Some(change) = change.take() {
+            change(&mut data);
+        }
+        writer.write_packet(data.into_boxed_slice(), serial, info, granule).unwrap();
+    }
+    drop(writer);
+    output
+}
+
+fn decode_all(mut player: OpusPlayer) -> Vec<f32> {
+    let mut samples = vec![0.0; 200_000];
+    let count = player.write_samples(&mut samples);
+    samples.truncate(count);
+    samples
+}
+
+#[test]
+fn decode() {
+    for &(file, channels, length) in [(STEREO, 2, 48000), (MONO, 1, 12345)].iter() {
+        let reference = reference(file, 0);
+        assert_eq!(reference.len(), length * channels);
+        let player = OpusPlayer::new(file).unwrap();
+        assert_eq!((player.channel_count(), player.sample_rate()), (channels, Some(48000)));
+        assert_eq!(player.length(), Some(length as u64));
+        assert_eq!(player.total_frames(), Length::Exact(length as u64));
+        assert_eq!(player.comment("title"), Some("Sine"));
+        assert_eq!(decode_all(player), reference);
+    }
+}
+
+#[test]
+fn output_gain() {
+    // The header's gain is in 1/256ths of a decibel
+    for &gain in [6 * 256, -12 * 256].iter() {
+        let file = with_head(STEREO, |head| head[16..18].copy_from_slice(&(gain as i16).to_le_bytes()));
+        let samples = decode_all(OpusPlayer::new(file).unwrap());
+        assert_eq!(samples, reference(STEREO, gain));
+
+        let scale = 10f32.powf(gain as f32 / 256.0 / 20.0);
+        let loudest = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let ratio = loudest(&samples) / loudest(&reference(STEREO, 0));
+        assert!((ratio / scale - 1.0).abs() < 0.01, "gain {} scaled by {}", gain, ratio);
+    }
+}
+
+#[test]
+fn channel_mapping() {
+    // Mapping families other than 0 are fine as long as they're still one stream with the channels in order
+    let mapped = |mapping: &'static [u8]| {
+        with_head(STEREO, move |head| {
+            head[18] = 1;
+            head.extend_from_slice(mapping);
+        })
+    };
+    let player = OpusPlayer::new(mapped(&[1, 1, 0, 1])).unwrap();
+    assert_eq!(decode_all(player), reference(STEREO, 0));
+    assert!(matches!(OpusPlayer::new(mapped(&[2, 0, 0, 1])), Err(Error::UnsupportedChannels)));
+    assert!(matches!(OpusPlayer::new(mapped(&[1, 1, 1, 0])), Err(Error::UnsupportedChannels)));
+
+    // Family 0 can't have more than two channels
+    let surround = with_head(STEREO, |head| head[9] = 3);
+    assert!(matches!(OpusPlayer::new(surround), Err(Error::UnsupportedChannels)));
+}
+
+#[test]
+fn seek() {
+    for &file in [STEREO, MONO].iter() {
+        let reference = reference(file, 0);
+        let mut player = OpusPlayer::new(file).unwrap();
+        let channels = player.channel_count();
+        let length = player.length().unwrap();
+        let mut samples = vec![0.0; 1024];
+
+        // Frames within the pre-roll of the start, on and around packet and page boundaries (which are offset by the
+        // pre-skip), and near the end, in no particular order so that seeks go both forwards and backwards
+        let frames = [length / 2, 0, 1, 1000, 9600 - 312, 9601 - 312, 4000, length / 3, length - 1, length];
+        for &frame in frames.iter() {
+            player.seek_frame(frame);
+            assert_eq!(player.remaining_frames(), Length::Exact(length - frame));
+            let count = player.write_samples(&mut samples);
+            let start = frame as usize * channels;
+            assert_eq!(count, 1024.min(reference.len() - start), "seeking to frame {}", frame);
+
+            // Opus decoders take a while to settle after they start somewhere new, even with the 80ms run-up the
+            // player gives them, so the samples are only close. They should still match the right frame better
+            // than the ones either side of it.
+            let written = &samples[..count];
+            let distance = |start: usize| -> f32 {
+                let expected = reference.get(start..(start + count)).unwrap_or(&[]);
+                written.iter().zip(expected).map(|(a, b)| (a - b) * (a - b)).sum()
+            };
+            let error = written.iter().zip(&reference[start..]).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(error < 0.05, "seeking to frame {} was off by {}", frame, error);
+            if count == 1024 && start > 0 {
+                let (before, at, after) = (distance(start - channels), distance(start), distance(start + channels));
+                assert!(at < before && at < after, "seeking to frame {} landed on the wrong frame", frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use boop::{Length, Player, Source, SourceExt};
+use std::time::Duration;
+
+// A stereo player whose frames count up from 1, at 10 frames per second
+fn counting(frames: usize) -> Player {
+    let samples: Vec<f32> = (1..=frames).flat_map(|frame| [frame as f32, -(frame as f32)]).collect();
+    Player::new(samples.into_boxed_slice(), 2).with_sample_rate(10)
+}
+
+// Reads a source to its end a few samples at a time, returning the left channel
+fn render(mut source: impl Source) -> Vec<f32> {
+    let mut output = Vec::new();
+    let mut buffer = [0.0; 6];
+    loop {
+        let count = source.write_samples(&mut buffer);
+        output.extend(buffer[..count].iter().step_by(2));
+        if count < buffer.len() {
+            return output
+        }
+    }
+}
+
+#[test]
+fn amplify() {
+    assert_eq!(render(counting(3).amplify(0.5)), [0.5, 1.0, 1.5]);
+}
+
+#[test]
+fn take_and_skip() {
+    let take = counting(10).take_frames(4);
+    assert_eq!(take.total_frames(), Length::Exact(4));
+    assert_eq!(render(take), [1.0, 2.0, 3.0, 4.0]);
+    assert_eq!(render(counting(10).take_duration(Duration::from_millis(250)).unwrap()), [1.0, 2.0]);
+    assert_eq!(render(counting(3).take_frames(10)), [1.0, 2.0, 3.0]);
+
+    let skip = counting(10).skip_frames(7);
+    assert_eq!(skip.remaining_frames(), Length::Exact(3));
+    assert_eq!(render(skip), [8.0, 9.0, 10.0]);
+    assert_eq!(render(counting(10).skip_duration(Duration::from_secs(1)).unwrap()), []);
+    assert_eq!(render(counting(3).skip_frames(10)), []);
+
+    // Durations need a sample rate to be turned into frames
+    let unknown = Player::new(vec![0.0; 4].into_boxed_slice(), 2);
+    assert!(unknown.take_duration(Duration::from_secs(1)).is_none());
+
+    // Huge lengths don't overflow
+    let take = counting(3).take_frames(u64::MAX);
+    assert_eq!(take.remaining_frames(), Length::Exact(3));
+    assert_eq!(render(take), [1.0, 2.0, 3.0]);
+    assert_eq!(render(counting(3).skip_frames(u64::MAX)), []);
+    assert_eq!(render(counting(3).take_duration(Duration::MAX).unwrap()), [1.0, 2.0, 3.0]);
+}
+
+#[test]
+fn chain() {
+    let chain = counting(2).chain(counting(3).amplify(10.0));
+    assert_eq!(chain.total_frames(), Length::Exact(5));
+    assert_eq!(render(chain), [1.0, 2.0, 10.0, 20.0, 30.0]);
+}
+
+#[test]
+fn repeat() {
+    let mut repeat = counting(2).repeat();
+    let mut buffer = [0.0; 10];
+    assert_eq!(repeat.write_samples(&mut buffer), 10);
+    assert_eq!(buffer, [1.0, -1.0, 2.0, -2.0, 1.0, -1.0, 2.0, -2.0, 1.0, -1.0]);
+
+    // An empty source doesn't loop forever
+    assert_eq!(render(counting(0).repeat()), []);
+}
+
+#[test]
+fn delay() {
+    let delay = counting(2).delay_frames(3);
+    assert_eq!(delay.total_frames(), Length::Exact(5));
+    assert_eq!(render(delay), [0.0, 0.0, 0.0, 1.0, 2.0]);
+    assert_eq!(render(counting(1).delay(Duration::from_millis(200)).unwrap()), [0.0, 0.0, 1.0]);
+
+    let delay = counting(2).delay_frames(u64::MAX);
+    assert_eq!(delay.total_frames(), Length::Exact(u64::MAX));
+}
+
+#[test]
+fn fades() {
+    let ones = || Player::new(vec![1.0; 12].into_boxed_slice(), 2).with_sample_rate(10);
+    assert_eq!(render(ones().fade_in_frames(4)), [0.0, 0.25, 0.5, 0.75, 1.0, 1.0]);
+    assert_eq!(render(ones().fade_out_frames(4)), [1.0, 1.0, 1.0, 0.75, 0.5, 0.25]);
+    assert_eq!(render(ones().fade_out(Duration::from_millis(200)).unwrap()), [1.0, 1.0, 1.0, 1.0, 1.0, 0.5]);
+
+    // Without knowing how long the source is, the fade starts straight away and the source ends with it
+    let unknown = ones().chain(ones().repeat());
+    assert_eq!(render(unknown.fade_out_frames(4)), [1.0, 0.75, 0.5, 0.25]);
+    let unknown = ones().repeat().fade_out_frames(u64::MAX);
+    assert_eq!(unknown.remaining_frames(), Length::Unknown);
+}
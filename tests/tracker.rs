@@ -0,0 +1,217 @@
+#![cfg(feature = "tracker")]
+
+use boop::{
+    source::tracker::{Format, Position, TrackerPlayer},
+    Source,
+};
+
+// At the default tempo of 125 BPM, a tick lasts 2.5 / 125 seconds, which is 960 frames at 48 kHz
+const TICK_FRAMES: usize = 960;
+
+// The level of the test modules' samples, which are 0x40 all the way through, and loop forever
+const SAMPLE_LEVEL: f32 = 0.5;
+
+// A C-4 played with sample 1 in a MOD file, with room for an effect
+const MOD_NOTE: [u8; 4] = [0x01, 0xAC, 0x10, 0x00];
+
+// Builds a 4-channel MOD file which plays `orders`, with one looping sample. Each cell is given as its pattern, row,
+// channel and 4 bytes.
+fn mod_file(title: &str, orders: &[u8], cells: &[(usize, usize, usize, [u8; 4])]) -> Vec<u8> {
+    let mut file = title.as_bytes().to_vec();
+    file.resize(20, 0);
+
+    // Sample 1 is 16 words long, at full volume, and loops from start to end
+    let mut sample = [0; 30];
+    sample[22..24].copy_from_slice(&16u16.to_be_bytes());
+    sample[25] = 64;
+    sample[28..30].copy_from_slice(&16u16.to_be_bytes());
+    file.extend_from_slice(&sample);
+    file.resize(950, 0);
+
+    file.extend_from_slice(&[orders.len() as u8, 0x7F]);
+    let mut order_table = [0; 128];
+    order_table[..orders.len()].copy_from_slice(orders);
+    file.extend_from_slice(&order_table);
+    file.extend_from_slice(b"M.K.");
+
+    let pattern_count = usize::from(*orders.iter().max().unwrap()) + 1;
+    let mut patterns = vec![0; pattern_count * 64 * 4 * 4];
+    for &(pattern, row, channel, bytes) in cells {
+        let offset = ((pattern * 64 + row) * 4 + channel) * 4;
+        patterns[offset..(offset + 4)].copy_from_slice(&bytes);
+    }
+    file.extend_from_slice(&patterns);
+    file.extend_from_slice(&[0x40; 32]);
+    file
+}
+
+// Builds a 4-channel MOD file which plays pattern 1 and then pattern 0. Pattern 1 sets the speed to 3 ticks per
+// row, then breaks to row 10 of the next pattern after its third row. Neither pattern has any notes.
+fn module() -> Vec<u8> {
+    // The row to break to is stored as two decimal digits
+    mod_file("Sync test", &[1, 0], &[(1, 0, 0, [0, 0, 0x0F, 0x03]), (1, 2, 0, [0, 0, 0x0D, 0x10])])
+}
+
+// Builds a 2-channel XM file with one pattern of `rows`, each of which has a note, or none if it's 0, for the first
+// channel. The one instrument has a volume envelope with the given points, sustained at the point given, and one
+// looping sample.
+fn xm_file(rows: &[u8], envelope: &[(u16, u16)], sustain: u8) -> Vec<u8> {
+    let mut file = b"Extended Module: Envelope".to_vec();
+    file.resize(37, 0);
+    file.push(0x1A);
+    file.resize(58, 0);
+    file.extend_from_slice(&0x0104u16.to_le_bytes());
+    // The header's length, song length, restart, channels, patterns, instruments, flags, speed and tempo
+    file.extend_from_slice(&276u32.to_le_bytes());
+    for &value in [1u16, 0, 2, 1, 1, 1, 6, 125].iter() {
+        file.extend_from_slice(&value.to_le_bytes());
+    }
+    file.resize(60 + 276, 0);
+
+    // Cells are packed, with the top bit of the first byte set and the rest saying what follows
+    let mut data = Vec::new();
+    for &note in rows {
+        match note {
+            0 => data.push(0x80),
+            note => data.extend_from_slice(&[0x83, note, 1]),
+        }
+        data.push(0x80);
+    }
+    file.extend_from_slice(&9u32.to_le_bytes());
+    file.push(0);
+    file.extend_from_slice(&(rows.len() as u16).to_le_bytes());
+    file.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    file.extend_from_slice(&data);
+
+    let mut instrument = vec![0; 263];
+    instrument[0..4].copy_from_slice(&263u32.to_le_bytes());
+    instrument[27..29].copy_from_slice(&1u16.to_le_bytes());
+    instrument[29..33].copy_from_slice(&40u32.to_le_bytes());
+    for (i, &(tick, value)) in envelope.iter().enumerate() {
+        instrument[(129 + i * 4)..(131 + i * 4)].copy_from_slice(&tick.to_le_bytes());
+        instrument[(131 + i * 4)..(133 + i * 4)].copy_from_slice(&value.to_le_bytes());
+    }
+    instrument[225] = envelope.len() as u8;
+    instrument[227] = sustain;
+    // The volume envelope is on and sustained
+    instrument[233] = 0x03;
+    file.extend_from_slice(&instrument);
+
+    // The sample is at full volume, panned to the centre, and loops forwards from start to end. Its data is stored
+    // as the difference between each byte and the last.
+    let mut sample = vec![0; 40];
+    sample[0..4].copy_from_slice(&32u32.to_le_bytes());
+    sample[8..12].copy_from_slice(&32u32.to_le_bytes());
+    sample[12] = 64;
+    sample[14] = 0x01;
+    sample[15] = 0x80;
+    file.extend_from_slice(&sample);
+    file.push(0x40);
+    file.extend_from_slice(&[0; 31]);
+    file
+}
+
+// Renders a number of ticks, returning the last frame of each, by which time any change in volume has finished
+// ramping. The positions each tick was rendered at are returned too.
+fn render_ticks(player: &mut TrackerPlayer, ticks: usize) -> (Vec<[f32; 2]>, Vec<(usize, usize)>) {
+    let mut buffer = vec![0.0; TICK_FRAMES * 2];
+    (0..ticks)
+        .map(|_| {
+            assert_eq!(player.write_samples(&mut buffer), buffer.len());
+            let position = player.position();
+            ([buffer[buffer.len() - 2], buffer[buffer.len() - 1]], (position.order, position.row))
+        })
+        .unzip()
+}
+
+// Checks each tick's last frame against the left and right levels expected of it
+fn assert_levels(frames: &[[f32; 2]], expected: &[[f32; 2]]) {
+    assert_eq!(frames.len(), expected.len());
+    for (tick, (frame, expected)) in frames.iter().zip(expected).enumerate() {
+        let close = frame.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-5);
+        assert!(close, "tick {} was {:?} rather than {:?}", tick, frame, expected);
+    }
+}
+
+// Returns the levels a MOD's sample plays at on its first channel, which is panned a quarter of the way from the
+// left, at each of the given volumes
+fn first_channel_levels(volumes: &[i32]) -> Vec<[f32; 2]> {
+    volumes
+        .iter()
+        .map(|&volume| {
+            let level = SAMPLE_LEVEL * volume as f32 / 64.0;
+            [level * 0.75f32.sqrt(), level * 0.25f32.sqrt()]
+        })
+        .collect()
+}
+
+#[test]
+fn position() {
+    let mut player = TrackerPlayer::new(module()).unwrap().with_sample_rate(48000);
+    assert_eq!(player.format(), Format::Mod);
+    assert_eq!(player.title(), "Sync test");
+
+    // The position moves on as soon as the first frame of a row is rendered
+    let row_frames = TICK_FRAMES * 3;
+    let mut buffer = vec![0.0; row_frames * 2];
+    let rows = (0..3).map(|row| (0, 1, row)).chain((10..64).map(|row| (1, 0, row)));
+    for (i, (order, pattern, row)) in rows.enumerate() {
+        let frames = if i == 0 { 1 } else { row_frames };
+        assert_eq!(player.write_samples(&mut buffer[..(frames * 2)]), frames * 2);
+        assert_eq!(player.position(), Position { order, pattern, row });
+    }
+
+    // The song ends with the last row of the last pattern
+    assert_eq!(player.write_samples(&mut buffer), (row_frames - 1) * 2);
+    assert_eq!(player.write_samples(&mut buffer), 0);
+}
+
+#[test]
+fn volume_effects() {
+    // C20 sets the volume to 32, then A02 slides it down by 2 and A30 slides it up by 3, on every tick but the first
+    // of their rows
+    let [a, b, _, _] = MOD_NOTE;
+    let cells = [(0, 0, 0, [a, b, 0x1C, 0x20]), (0, 1, 0, [0, 0, 0x0A, 0x02]), (0, 2, 0, [0, 0, 0x0A, 0x30])];
+    let mut player = TrackerPlayer::new(mod_file("Effects", &[0], &cells)).unwrap();
+    let (frames, _) = render_ticks(&mut player, 18);
+
+    let volumes = [32, 32, 32, 32, 32, 32, 32, 30, 28, 26, 24, 22, 22, 25, 28, 31, 34, 37];
+    assert_levels(&frames, &first_channel_levels(&volumes));
+}
+
+#[test]
+fn pattern_loop() {
+    // At one tick per row, rows 1 to 3 play three times over with E60 and E62, and EB4 on row 2 slides the volume
+    // down by 4 each time
+    let [a, b, _, _] = MOD_NOTE;
+    let cells = [
+        (0, 0, 0, [a, b, 0x1C, 0x20]),
+        (0, 0, 1, [0, 0, 0x0F, 0x01]),
+        (0, 1, 1, [0, 0, 0x0E, 0x60]),
+        (0, 2, 0, [0, 0, 0x0E, 0xB4]),
+        (0, 3, 1, [0, 0, 0x0E, 0x62]),
+    ];
+    let mut player = TrackerPlayer::new(mod_file("Loop", &[0], &cells)).unwrap();
+    let (frames, positions) = render_ticks(&mut player, 11);
+    let rows = [0, 1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+    assert_eq!(positions, rows.map(|row| (0, row)));
+
+    let volumes = [32, 32, 28, 28, 28, 24, 24, 24, 20, 20, 20];
+    assert_levels(&frames, &first_channel_levels(&volumes));
+}
+
+#[test]
+fn volume_envelope() {
+    // The note's envelope dips down, comes back up to the sustain point and holds there until it's released on the
+    // third row, and then falls to nothing, which stops the note
+    let envelope = [(0, 64), (2, 16), (6, 48), (8, 0)];
+    let mut player = TrackerPlayer::new(xm_file(&[49, 0, 97, 0], &envelope, 2)).unwrap();
+    assert_eq!(player.format(), Format::Xm);
+    let (frames, _) = render_ticks(&mut player, 24);
+
+    let mut values = vec![64, 40, 16, 24, 32, 40, 48, 48, 48, 48, 48, 48, 48, 24];
+    values.resize(24, 0);
+    let expected =
+        values.iter().map(|&value| [SAMPLE_LEVEL * value as f32 / 64.0 * 0.5f32.sqrt(); 2]).collect::<Vec<_>>();
+    assert_levels(&frames, &expected);
+}
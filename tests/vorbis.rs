@@ -0,0 +1,44 @@
+#![cfg(feature = "vorbis")]
+
+use boop::{source::vorbis::VorbisPlayer, Seekable, Source};
+use lewton::{inside_ogg::OggStreamReader, samples::InterleavedSamples};
+use std::io::Cursor;
+
+// One second of a 440 Hz sine wave in stereo, from the audrey crate's samples
+const SINE: &[u8] = include_bytes!("data/sine_440hz_stereo.ogg");
+
+// Decodes the whole file straight through with lewton's own Ogg reader
+fn reference() -> Vec<f32> {
+    let mut reader = OggStreamReader::new(Cursor::new(SINE)).unwrap();
+    let mut samples = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_generic::<InterleavedSamples<f32>>().unwrap() {
+        samples.extend_from_slice(&packet.samples);
+    }
+    samples
+}
+
+#[test]
+fn decode() {
+    let mut player = VorbisPlayer::new(SINE).unwrap();
+    let mut samples = vec![0.0; 100_000];
+    let count = player.write_samples(&mut samples);
+    assert_eq!(player.length(), Some(44100));
+    assert_eq!(samples[..count], reference()[..]);
+}
+
+#[test]
+fn seek() {
+    let reference = reference();
+    let mut player = VorbisPlayer::new(SINE).unwrap();
+    let mut samples = vec![0.0; 512];
+
+    // Frames inside the first packet, on and around page and packet boundaries, and near the end, in no
+    // particular order so that seeks go both forwards and backwards
+    for &frame in [30000, 0, 100, 1024, 4095, 4096, 4097, 44000, 22050, 8191, 128, 44099, 44100].iter() {
+        player.seek_frame(frame);
+        let count = player.write_samples(&mut samples);
+        let start = frame as usize * 2;
+        let expected = &reference[start..(start + 512).min(reference.len())];
+        assert_eq!(samples[..count], *expected, "seeking to frame {}", frame);
+    }
+}
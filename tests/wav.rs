@@ -0,0 +1,175 @@
+use boop::{
+    source::wav::{self, BroadcastExtension, CuePoint, Format, StreamingWavPlayer, WavPlayer, WavWriter},
+    Player, Source,
+};
+use std::io::Cursor;
+
+// A few frames of a ramp, which covers the whole range of every format
+fn ramp(channels: usize) -> Vec<f32> {
+    (0..64 * channels).map(|i| (i as f32 / (32 * channels) as f32) - 1.0).collect()
+}
+
+// Appends a chunk to `file`, padded to an even length
+fn chunk(file: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    file.extend_from_slice(id);
+    file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    file.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        file.push(0);
+    }
+}
+
+fn read_all(source: &mut impl Source) -> Vec<f32> {
+    let mut samples = vec![0.0; 4096];
+    let count = source.write_samples(&mut samples);
+    samples.truncate(count);
+    samples
+}
+
+#[test]
+fn roundtrip() {
+    let formats = [
+        (Format::U8, 1.0 / 64.0),
+        (Format::I16, 1.0 / 16384.0),
+        (Format::I24, 1.0 / 4194304.0),
+        (Format::I32, 1.0e-6),
+        (Format::F32, 0.0),
+        (Format::F64, 0.0),
+        (Format::ALaw, 1.0 / 32.0),
+        (Format::MuLaw, 1.0 / 32.0),
+    ];
+    for &(format, tolerance) in formats.iter() {
+        for &channels in [1, 2, 6].iter() {
+            let samples = ramp(channels);
+            let player = Player::new(samples.clone().into_boxed_slice(), channels).with_sample_rate(22050);
+            let file = wav::encode(player, format).unwrap();
+
+            let mut wav = WavPlayer::new(file.clone()).unwrap();
+            assert_eq!(wav.channel_count(), channels);
+            assert_eq!(Source::sample_rate(&wav), Some(22050));
+            let decoded = read_all(&mut wav);
+            assert_eq!(decoded.len(), samples.len(), "{:?} with {} channels", format, channels);
+            for (a, b) in samples.iter().zip(&decoded) {
+                assert!((a - b).abs() <= tolerance, "{:?} with {} channels: {} became {}", format, channels, a, b);
+            }
+
+            let mut streaming = StreamingWavPlayer::new(Cursor::new(file)).unwrap();
+            assert_eq!(read_all(&mut streaming), decoded);
+        }
+    }
+}
+
+#[test]
+fn extensible_header() {
+    let file = wav::encode(Player::new(ramp(6).into_boxed_slice(), 6).with_sample_rate(48000), Format::I16).unwrap();
+    let wav = WavPlayer::new(file.clone()).unwrap();
+    assert_eq!(wav.channel_mask(), Some(0x3F));
+
+    // The fmt chunk comes after the RIFF header and the reserved JUNK chunk
+    assert_eq!(&file[48..52], b"fmt ");
+    assert_eq!(u16::from_le_bytes([file[56], file[57]]), 0xFFFE);
+
+    let file = wav::encode(Player::new(ramp(2).into_boxed_slice(), 2).with_sample_rate(48000), Format::I16).unwrap();
+    assert_eq!(u16::from_le_bytes([file[56], file[57]]), 1);
+    assert_eq!(WavPlayer::new(file).unwrap().channel_mask(), None);
+}
+
+#[test]
+fn write_errors() {
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), Format::I16, 2, 44100).unwrap();
+    let mut mono = Player::new(ramp(1).into_boxed_slice(), 1);
+    assert!(matches!(writer.write_source(&mut mono), Err(wav::Error::ChannelCountMismatch)));
+
+    let too_wide = WavWriter::new(Cursor::new(Vec::new()), Format::F64, 10000, 44100);
+    assert!(matches!(too_wide, Err(wav::Error::UnknownFormat)));
+    assert!(matches!(wav::encode(mono, Format::I16), Err(wav::Error::UnknownSampleRate)));
+}
+
+#[test]
+fn metadata() {
+    let mut info = b"INFO".to_vec();
+    chunk(&mut info, b"INAM", b"Title\0");
+    chunk(&mut info, b"IART", b"Odd\0");
+    chunk(&mut info, b"ICMT", b"Comment\0");
+
+    // The names of the cue points come before the cue chunk, and the points themselves are out of order
+    let mut adtl = b"adtl".to_vec();
+    chunk(&mut adtl, b"labl", &[&2u32.to_le_bytes()[..], b"Loop\0"].concat());
+    chunk(&mut adtl, b"note", &[&1u32.to_le_bytes()[..], b"Start\0"].concat());
+    let mut ltxt = 2u32.to_le_bytes().to_vec();
+    ltxt.extend_from_slice(&5u32.to_le_bytes());
+    ltxt.extend_from_slice(&[0; 12]);
+    ltxt.extend_from_slice(b"Region\0");
+    chunk(&mut adtl, b"ltxt", &ltxt);
+    chunk(&mut adtl, b"labl", &[&9u32.to_le_bytes()[..], b"Missing\0"].concat());
+
+    let mut cue = 2u32.to_le_bytes().to_vec();
+    for &(id, frame) in [(2u32, 10u32), (1, 3)].iter() {
+        cue.extend_from_slice(&id.to_le_bytes());
+        cue.extend_from_slice(&frame.to_le_bytes());
+        cue.extend_from_slice(b"data");
+        cue.extend_from_slice(&[0; 8]);
+        cue.extend_from_slice(&frame.to_le_bytes());
+    }
+
+    let mut bext = vec![0; 602];
+    bext[..11].copy_from_slice(b"Description");
+    bext[256..266].copy_from_slice(b"Originator");
+    bext[288..297].copy_from_slice(b"Reference");
+    bext[320..330].copy_from_slice(b"2024-01-02");
+    bext[330..338].copy_from_slice(b"03:04:05");
+    bext[338..346].copy_from_slice(&48000u64.to_le_bytes());
+    bext[346..348].copy_from_slice(&1u16.to_le_bytes());
+    bext[348..412].copy_from_slice(&[7; 64]);
+    bext.extend_from_slice(b"A=PCM,F=8000\r\n");
+
+    let mut fmt = 1u16.to_le_bytes().to_vec();
+    fmt.extend_from_slice(&1u16.to_le_bytes());
+    fmt.extend_from_slice(&8000u32.to_le_bytes());
+    fmt.extend_from_slice(&16000u32.to_le_bytes());
+    fmt.extend_from_slice(&2u16.to_le_bytes());
+    fmt.extend_from_slice(&16u16.to_le_bytes());
+
+    let mut body = b"WAVE".to_vec();
+    chunk(&mut body, b"fmt ", &fmt);
+    chunk(&mut body, b"LIST", &info);
+    chunk(&mut body, b"LIST", &adtl);
+    chunk(&mut body, b"cue ", &cue);
+    chunk(&mut body, b"bext", &bext);
+    chunk(&mut body, b"data", &[0; 32]);
+    let mut file = Vec::new();
+    chunk(&mut file, b"RIFF", &body);
+
+    let start = CuePoint { id: 1, frame: 3, length: None, label: None, note: Some("Start".into()), text: None };
+    let region = CuePoint {
+        id: 2,
+        frame: 10,
+        length: Some(5),
+        label: Some("Loop".into()),
+        note: None,
+        text: Some("Region".into()),
+    };
+    let expected_cue_points = vec![start, region];
+    let expected_broadcast = BroadcastExtension {
+        description: "Description".into(),
+        originator: "Originator".into(),
+        originator_reference: "Reference".into(),
+        origination_date: "2024-01-02".into(),
+        origination_time: "03:04:05".into(),
+        time_reference: 48000,
+        version: 1,
+        umid: Some([7; 64]),
+        coding_history: "A=PCM,F=8000".into(),
+    };
+
+    let wav = WavPlayer::new(file.clone()).unwrap();
+    let streaming = StreamingWavPlayer::new(Cursor::new(file)).unwrap();
+    for metadata in [wav.metadata(), streaming.metadata()].iter() {
+        assert_eq!(metadata.title(), Some("Title"));
+        assert_eq!(metadata.artist(), Some("Odd"));
+        assert_eq!(metadata.comment(), Some("Comment"));
+        assert_eq!(metadata.info.len(), 3);
+        assert_eq!(metadata.cue_points, expected_cue_points);
+        assert_eq!(metadata.broadcast.as_ref(), Some(&expected_broadcast));
+    }
+}